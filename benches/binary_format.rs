@@ -0,0 +1,87 @@
+// Saving and loading a long recording in the binary format, against RON.
+// Run with `cargo bench`.
+
+use ahk_rs::keycodes::{KeyCode, KeyUpDown};
+use ahk_rs::macro_events::{
+    CoordSpace, KeyboardEvent, MacroEvent, MouseAction, MouseButton, MouseButtonEvent,
+    MouseMoveEvent,
+};
+use ahk_rs::macro_file::{Format, MacroFile};
+use ahk_rs::r#macro::{Macro, MacroBlock, MacroSettings};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const EVENTS: usize = 100_000;
+
+// key presses and clicks with the sleeps between them, like the recorder writes
+fn recording() -> MacroFile {
+    let keys = [KeyCode::VK_W, KeyCode::VK_A, KeyCode::VK_S, KeyCode::VK_D];
+    let events = (0..EVENTS / 4)
+        .flat_map(|i| {
+            let (down, up) = if i % 10 == 0 {
+                let click = |action| {
+                    MacroEvent::MouseBtn(MouseButtonEvent {
+                        button: MouseButton::Left,
+                        action,
+                    })
+                };
+                (
+                    MacroEvent::MouseMove(MouseMoveEvent {
+                        x: (i % 1920) as i32,
+                        y: (i % 1080) as i32,
+                        space: CoordSpace::VirtualDesk,
+                    }),
+                    click(MouseAction::Click),
+                )
+            } else {
+                let key = |key_up_down| {
+                    MacroEvent::Keybd(KeyboardEvent {
+                        key: Some(keys[i % keys.len()]),
+                        key_up_down: Some(key_up_down),
+                        custom_flags: None,
+                    })
+                };
+                (key(KeyUpDown::Down), key(KeyUpDown::Up))
+            };
+            [
+                down,
+                MacroEvent::SleepMs(40 + (i % 60) as u64),
+                up,
+                MacroEvent::SleepMs(100 + (i % 200) as u64),
+            ]
+        })
+        .collect();
+    let body = Macro {
+        name: "recording".to_string(),
+        blocks: vec![MacroBlock {
+            hotkey: None,
+            events,
+            running: false,
+            max_threads: 1,
+            suspend_exempt: false,
+            active_when: None,
+        }],
+        settings: MacroSettings::default(),
+        timers: vec![],
+    };
+    MacroFile::new(body, None)
+}
+
+fn formats(c: &mut Criterion) {
+    let file = recording();
+    let mut group = c.benchmark_group("recording");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+    for format in [Format::Binary, Format::Ron] {
+        let bytes = file.to_bytes(format).unwrap();
+        println!("{:?}: {} bytes", format, bytes.len());
+        group.bench_function(BenchmarkId::new("save", format!("{:?}", format)), |b| {
+            b.iter(|| file.to_bytes(format).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("load", format!("{:?}", format)), |b| {
+            b.iter(|| MacroFile::parse(&bytes, format).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, formats);
+criterion_main!(benches);
@@ -1,14 +1,28 @@
 // todo: everything
 // (that means atleast partial .ahk file support)
 
-use std::{io::BufRead, str::FromStr};
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    keycodes::{KeyCode, KeyUpDown, MouseFlags},
-    macro_events::{KeyboardEvent, LoopEvent, MacroEvent, MouseButtonEvent, MouseMoveEvent},
-    r#macro::{Macro, MacroBlock},
+    ahk_v2,
+    condition::Condition,
+    keycodes::{KeyCode, KeyUpDown, KeyboardFlags, MouseFlags},
+    layout::{self, Layout},
+    lexer::{logical_lines, Line},
+    macro_events::{
+        CoordSpace, IfEvent, KeyboardEvent, LoopEvent, MacroEvent, MouseAction, MouseButton,
+        MouseButtonEvent, MouseMoveEvent, MouseWheelEvent, RunEvent, SetVariableEvent, ToggleMode,
+        VariableValue, WaitForKeyEvent,
+    },
+    r#macro::{CoordMode, Macro, MacroBlock, MacroSettings, SingleInstance},
+    timer::{default_period_ms, MacroTimer, SetTimerEvent, TimerCommand},
+    window::check_win_title,
 };
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, Eq, PartialEq)]
@@ -26,195 +40,321 @@ pub enum AhkFunctions {
     Click,
     MouseMove,
     DllCall,
-    // Reload,
+    Reload,
+    Suspend,
+    Pause,
     ExitApp,
     Loop,
     Run,
+    RunWait,
+    If,
+    KeyWait,
+    Label,
+    SetTimer,
+    Assign,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
 pub struct AhkFile {
     pub path: String,
     pub blocks: Vec<AhkBlock>,
+    pub settings: MacroSettings,
+    // None to pick the version from `#Requires`, defaulting to v1
+    pub version: Option<AhkVersion>,
+    // what Send types characters with
+    pub layout: Layout,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Eq, PartialEq)]
+pub enum AhkVersion {
+    V1,
+    V2,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
 pub struct AhkBlock {
     pub condition: Option<AhkFunction>,
     pub functions: Vec<AhkFunction>,
+    pub max_threads: Option<u32>,
+    // from the #If directive in effect where the hotkey was defined
+    pub active_when: Option<Condition>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
 pub struct AhkFunction {
     pub func: Option<AhkFunctions>,
     pub args: Vec<String>,
     pub special_args: Vec<String>,
+    // functions inside a Loop or If
+    pub body: Vec<AhkFunction>,
+    pub else_body: Vec<AhkFunction>,
+    // the CoordMode in effect where the function is, for its absolute mouse
+    // coordinates
+    #[serde(default)]
+    pub coord_mode: CoordMode,
 }
 
-impl AhkFile {
-    pub fn parse(&mut self) -> Option<Macro> {
-        let mut m: Macro = Macro {
-            name: self.path.clone(),
-            blocks: vec![],
-        };
-        let file: std::fs::File = std::fs::File::open(&self.path).unwrap();
-        let mut ahk_block = AhkBlock::default();
-        for line in std::io::BufReader::new(file).lines() {
-            let line = line.unwrap();
-            let mut words = line.split_whitespace();
-            let mut func = words.next().unwrap_or("").to_lowercase();
-            func = func.trim_matches(',').to_string();
-
-            if func.is_empty() || func.starts_with(';') {
-                continue;
-            }
-            if func.contains("dllcall") {
-                let mut dllcall = AhkFunction {
-                    func: Some(AhkFunctions::DllCall),
-                    args: vec![],
-                    special_args: vec![],
-                };
-                let mut dllcall_args = func.split('(').collect::<Vec<&str>>();
-                dllcall_args.remove(0);
-                let mut dllcall_args = dllcall_args.join("(");
-                dllcall_args.pop();
-                dllcall_args = dllcall_args.trim_matches(&['(', ')'][..]).to_string();
-                let dllcall_args = dllcall_args.split(',').collect::<Vec<&str>>();
-                match dllcall_args[0] {
-                    "\"mouse_event\"" => {
-                        let flags: u16 = dllcall_args[2].parse::<u16>().unwrap();
-
-                        if flags & MouseFlags::MOUSEEVENTF_MOVE as u16 != 0 {
-                            dllcall.func = Some(AhkFunctions::MouseMove);
-                        }
+/// Tracks the files read while following `#Include` directives.
+#[derive(Debug, Default)]
+struct Includes {
+    /// Files currently being read, outermost first.
+    stack: Vec<PathBuf>,
+    /// Every file read so far, so plain `#Include` reads each one once.
+    seen: HashSet<PathBuf>,
+    /// Directory relative includes resolve against. Starts as the script's
+    /// directory and is changed by `#Include <dir>`.
+    dir: PathBuf,
+}
 
-                        let x = dllcall_args[4].parse::<i32>().unwrap();
-                        let y = dllcall_args[6].parse::<i32>().unwrap();
+/// A Loop or If whose body is still being read.
+#[derive(Debug)]
+struct OpenBlock {
+    func: AhkFunction,
+    /// Whether the body is wrapped in braces. Without them it closes after
+    /// the first function added to it.
+    braced: bool,
+    /// Whether functions go into the If's else branch.
+    in_else: bool,
+}
 
-                        let absolute = flags & MouseFlags::MOUSEEVENTF_ABSOLUTE as u16 != 0;
+impl OpenBlock {
+    fn is_empty(&self) -> bool {
+        if self.in_else {
+            self.func.else_body.is_empty()
+        } else {
+            self.func.body.is_empty()
+        }
+    }
 
-                        dllcall.args.push(x.to_string());
-                        dllcall.args.push(y.to_string());
-                        dllcall.args.push(absolute.to_string());
+    fn body(&mut self) -> &mut Vec<AhkFunction> {
+        if self.in_else {
+            &mut self.func.else_body
+        } else {
+            &mut self.func.body
+        }
+    }
+}
 
-                        ahk_block.functions.push(dllcall);
-                    }
-                    _ => {
-                        eprintln!("DllCall function not implemented: {:?}", dllcall_args[0]);
-                    }
-                }
-                continue;
+/// Collects functions into the current block while lines are read, keeping
+/// track of loops and ifs whose bodies haven't been closed yet.
+#[derive(Debug, Default)]
+struct BlockBuilder {
+    block: AhkBlock,
+    /// Innermost last.
+    open_blocks: Vec<OpenBlock>,
+    /// Condition from the last `#If`, given to the hotkeys after it.
+    active_when: Option<Condition>,
+    /// The CoordMode set by the lines read so far in the current block.
+    coord_mode: CoordMode,
+}
+
+impl BlockBuilder {
+    fn push(&mut self, mut func: AhkFunction) {
+        func.coord_mode = self.coord_mode;
+        while let Some(parent) = self.open_blocks.last_mut() {
+            parent.body().push(func);
+            if parent.braced {
+                return;
             }
-            let args = words.collect::<Vec<&str>>();
-            let mut ahk_func = AhkFunction {
-                func: None,
-                args: vec![],
-                special_args: vec![],
-            };
-            if func.contains("::") {
-                ahk_func.func = Some(AhkFunctions::Hotkey);
-                let mut hotkeys: Vec<KeyCode> = vec![];
-                for char in func.trim_matches(':').chars() {
-                    match char {
-                        '^' => hotkeys.push(KeyCode::VK_CONTROL),
-                        '!' => hotkeys.push(KeyCode::VK_MENU),
-                        '+' => hotkeys.push(KeyCode::VK_SHIFT),
-                        '#' => hotkeys.push(KeyCode::VK_LWIN),
-                        _ => {}
-                    }
-                }
+            func = self.open_blocks.pop().unwrap().func;
+        }
+        self.block.functions.push(func);
+    }
 
-                let mut hotkey_no_modifiers = func
-                    .trim_matches(&[':', '^', '!', '+', '#'][..])
-                    .to_uppercase();
-                if hotkey_no_modifiers.contains(':') {
-                    let c = hotkey_no_modifiers.clone();
-                    let split = c.split(':').collect::<Vec<&str>>();
-
-                    hotkey_no_modifiers = split[0].to_string();
-                    if split.len() > 1 {
-                        for arg in &split[1..] {
-                            if arg.is_empty() {
-                                continue;
-                            }
-                            ahk_func.special_args.push(arg.to_string());
-                        }
-                    }
-                }
-                hotkeys.push(KeyCode::from_str(hotkey_no_modifiers.as_str()).unwrap());
-
-                ahk_func.args.push(
-                    hotkeys
-                        .iter()
-                        .map(|x| x.to_string())
-                        .collect::<Vec<String>>()
-                        .join(" "),
-                );
-                println!("hotkey func {:?}", ahk_func);
-                ahk_block.condition = Some(ahk_func);
+    fn open(&mut self, func: AhkFunction, braced: bool) {
+        self.open_blocks.push(OpenBlock {
+            func,
+            braced,
+            in_else: false,
+        });
+    }
 
-                continue;
-            }
-            let mut skip_add_func = false;
-            match func.as_str() {
-                "sleep" => {
-                    ahk_func.func = Some(AhkFunctions::Sleep);
-                    ahk_func.args.push(args[0].to_string());
-                }
-                "send" => {
-                    ahk_func.func = Some(AhkFunctions::Send);
-                    ahk_func
-                        .args
-                        .push(args.join(" ").trim_matches(&['{', '}'][..]).to_string());
-                }
-                "loop" => {
-                    self.blocks.push(ahk_block);
-                    ahk_block = AhkBlock::default();
+    /// Handles an `else`, reopening the If that was just closed.
+    fn open_else(&mut self, braced: bool) -> Result<(), String> {
+        let container = match self.open_blocks.last_mut() {
+            Some(parent) => parent.body(),
+            None => &mut self.block.functions,
+        };
+        if !matches!(container.last(), Some(func) if func.func == Some(AhkFunctions::If)) {
+            return Err("else without a matching if".to_string());
+        }
+        let mut func = container.pop().unwrap();
 
-                    ahk_func.func = Some(AhkFunctions::Loop);
-                    ahk_func.args.push(args[0].to_string());
-                    ahk_block.condition = Some(ahk_func.clone());
+        // in an `if ... else if ...` chain the else belongs to the last if
+        while !func.else_body.is_empty() {
+            let chained =
+                func.else_body.len() == 1 && func.else_body[0].func == Some(AhkFunctions::If);
+            if !chained {
+                self.push(func);
+                return Err("if already has an else".to_string());
+            }
+            let inner = func.else_body.pop().unwrap();
+            self.open_blocks.push(OpenBlock {
+                func,
+                braced: false,
+                in_else: true,
+            });
+            func = inner;
+        }
+        self.open_blocks.push(OpenBlock {
+            func,
+            braced,
+            in_else: true,
+        });
+        Ok(())
+    }
 
-                    skip_add_func = true;
-                }
-                "{" => {
-                    // TODO: figure out how to mark the start of a nested block
-                    // if ahk_block.functions.last().is_some() {
-                    //     self.blocks.push(ahk_block);
-                    //     ahk_block = AhkBlock {
-                    //         condition: None,
-                    //         functions: vec![],
-                    //     };
-                    // }
-                }
-                "}" => {
-                    self.blocks.push(ahk_block);
-                    ahk_block = AhkBlock::default();
-                }
-                "click" => {
-                    ahk_func.func = Some(AhkFunctions::Click);
-                }
-                "run" => {
-                    ahk_func.func = Some(AhkFunctions::Run);
-                    ahk_func.args.push(args.join(" ").to_string());
-                }
-                _ => {
-                    eprintln!("Function not implemented: {:?} args {:?}", func, args);
-                }
+    /// Handles a `{` line. Returns whether it opened the body of a loop or if.
+    fn open_brace(&mut self) -> bool {
+        match self.open_blocks.last_mut() {
+            Some(open) if !open.braced && open.is_empty() => {
+                open.braced = true;
+                true
             }
-            if ahk_func.func.is_none() && ahk_func.args.is_empty() {
-                continue;
+            _ => false,
+        }
+    }
+
+    /// Handles a `}` line. Returns whether it closed the body of a loop or if.
+    fn close_brace(&mut self) -> bool {
+        match self.open_blocks.last() {
+            Some(open) if open.braced => {
+                let open = self.open_blocks.pop().unwrap();
+                self.push(open.func);
+                true
             }
-            if !skip_add_func {
-                ahk_block.functions.push(ahk_func);
+            _ => false,
+        }
+    }
+
+    /// Ends the current block, returning it if it has anything in it.
+    fn finish(&mut self) -> Option<AhkBlock> {
+        while let Some(open) = self.open_blocks.pop() {
+            eprintln!(
+                "{:?} is missing a closing brace: {:?}",
+                open.func.func, open.func.args
+            );
+            self.push(open.func);
+        }
+        let block = std::mem::take(&mut self.block);
+        if block.condition.is_none() && block.functions.is_empty() {
+            return None;
+        }
+        Some(block)
+    }
+}
+
+/// The AHK version `#Requires` asks for, from its version requirement like
+/// `v2.0`, `>=2.0-beta` or `^1.1`. None if it isn't an AutoHotkey
+/// requirement or the version can't be read.
+fn required_version(requirement: &str) -> Option<AhkVersion> {
+    let mut words = requirement.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("autohotkey") {
+        return None;
+    }
+    let version = words.next()?;
+    let below = version.starts_with('<') && !version.starts_with("<=");
+    let version = version.trim_start_matches(['<', '>', '=', '^']);
+    let version = version.strip_prefix(['v', 'V']).unwrap_or(version);
+    let (major, rest) = version.split_once('.').unwrap_or((version, ""));
+    let mut major = major.parse::<u32>().ok()?;
+    // `<2.0` is any v1, but `<2.1` can be a v2
+    if below && rest.chars().all(|c| c == '0' || c == '.') {
+        major = major.saturating_sub(1);
+    }
+    Some(if major >= 2 { AhkVersion::V2 } else { AhkVersion::V1 })
+}
+
+/// Removes SetTimer events, also those in loops and ifs, for labels that
+/// aren't in `labels`.
+fn remove_unknown_timers(events: &mut Vec<MacroEvent>, labels: &HashSet<String>, path: &str) {
+    events.retain_mut(|event| match event {
+        MacroEvent::SetTimer(event) if !labels.contains(&event.name.to_lowercase()) => {
+            eprintln!("{}: SetTimer: no label named {:?}", path, event.name);
+            false
+        }
+        MacroEvent::Loop(event) => {
+            remove_unknown_timers(&mut event.events, labels, path);
+            true
+        }
+        MacroEvent::If(event) => {
+            remove_unknown_timers(&mut event.events, labels, path);
+            remove_unknown_timers(&mut event.else_events, labels, path);
+            true
+        }
+        _ => true,
+    });
+}
+
+/// Lexically resolves `.` and `..` components, so `%A_LineFile%\..\lib.ahk`
+/// works without the intermediate path existing as a directory.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
             }
+            _ => normalized.push(component),
         }
-        self.blocks.push(ahk_block);
+    }
+    normalized
+}
+
+impl AhkFile {
+    pub fn parse(&mut self) -> Option<Macro> {
+        layout::with_layout(self.layout, || self.parse_macro())
+    }
+
+    fn parse_macro(&mut self) -> Option<Macro> {
+        let mut m: Macro = Macro {
+            name: self.path.clone(),
+            blocks: vec![],
+            settings: MacroSettings::default(),
+            timers: vec![],
+        };
+        let path = normalize_path(Path::new(&self.path));
+        let mut includes = Includes {
+            dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            ..Default::default()
+        };
+        let mut builder = BlockBuilder::default();
+        if let Err(e) = self.parse_file(&path, &mut builder, &mut includes) {
+            eprintln!("{}: {}", self.path, e);
+            return None;
+        }
+        self.blocks.extend(builder.finish());
+        m.settings = self.settings.clone();
         for block in &self.blocks {
+            // labels are only used by SetTimer, so they become timers
+            if let Some(label) = block
+                .condition
+                .as_ref()
+                .filter(|x| x.func == Some(AhkFunctions::Label))
+            {
+                m.timers.push(MacroTimer {
+                    name: label.args[0].clone(),
+                    period_ms: default_period_ms(),
+                    run_count: None,
+                    enabled: false,
+                    events: block.functions.iter().flat_map(|x| x.parse()).collect(),
+                });
+                continue;
+            }
             let mut macro_block = MacroBlock {
                 hotkey: None,
                 events: vec![],
                 running: false,
+                max_threads: block.max_threads.unwrap_or(1),
+                // like AHK, a hotkey that starts with Suspend isn't suspended
+                suspend_exempt: block
+                    .functions
+                    .first()
+                    .is_some_and(|x| x.func == Some(AhkFunctions::Suspend)),
+                active_when: block.active_when.clone(),
             };
             if block.condition.clone().is_some() && block.condition.clone().unwrap().func.is_some()
             {
@@ -222,20 +362,6 @@ impl AhkFile {
                 let func = cond.func.unwrap();
                 println!("cond {:?} func {:?}", cond, func);
                 match func {
-                    AhkFunctions::Loop => {
-                        let count = cond.args[0].parse::<u32>().unwrap();
-                        let mut events = vec![];
-                        for event in &block.functions {
-                            let me = event.parse();
-                            if me.is_none() {
-                                continue;
-                            }
-                            events.push(me.unwrap());
-                        }
-                        macro_block
-                            .events
-                            .push(MacroEvent::Loop(LoopEvent { count, events }));
-                    }
                     AhkFunctions::Hotkey => {
                         let hotkey = cond.args[0].split(' ').collect::<Vec<&str>>();
                         let mut hotkeys: Vec<KeyCode> = vec![];
@@ -244,13 +370,6 @@ impl AhkFile {
                         }
                         println!("hotkey button {:?}", hotkeys);
                         macro_block.hotkey = Some(hotkeys);
-                        if !cond.special_args.is_empty() {
-                            for arg in &cond.special_args {
-                                if arg.to_lowercase().as_str() == "exitapp" {
-                                    macro_block.events.push(MacroEvent::ExitApp);
-                                }
-                            }
-                        }
                     }
                     _ => {
                         eprintln!("Condition not implemented: {:?}", func);
@@ -259,91 +378,1748 @@ impl AhkFile {
             }
             for func in &block.functions {
                 println!("block functions {:?}", func);
-                let me = func.parse();
-                if me.is_none() {
-                    continue;
-                }
-                macro_block.events.push(me.unwrap());
+                macro_block.events.extend(func.parse());
             }
             m.blocks.push(macro_block);
         }
+        // labels can come after the SetTimer that names them, so this waits
+        // until they've all been read
+        let labels = m.timers.iter().map(|x| x.name.to_lowercase()).collect();
+        let events = m.blocks.iter_mut().map(|x| &mut x.events);
+        for events in events.chain(m.timers.iter_mut().map(|x| &mut x.events)) {
+            remove_unknown_timers(events, &labels, &self.path);
+        }
         println!("{:#?}", m);
         Some(m)
     }
-}
 
-impl AhkFunction {
-    pub fn parse(&self) -> Option<MacroEvent> {
-        self.func?;
-        match self.func.unwrap() {
-            AhkFunctions::Sleep => Some(MacroEvent::SleepMs(self.args[0].parse::<u64>().unwrap())),
-            AhkFunctions::Send => {
-                let input = self.args.join(" ");
-                let input_vec: Vec<&str> = input.split(' ').collect();
-
-                let vk = match input_vec[0].to_lowercase().as_str() {
-                    "shift" => KeyCode::VK_SHIFT,
-                    "ctrl" => KeyCode::VK_CONTROL,
-                    "alt" => KeyCode::VK_MENU,
-                    "space" => KeyCode::VK_SPACE,
-                    _ => KeyCode::from_str(input_vec[0]).unwrap(),
-                };
+    /// Reads the lines of one script file into `ahk_block`/`self.blocks`,
+    /// recursing into `#Include`d files as if their text were pasted in.
+    fn parse_file(
+        &mut self,
+        path: &Path,
+        builder: &mut BlockBuilder,
+        includes: &mut Includes,
+    ) -> std::io::Result<()> {
+        let source = std::fs::read_to_string(path)?;
+        includes.stack.push(path.to_path_buf());
+        includes.seen.insert(path.to_path_buf());
 
-                let upordown = input_vec.get(1).map(|&s| {
-                    match s.to_lowercase().as_str() {
-                        "up" => KeyUpDown::Up,
-                        "down" => KeyUpDown::Down,
-                        _ => KeyUpDown::Down, // Default to "down" if an unknown value is provided
+        // comments depend on the version, so a `#Requires` has to be found
+        // before the file is split into lines
+        if self.version.is_none() {
+            self.version = source.lines().find_map(|line| {
+                let line = line.trim();
+                let directive = line.get(..9)?;
+                directive.eq_ignore_ascii_case("#requires").then(|| required_version(&line[9..]))?
+            });
+        }
+        let v2 = self.version == Some(AhkVersion::V2);
+        for Line { number, text: line } in logical_lines(&source, v2) {
+            self.parse_line(path, number, line, builder, includes);
+        }
+
+        includes.stack.pop();
+        Ok(())
+    }
+
+    /// Parses one logical line into `builder`.
+    fn parse_line(
+        &mut self,
+        path: &Path,
+        number: usize,
+        line: String,
+        builder: &mut BlockBuilder,
+        includes: &mut Includes,
+    ) {
+        let line = match self.version {
+            Some(AhkVersion::V2) => ahk_v2::lower_line(&line),
+            _ => line,
+        };
+
+        // `} else {` closes a block and carries on with the rest of the line
+        if let Some(rest) = line.strip_prefix('}').filter(|x| !x.trim().is_empty()) {
+            self.parse_line(path, number, "}".to_string(), builder, includes);
+            self.parse_line(path, number, rest.trim().to_string(), builder, includes);
+            return;
+        }
+        let mut words = line.split_whitespace();
+        let mut func = words.next().unwrap_or("").to_lowercase();
+        func = func.trim_matches(',').to_string();
+
+        if func.is_empty() {
+            return;
+        }
+        if func == "#requires" {
+            if self.version.is_none() {
+                self.version = required_version(line.trim()[func.len()..].trim());
+            }
+            return;
+        }
+        if func == "#include" || func == "#includeagain" {
+            let target = line.trim()[func.len()..].trim_start_matches([',', ' ', '\t']);
+            if let Err(e) = self.include(path, target, func == "#includeagain", builder, includes) {
+                eprintln!("{}:{}: {}", path.display(), number, e);
+            }
+            return;
+        }
+        if func.contains("dllcall") {
+            match parse_dllcall(line.trim()) {
+                Ok(dllcall) => builder.push(dllcall),
+                Err(e) => eprintln!("{}:{}: {}", path.display(), number, e),
+            }
+            return;
+        }
+        if is_hotkey(&line) {
+            match parse_hotkey(&line) {
+                Ok(hotkey) => {
+                    println!("hotkey func {:?}", hotkey);
+                    self.blocks.extend(builder.finish());
+                    // a one-line hotkey like `F1::ExitApp` has no body after it
+                    let action = hotkey.special_args.first().cloned();
+                    builder.block.condition = Some(hotkey);
+                    builder.block.max_threads = Some(self.settings.max_threads_per_hotkey);
+                    // each hotkey thread starts with the auto-execute section's CoordMode
+                    builder.coord_mode = self.settings.coord_mode;
+                    builder.block.active_when = builder.active_when.clone();
+                    if let Some(action) = action {
+                        self.parse_line(path, number, action, builder, includes);
+                        self.blocks.extend(builder.finish());
                     }
+                }
+                Err(e) => eprintln!("{}:{}: {}", path.display(), number, e),
+            }
+            return;
+        }
+        if let Some(label) = parse_label(&line) {
+            self.blocks.extend(builder.finish());
+            builder.block.condition = Some(AhkFunction {
+                func: Some(AhkFunctions::Label),
+                args: vec![label.to_string()],
+                ..Default::default()
+            });
+            builder.coord_mode = self.settings.coord_mode;
+            return;
+        }
+        if let Some((name, op, value)) = parse_assignment(&line) {
+            builder.push(AhkFunction {
+                func: Some(AhkFunctions::Assign),
+                args: vec![name.to_string(), op.to_string(), value.to_string()],
+                ..Default::default()
+            });
+            return;
+        }
+        let args = words.collect::<Vec<&str>>();
+        let mut ahk_func = AhkFunction::default();
+        match func.as_str() {
+            "sleep" => {
+                let Some(delay) = args.first() else {
+                    eprintln!("{}:{}: Sleep needs a delay", path.display(), number);
+                    return;
+                };
+                ahk_func.func = Some(AhkFunctions::Sleep);
+                ahk_func.args.push(delay.to_string());
+            }
+            "send" => {
+                ahk_func.func = Some(AhkFunctions::Send);
+                // keep the spaces, they're typed too
+                let keys = line.trim().split_once(char::is_whitespace).map_or("", |x| x.1);
+                ahk_func.args.push(keys.trim_start_matches([',', ' ', '\t']).to_string());
+            }
+            "loop" => {
+                ahk_func.func = Some(AhkFunctions::Loop);
+                let braced = args.last() == Some(&"{");
+                ahk_func.args.extend(
+                    args.iter()
+                        .take_while(|x| **x != "{")
+                        .map(|x| x.to_string()),
+                );
+                builder.open(ahk_func, braced);
+                return;
+            }
+            "if" => {
+                let condition = args.join(" ");
+                let braced = condition.ends_with('{');
+                ahk_func.func = Some(AhkFunctions::If);
+                ahk_func
+                    .args
+                    .push(condition.trim_end_matches('{').trim().to_string());
+                builder.open(ahk_func, braced);
+                return;
+            }
+            "else" => {
+                let rest = args.join(" ");
+                if let Err(e) = builder.open_else(rest == "{") {
+                    eprintln!("{}:{}: {}", path.display(), number, e);
+                    return;
+                }
+                // `else if ...` or a one-line `else Send ...`
+                if !rest.is_empty() && rest != "{" {
+                    self.parse_line(path, number, rest, builder, includes);
+                }
+                return;
+            }
+            "keywait" => {
+                ahk_func.func = Some(AhkFunctions::KeyWait);
+                let params = args.join(" ");
+                ahk_func
+                    .args
+                    .extend(params.split(',').map(|x| x.trim().to_string()));
+            }
+            "{" => {
+                // a brace that doesn't open a loop or if wraps a hotkey's body
+                builder.open_brace();
+                return;
+            }
+            "}" => {
+                if !builder.close_brace() {
+                    self.blocks.extend(builder.finish());
+                }
+                return;
+            }
+            "return" => {
+                if builder.open_blocks.is_empty() {
+                    self.blocks.extend(builder.finish());
+                }
+                return;
+            }
+            "click" => {
+                ahk_func.func = Some(AhkFunctions::Click);
+                ahk_func.args.push(args.join(" "));
+            }
+            "mousemove" => {
+                let params = args.join(" ");
+                let params = params.split(',').map(|x| x.trim()).collect::<Vec<&str>>();
+                if params.len() < 2 {
+                    eprintln!("{}:{}: MouseMove needs X and Y", path.display(), number);
+                    return;
+                }
+                let relative = params.get(3).map(|x| x.eq_ignore_ascii_case("r"));
+                ahk_func.func = Some(AhkFunctions::MouseMove);
+                ahk_func.args.push(params[0].to_string());
+                ahk_func.args.push(params[1].to_string());
+                ahk_func.args.push((relative != Some(true)).to_string());
+            }
+            "exitapp" => {
+                ahk_func.func = Some(AhkFunctions::ExitApp);
+            }
+            "run" | "runwait" => {
+                ahk_func.func = Some(if func == "run" {
+                    AhkFunctions::Run
+                } else {
+                    AhkFunctions::RunWait
                 });
+                let params = line.trim()[func.len()..].trim_start_matches([',', ' ', '\t']);
+                ahk_func.args.extend(split_command_params(params));
+            }
+            "suspend" | "pause" => {
+                ahk_func.func = Some(if func == "suspend" {
+                    AhkFunctions::Suspend
+                } else {
+                    AhkFunctions::Pause
+                });
+                let params = line.trim()[func.len()..].trim_start_matches([',', ' ', '\t']);
+                ahk_func.args.extend(split_command_params(params));
+            }
+            "reload" => {
+                ahk_func.func = Some(AhkFunctions::Reload);
+            }
+            "#if" | "#hotif" | "#ifwinactive" | "#ifwinnotactive" => {
+                let params = line.trim()[func.len()..].trim_start_matches([',', ' ', '\t']);
+                builder.active_when = if params.is_empty() {
+                    None
+                } else if func == "#if" || func == "#hotif" {
+                    match Condition::from_str(params) {
+                        Ok(condition) => Some(condition),
+                        Err(e) => {
+                            eprintln!("{}:{}: {}", path.display(), number, e);
+                            None
+                        }
+                    }
+                } else {
+                    // the WinText parameter isn't supported, only the title
+                    let win_title = split_command_params(params).remove(0);
+                    if let Err(e) = check_win_title(&win_title) {
+                        eprintln!("{}:{}: {}", path.display(), number, e);
+                        builder.active_when = None;
+                        return;
+                    }
+                    let condition = Condition::WinActive(win_title);
+                    if func == "#ifwinactive" {
+                        Some(condition)
+                    } else {
+                        Some(Condition::Not(Box::new(condition)))
+                    }
+                };
+                return;
+            }
+            "settimer" => {
+                ahk_func.func = Some(AhkFunctions::SetTimer);
+                let params = line.trim()[func.len()..].trim_start_matches([',', ' ', '\t']);
+                ahk_func.args.extend(split_command_params(params));
+            }
+            "coordmode" => {
+                // applies to the lines after it, and to every hotkey when it's
+                // in the auto-execute section
+                let params = args.join(" ");
+                let params = params.split(',').map(|x| x.trim()).collect::<Vec<&str>>();
+                let mut settings = MacroSettings::default();
+                if let Err(e) = apply_setting(&mut settings, &func, &params) {
+                    eprintln!("{}:{}: {}", path.display(), number, e);
+                    return;
+                }
+                builder.coord_mode = settings.coord_mode;
+                if builder.block.condition.is_none() {
+                    self.settings.coord_mode = settings.coord_mode;
+                }
+            }
+            "#singleinstance" | "#maxthreadsperhotkey" | "setkeydelay" | "setmousedelay" => {
+                // empty params are kept so the ones after them stay in place
+                let params = args.join(" ");
+                let params = params.split(',').map(|x| x.trim()).collect::<Vec<&str>>();
+                if let Err(e) = apply_setting(&mut self.settings, &func, &params) {
+                    eprintln!("{}:{}: {}", path.display(), number, e);
+                }
+            }
+            _ => {
+                eprintln!("Function not implemented: {:?} args {:?}", func, args);
+            }
+        }
+        if ahk_func.func.is_none() && ahk_func.args.is_empty() {
+            return;
+        }
+        builder.push(ahk_func);
+    }
 
-                Some(MacroEvent::Keybd(KeyboardEvent {
-                    key: Some(vk),
-                    key_up_down: upordown,
-                    custom_flags: None,
-                }))
+    /// Handles an `#Include`/`#IncludeAgain` directive found in `from`.
+    fn include(
+        &mut self,
+        from: &Path,
+        target: &str,
+        again: bool,
+        builder: &mut BlockBuilder,
+        includes: &mut Includes,
+    ) -> Result<(), String> {
+        let (ignore_missing, target) = match target.strip_prefix("*i") {
+            Some(rest) => (true, rest.trim_start()),
+            None => (false, target),
+        };
+        if target.starts_with('<') {
+            return Err(format!("library includes not implemented: {}", target));
+        }
+
+        let script_dir = includes.stack[0].parent().unwrap_or(Path::new(""));
+        let target = target
+            .replace("%A_ScriptDir%", &script_dir.to_string_lossy())
+            .replace("%A_LineFile%", &from.to_string_lossy())
+            .replace('\\', "/");
+        let path = normalize_path(&includes.dir.join(target));
+
+        if path.is_dir() {
+            // `#Include <dir>` changes where later relative includes resolve
+            includes.dir = path;
+            return Ok(());
+        }
+        if !path.is_file() {
+            if ignore_missing {
+                return Ok(());
             }
+            return Err(format!("included file not found: {}", path.display()));
+        }
+        // a file can't include one it's still being included from, plain
+        // #Include or not
+        if includes.stack.contains(&path) {
+            let cycle = includes
+                .stack
+                .iter()
+                .chain(std::iter::once(&path))
+                .map(|x| x.display().to_string())
+                .collect::<Vec<String>>()
+                .join(" -> ");
+            return Err(format!("include cycle: {}", cycle));
+        }
+        if !again && includes.seen.contains(&path) {
+            return Ok(());
+        }
+
+        self.parse_file(&path, builder, includes)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+impl AhkFunction {
+    /// The events the function runs, with absolute mouse coordinates in
+    /// the space `coord_mode` gives them.
+    pub fn parse(&self) -> Vec<MacroEvent> {
+        let Some(func) = self.func else {
+            return vec![];
+        };
+        match func {
+            AhkFunctions::Sleep => match self.args[0].parse::<u64>() {
+                Ok(ms) => vec![MacroEvent::SleepMs(ms)],
+                Err(_) => {
+                    eprintln!("Sleep delay not implemented: {:?}", self.args[0]);
+                    vec![]
+                }
+            },
+            AhkFunctions::Send => send_events(&self.args[0]),
             AhkFunctions::MouseMove => {
-                println!("{:?}", self.args);
-                let x = self.args[0].parse::<i32>().unwrap();
-                let y = self.args[1].parse::<i32>().unwrap();
-                let absolute = self.args[2].parse::<bool>().unwrap();
-                Some(MacroEvent::MouseMove(MouseMoveEvent { x, y, absolute }))
+                let (Ok(x), Ok(y)) = (self.args[0].parse::<i32>(), self.args[1].parse::<i32>())
+                else {
+                    eprintln!("MouseMove coordinates not implemented: {:?}", self.args);
+                    return vec![];
+                };
+                let space = if self.args[2].parse::<bool>().unwrap() {
+                    self.coord_mode.space()
+                } else {
+                    CoordSpace::Relative
+                };
+                vec![MacroEvent::MouseMove(MouseMoveEvent { x, y, space })]
             }
             AhkFunctions::Click => {
-                if !self.args.is_empty() {
-                    eprintln!("Function arguments not implemented: {:?}", self.func);
-                    return None;
-                }
-                Some(MacroEvent::MouseBtn(MouseButtonEvent {
-                    flags: MouseFlags::MOUSEEVENTF_LEFTDOWN,
-                    up_down: None,
-                }))
-            }
-            AhkFunctions::Run => {
-                println!("{:?}", self.args);
-                let args = self.args.join(" ");
-                let mut args = args
-                    .split(&[',', ' '][..])
+                let options = self.args.join(" ").to_lowercase();
+                let mut button = MouseButton::Left;
+                let mut up_down = None;
+                // (horizontal, direction)
+                let mut wheel = None;
+                let mut relative = false;
+                let mut numbers = vec![];
+                for option in options
+                    .split(|c: char| c == ',' || c.is_whitespace())
                     .filter(|x| !x.is_empty())
-                    .collect::<Vec<&str>>();
-
-                let should_hide_cmd =
-                    args.last().map(|s| s.to_lowercase().contains("hide")) == Some(true);
-                if should_hide_cmd {
-                    args.pop();
+                {
+                    match option {
+                        "left" | "l" => button = MouseButton::Left,
+                        "right" | "r" => button = MouseButton::Right,
+                        "middle" | "m" => button = MouseButton::Middle,
+                        "x1" => button = MouseButton::X1,
+                        "x2" => button = MouseButton::X2,
+                        "down" | "d" => up_down = Some(KeyUpDown::Down),
+                        "up" | "u" => up_down = Some(KeyUpDown::Up),
+                        "wheelup" | "wu" => wheel = Some((false, 1)),
+                        "wheeldown" | "wd" => wheel = Some((false, -1)),
+                        "wheelleft" | "wl" => wheel = Some((true, -1)),
+                        "wheelright" | "wr" => wheel = Some((true, 1)),
+                        "rel" | "relative" => relative = true,
+                        _ => match option.parse::<i32>() {
+                            Ok(n) => numbers.push(n),
+                            Err(_) => {
+                                eprintln!("Click option not implemented: {:?}", option);
+                                return vec![];
+                            }
+                        },
+                    }
                 }
 
-                let command = format!("{} {}", args.remove(0).trim_matches('\\'), args.join(" "));
-                Some(MacroEvent::Run(command))
+                // a lone number is the click count, otherwise X, Y and then the count
+                let (position, count) = match numbers[..] {
+                    [] => (None, 1),
+                    [count] => (None, count),
+                    [x, y] => (Some((x, y)), 1),
+                    [x, y, count, ..] => (Some((x, y)), count),
+                };
+
+                let mut events = vec![];
+                if let Some((x, y)) = position {
+                    events.push(MacroEvent::MouseMove(MouseMoveEvent {
+                        x,
+                        y,
+                        space: if relative {
+                            CoordSpace::Relative
+                        } else {
+                            self.coord_mode.space()
+                        },
+                    }));
+                }
+                if let Some((horizontal, direction)) = wheel {
+                    events.push(MacroEvent::MouseWheel(MouseWheelEvent::notches(
+                        direction * count,
+                        horizontal,
+                    )));
+                    return events;
+                }
+                let (action, count) = match (up_down, count) {
+                    (Some(KeyUpDown::Down), _) => (MouseAction::Down, 1),
+                    (Some(KeyUpDown::Up), _) => (MouseAction::Up, 1),
+                    (None, 2) => (MouseAction::DoubleClick, 1),
+                    (None, count) => (MouseAction::Click, count),
+                };
+                for _ in 0..count {
+                    events.push(MacroEvent::MouseBtn(MouseButtonEvent { button, action }));
+                }
+                events
+            }
+            AhkFunctions::If => {
+                let condition = match Condition::from_str(&self.args[0]) {
+                    Ok(condition) => condition,
+                    Err(e) => {
+                        eprintln!("If not implemented: {}", e);
+                        return vec![];
+                    }
+                };
+                vec![MacroEvent::If(IfEvent {
+                    condition,
+                    events: self.body.iter().flat_map(|x| x.parse()).collect(),
+                    else_events: self.else_body.iter().flat_map(|x| x.parse()).collect(),
+                })]
+            }
+            AhkFunctions::KeyWait => {
+                let Some(key) = self.args.first().filter(|x| !x.is_empty()) else {
+                    eprintln!("KeyWait is missing a key: {:?}", self.args);
+                    return vec![];
+                };
+                let key = key_from_name(key);
+                let mut state = KeyUpDown::Up;
+                let mut timeout_ms = None;
+                for option in self
+                    .args
+                    .get(1)
+                    .map(|x| x.as_str())
+                    .unwrap_or("")
+                    .split_whitespace()
+                {
+                    let option = option.to_lowercase();
+                    if option == "d" {
+                        state = KeyUpDown::Down;
+                    } else if let Some(seconds) = option.strip_prefix('t') {
+                        match seconds.parse::<f64>() {
+                            Ok(seconds) => timeout_ms = Some((seconds * 1000.0) as u64),
+                            Err(_) => eprintln!("Invalid KeyWait timeout: {:?}", option),
+                        }
+                    } else if option != "l" {
+                        eprintln!("KeyWait option not implemented: {:?}", option);
+                    }
+                }
+                vec![MacroEvent::WaitForKey(WaitForKeyEvent {
+                    key,
+                    state,
+                    timeout_ms,
+                })]
+            }
+            AhkFunctions::Loop => {
+                let Some(count) = self.args.first().and_then(|x| x.parse::<u32>().ok()) else {
+                    eprintln!("Loop count not implemented: {:?}", self.args);
+                    return vec![];
+                };
+                let events = self.body.iter().flat_map(|x| x.parse()).collect();
+                vec![MacroEvent::Loop(LoopEvent { count, events })]
+            }
+            AhkFunctions::Run | AhkFunctions::RunWait => {
+                // Run, Target [, WorkingDir, Options, OutputVarPID]
+                let Some((program, args)) = self.args.first().and_then(|x| split_target(x)) else {
+                    eprintln!("Run is missing a target: {:?}", self.args);
+                    return vec![];
+                };
+                let working_dir = self.args.get(1).filter(|x| !x.is_empty()).cloned();
+                let mut hide = false;
+                for option in self.args.get(2).iter().flat_map(|x| x.split_whitespace()) {
+                    match option.to_lowercase().as_str() {
+                        "hide" => hide = true,
+                        // there are no windows to size and errors are always just printed
+                        "max" | "min" | "useerrorlevel" => {}
+                        other => eprintln!("Run option not implemented: {:?}", other),
+                    }
+                }
+                let wait = self.func == Some(AhkFunctions::RunWait);
+                let pid_var = self.args.get(3).filter(|x| !x.is_empty()).cloned();
+                vec![MacroEvent::Run(RunEvent {
+                    program,
+                    args,
+                    working_dir,
+                    hide,
+                    wait,
+                    exit_code_var: wait.then(|| "ErrorLevel".to_string()),
+                    pid_var,
+                    output_var: None,
+                })]
+            }
+            AhkFunctions::DllCall => {
+                let Some((name, values)) = self.args.split_first() else {
+                    eprintln!("DllCall is missing a function");
+                    return vec![];
+                };
+                let values = values.iter().map(|x| x.parse::<i64>());
+                let Ok(values) = values.collect::<Result<Vec<i64>, _>>() else {
+                    eprintln!("DllCall arguments not implemented: {:?}", self.args);
+                    return vec![];
+                };
+                match DLLCALL_TRANSLATIONS.iter().find(|t| t.name == *name) {
+                    Some(translation) if values.len() >= translation.arg_count => {
+                        (translation.translate)(&values)
+                    }
+                    _ => {
+                        eprintln!("DllCall function not implemented: {:?}", self.args);
+                        vec![]
+                    }
+                }
+            }
+            AhkFunctions::SetTimer => {
+                // SetTimer, Label [, PeriodOnOffDelete]
+                let name = self.args.first().cloned().unwrap_or_default();
+                if name.is_empty() {
+                    eprintln!("SetTimer without a label not implemented");
+                    return vec![];
+                }
+                let period = self
+                    .args
+                    .get(1)
+                    .map(|x| x.to_lowercase())
+                    .unwrap_or_default();
+                let command = match period.as_str() {
+                    "" | "on" => TimerCommand::Start {
+                        period_ms: None,
+                        run_count: None,
+                    },
+                    "off" | "delete" => TimerCommand::Stop,
+                    // a negative period runs the timer only once
+                    _ => match period.parse::<i64>() {
+                        Ok(period) => TimerCommand::Start {
+                            period_ms: Some(period.unsigned_abs()),
+                            run_count: (period < 0).then_some(1),
+                        },
+                        Err(_) => {
+                            eprintln!("Invalid SetTimer period: {:?}", period);
+                            return vec![];
+                        }
+                    },
+                };
+                vec![MacroEvent::SetTimer(SetTimerEvent { name, command })]
+            }
+            AhkFunctions::Suspend | AhkFunctions::Pause => {
+                let suspend = self.func == Some(AhkFunctions::Suspend);
+                let mode = match self.args.first().map(|x| x.to_lowercase()).as_deref() {
+                    None | Some("") | Some("toggle") | Some("-1") => ToggleMode::Toggle,
+                    Some("on") | Some("1") => ToggleMode::On,
+                    Some("off") | Some("0") => ToggleMode::Off,
+                    // only marks the hotkey as exempt
+                    Some("permit") if suspend => return vec![],
+                    Some(other) => {
+                        eprintln!("Invalid {:?} mode: {:?}", self.func, other);
+                        return vec![];
+                    }
+                };
+                if suspend {
+                    vec![MacroEvent::Suspend(mode)]
+                } else {
+                    vec![MacroEvent::Pause(mode)]
+                }
+            }
+            AhkFunctions::Reload => {
+                vec![MacroEvent::Reload]
+            }
+            AhkFunctions::Assign => {
+                let (name, op, value) = (&self.args[0], &self.args[1], self.args[2].trim());
+                let value = if op == "=" {
+                    // legacy assignment, the value is plain text
+                    VariableValue::Text(value.to_string())
+                } else if let Some(other) = value
+                    .strip_prefix('!')
+                    .or_else(|| value.strip_prefix("not "))
+                {
+                    VariableValue::Not(other.trim().to_string())
+                } else if value.eq_ignore_ascii_case("true") {
+                    VariableValue::Text("1".to_string())
+                } else if value.eq_ignore_ascii_case("false") {
+                    VariableValue::Text("0".to_string())
+                } else if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+                    VariableValue::Text(value[1..value.len() - 1].replace("\"\"", "\""))
+                } else if value.parse::<f64>().is_ok() {
+                    VariableValue::Text(value.to_string())
+                } else {
+                    eprintln!("Expression not implemented: {} := {}", name, value);
+                    return vec![];
+                };
+                vec![MacroEvent::SetVariable(SetVariableEvent {
+                    name: name.clone(),
+                    value,
+                })]
             }
             AhkFunctions::ExitApp => {
-                Some(MacroEvent::ExitApp)
+                vec![MacroEvent::ExitApp]
             }
             _ => {
                 eprintln!("Function not implemented: {:?}", self.func);
-                None
+                vec![]
+            }
+        }
+    }
+}
+
+/// Turns the keys of a Send command into keyboard events. `{Name}`,
+/// `{Name down}`, `{Name up}` and `{Name N}` send keys by name, `^!+#` hold
+/// a modifier for the next key and other characters are typed on the
+/// current layout.
+fn send_events(keys: &str) -> Vec<MacroEvent> {
+    let mut events = vec![];
+    let mut modifiers = vec![];
+    let mut rest = keys;
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        let modifier = match c {
+            '^' => Some(KeyCode::VK_CONTROL),
+            '!' => Some(KeyCode::VK_MENU),
+            '+' => Some(KeyCode::VK_SHIFT),
+            '#' => Some(KeyCode::VK_LWIN),
+            _ => None,
+        };
+        if let Some(modifier) = modifier {
+            modifiers.push(modifier);
+            continue;
+        }
+
+        let (mut name, mut up_down, mut count) = (String::new(), None, 1);
+        match c {
+            '{' => {
+                // `{}}` sends a brace, so the name is at least one character
+                let skip = rest.chars().next().map_or(0, char::len_utf8);
+                let Some(end) = rest[skip..].find('}').map(|x| x + skip) else {
+                    eprintln!("Missing }} in Send: {:?}", keys);
+                    break;
+                };
+                let braced = &rest[..end];
+                rest = &rest[end + 1..];
+                let (key, arg) = match braced.rsplit_once(' ') {
+                    Some((key, arg)) if !key.is_empty() => (key, arg),
+                    _ => (braced, ""),
+                };
+                name = key.to_string();
+                match arg.to_lowercase().as_str() {
+                    "" => {}
+                    "down" => up_down = Some(KeyUpDown::Down),
+                    "up" => up_down = Some(KeyUpDown::Up),
+                    arg => match arg.parse() {
+                        Ok(n) => count = n,
+                        Err(_) => eprintln!("Unknown Send option: {:?}", arg),
+                    },
+                }
+            }
+            // escape sequences
+            '`' => {
+                let escaped = rest.chars().next().unwrap_or('`');
+                rest = &rest[escaped.len_utf8().min(rest.len())..];
+                name.push(match escaped {
+                    'n' => '\n',
+                    't' => '\t',
+                    c => c,
+                });
+            }
+            c => name.push(c),
+        }
+
+        let mut chars = name.chars();
+        let (key, typed_with) = match (chars.next(), chars.next()) {
+            (Some(c), None) => match Layout::current().key_for(c) {
+                Some((key, typed_with)) => (key, typed_with.keys()),
+                None => {
+                    eprintln!("Can't type {:?} on the {:?} layout", c, Layout::current());
+                    modifiers.clear();
+                    continue;
+                }
+            },
+            _ => match KeyCode::from_str(&name) {
+                Ok(key) => (key, vec![]),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    modifiers.clear();
+                    continue;
+                }
+            },
+        };
+        // keys pressed down or let go of by name are sent as they are
+        if up_down.is_none() {
+            modifiers.extend(typed_with);
+        }
+        // the numpad's Enter is the extended one, while its arrows, Home,
+        // Ins and so on are the dedicated keys without the extended flag
+        let custom_flags = if name.eq_ignore_ascii_case("NumpadEnter") {
+            Some(KeyboardFlags::KEYEVENTF_EXTENDEDKEY)
+        } else if key.numpad_name().is_some_and(|x| x.eq_ignore_ascii_case(&name)) {
+            Some(KeyboardFlags::empty())
+        } else {
+            None
+        };
+        let wheel = match key {
+            KeyCode::VK_WHEEL_UP => Some((1, false)),
+            KeyCode::VK_WHEEL_DOWN => Some((-1, false)),
+            KeyCode::VK_WHEEL_RIGHT => Some((1, true)),
+            KeyCode::VK_WHEEL_LEFT => Some((-1, true)),
+            _ => None,
+        };
+        let event = match wheel {
+            // `{WheelUp 3}` turns the wheel three notches at once
+            Some((direction, horizontal)) => {
+                let notches = direction * std::mem::replace(&mut count, 1);
+                MacroEvent::MouseWheel(MouseWheelEvent::notches(notches, horizontal))
             }
+            None => MacroEvent::Keybd(KeyboardEvent {
+                key: Some(key),
+                key_up_down: up_down,
+                custom_flags,
+            }),
+        };
+        let held = |up_down| {
+            modifiers.iter().map(move |key| {
+                MacroEvent::Keybd(KeyboardEvent {
+                    key: Some(*key),
+                    key_up_down: Some(up_down),
+                    custom_flags: None,
+                })
+            })
+        };
+        for _ in 0..count {
+            events.extend(held(KeyUpDown::Down));
+            events.push(event.clone());
+            events.extend(held(KeyUpDown::Up).rev());
+        }
+        modifiers.clear();
+    }
+    events
+}
+
+/// Looks up a key by the name used in Send, KeyWait and GetKeyState.
+pub fn key_from_name(name: &str) -> KeyCode {
+    KeyCode::from_str(name).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        KeyCode::VK_NONE
+    })
+}
+
+/// Whether a line defines a hotkey, e.g. `^a::` or `a & b:: {`.
+fn is_hotkey(line: &str) -> bool {
+    match line.find("::") {
+        Some(0) | None => false,
+        Some(end) => !line[..end].contains(['(', ',', '"']),
+    }
+}
+
+/// Splits a `Var := value` or `Var = value` line into its name, operator and
+/// value.
+fn parse_assignment(line: &str) -> Option<(&str, &str, &str)> {
+    let line = line.trim();
+    let name_end = line.find(|c: char| !(c.is_alphanumeric() || c == '_'))?;
+    let rest = line[name_end..].trim_start();
+    let op = if rest.starts_with(":=") {
+        ":="
+    } else if rest.starts_with('=') && !rest.starts_with("==") {
+        "="
+    } else {
+        return None;
+    };
+    if name_end == 0 {
+        return None;
+    }
+    Some((&line[..name_end], op, rest[op.len()..].trim()))
+}
+
+/// Returns the name of a `Label:` line.
+fn parse_label(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_suffix(':')
+        .filter(|x| !x.is_empty() && x.chars().all(|c| c.is_alphanumeric() || c == '_'))
+}
+
+/// Parses a hotkey line into a Hotkey function whose first arg is the
+/// space-separated key names, with anything after the `::` as a special arg.
+fn parse_hotkey(line: &str) -> Result<AhkFunction, String> {
+    let end = line.find("::").unwrap();
+    let (mut keys, action) = (line[..end].trim(), line[end + 2..].trim());
+    let mut hotkeys: Vec<KeyCode> = vec![];
+
+    // modifier symbols, optionally prefixed with < or > for the left/right key
+    let mut side = None;
+    while keys.len() > 1 {
+        let c = keys.chars().next().unwrap();
+        let modifier = match (c, side) {
+            ('<' | '>', _) => {
+                side = Some(c);
+                keys = &keys[1..];
+                continue;
+            }
+            // prefixes that only change how AHK registers the hotkey
+            ('*' | '~' | '$', _) => {
+                keys = &keys[1..];
+                continue;
+            }
+            ('^', None) => KeyCode::VK_CONTROL,
+            ('^', Some('<')) => KeyCode::VK_LCONTROL,
+            ('^', Some(_)) => KeyCode::VK_RCONTROL,
+            ('!', None) => KeyCode::VK_MENU,
+            ('!', Some('<')) => KeyCode::VK_LMENU,
+            ('!', Some(_)) => KeyCode::VK_RMENU,
+            ('+', None) => KeyCode::VK_SHIFT,
+            ('+', Some('<')) => KeyCode::VK_LSHIFT,
+            ('+', Some(_)) => KeyCode::VK_RSHIFT,
+            ('#', None | Some('<')) => KeyCode::VK_LWIN,
+            ('#', Some(_)) => KeyCode::VK_RWIN,
+            _ => break,
+        };
+        hotkeys.push(modifier);
+        side = None;
+        keys = &keys[1..];
+    }
+
+    // `a & b` custom combinations
+    for key in keys.split('&') {
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("invalid hotkey: {:?}", line));
+        }
+        // `; escapes the comment character
+        let key = key.strip_prefix('`').filter(|x| !x.is_empty()).unwrap_or(key);
+        hotkeys.push(KeyCode::from_str(key)?);
+    }
+
+    let mut special_args = vec![];
+    if !action.is_empty() && action != "{" {
+        special_args.push(action.to_string());
+    }
+    Ok(AhkFunction {
+        func: Some(AhkFunctions::Hotkey),
+        args: vec![hotkeys
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .join(" ")],
+        special_args,
+        ..Default::default()
+    })
+}
+
+/// Splits command parameters on commas, keeping escaped `` `, `` as commas.
+fn split_command_params(params: &str) -> Vec<String> {
+    let mut split = vec![String::new()];
+    let mut chars = params.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '`' if chars.peek() == Some(&',') => {
+                split.last_mut().unwrap().push(chars.next().unwrap());
+            }
+            ',' => split.push(String::new()),
+            _ => split.last_mut().unwrap().push(c),
+        }
+    }
+    split.iter().map(|x| x.trim().to_string()).collect()
+}
+
+/// Splits a Run target into the program and its arguments. Quoted words are
+/// kept together. AHK runs an unquoted target as one command line, so there
+/// the program is the longest prefix ending in an executable extension,
+/// which lets unquoted paths with spaces work.
+fn split_target(target: &str) -> Option<(String, Vec<String>)> {
+    const EXTENSIONS: [&str; 4] = [".exe", ".bat", ".cmd", ".com"];
+    let target = target.trim();
+    if !target.starts_with('"') {
+        let lower = target.to_lowercase();
+        let end = EXTENSIONS
+            .iter()
+            .flat_map(|ext| lower.match_indices(ext).map(|(i, _)| i + ext.len()))
+            .filter(|end| target[*end..].chars().next().is_none_or(char::is_whitespace))
+            .max();
+        if let Some(end) = end {
+            return Some((target[..end].to_string(), split_words(&target[end..])));
+        }
+    }
+    let mut words = split_words(target);
+    if words.is_empty() {
+        return None;
+    }
+    let program = words.remove(0);
+    Some((program, words))
+}
+
+/// Splits on whitespace outside of double quotes, removing the quotes.
+fn split_words(s: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = None::<String>;
+    let mut in_quotes = false;
+    for c in s.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !in_quotes => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// Applies a directive or settings command to `settings`.
+fn apply_setting(settings: &mut MacroSettings, func: &str, params: &[&str]) -> Result<(), String> {
+    // AHK delays are in ms, with -1 meaning no delay at all
+    fn delay_us(param: &str) -> Result<u64, String> {
+        let ms = param
+            .parse::<i64>()
+            .map_err(|_| format!("invalid delay: {:?}", param))?;
+        Ok(ms.max(0) as u64 * 1000)
+    }
+    // an omitted or empty param leaves its setting as it was
+    let param = |i: usize| params.get(i).copied().filter(|x| !x.is_empty());
+
+    match func {
+        "#singleinstance" => {
+            settings.single_instance = match param(0).map(|x| x.to_lowercase()).as_deref() {
+                Some("force") => SingleInstance::Force,
+                Some("ignore") => SingleInstance::Ignore,
+                Some("prompt") | None => SingleInstance::Prompt,
+                Some("off") => SingleInstance::Off,
+                Some(other) => return Err(format!("invalid #SingleInstance mode: {:?}", other)),
+            };
+        }
+        "#maxthreadsperhotkey" => {
+            let threads = param(0)
+                .and_then(|x| x.parse::<u32>().ok())
+                .ok_or_else(|| format!("invalid #MaxThreadsPerHotkey: {:?}", params))?;
+            settings.max_threads_per_hotkey = threads.clamp(1, 255);
+        }
+        "setkeydelay" => {
+            if let Some(delay) = param(0) {
+                settings.key_delay_us = delay_us(delay)?;
+            }
+            if let Some(duration) = param(1) {
+                settings.key_press_duration_us = delay_us(duration)?;
+            }
+        }
+        "setmousedelay" => {
+            if let Some(delay) = param(0) {
+                settings.mouse_delay_us = delay_us(delay)?;
+            }
+        }
+        "coordmode" => {
+            // Mouse is the only target there is here, so it's also the default
+            let target = param(0).unwrap_or("mouse").to_lowercase();
+            if target != "mouse" {
+                return Err(format!("CoordMode target not implemented: {:?}", target));
+            }
+            // omitting the second parameter means Screen
+            settings.coord_mode = match param(1).map(|x| x.to_lowercase()).as_deref() {
+                Some("screen") | None => CoordMode::Screen,
+                Some("window") | Some("relative") => CoordMode::Window,
+                Some("client") => CoordMode::Client,
+                Some(other) => return Err(format!("invalid CoordMode: {:?}", other)),
+            };
         }
+        _ => return Err(format!("setting not implemented: {:?}", func)),
+    }
+    Ok(())
+}
+
+/// A DllCall that can be converted into macro events.
+struct DllCallTranslation {
+    /// Function name, lowercased and without the `dll\` prefix.
+    name: &'static str,
+    /// Number of integer arguments the translator reads. Missing trailing
+    /// arguments are passed as 0, like the optional ones in the Win32 API.
+    arg_count: usize,
+    translate: fn(&[i64]) -> Vec<MacroEvent>,
+}
+
+const DLLCALL_TRANSLATIONS: &[DllCallTranslation] = &[
+    DllCallTranslation {
+        name: "mouse_event",
+        arg_count: 5,
+        translate: translate_mouse_event,
+    },
+    DllCallTranslation {
+        name: "keybd_event",
+        arg_count: 4,
+        translate: translate_keybd_event,
+    },
+    DllCallTranslation {
+        name: "setcursorpos",
+        arg_count: 2,
+        translate: translate_set_cursor_pos,
+    },
+    DllCallTranslation {
+        name: "sleep",
+        arg_count: 1,
+        translate: |args| vec![MacroEvent::SleepMs(args[0].max(0) as u64)],
+    },
+    // sleeps are already busy-waited, so the timer resolution doesn't matter
+    DllCallTranslation {
+        name: "timebeginperiod",
+        arg_count: 1,
+        translate: |_| vec![],
+    },
+    DllCallTranslation {
+        name: "timeendperiod",
+        arg_count: 1,
+        translate: |_| vec![],
+    },
+];
+
+/// Parses a `DllCall("dll\function", "Type", value, ...)` line into an
+/// [`AhkFunction`] whose args are the function name followed by its
+/// integer argument values.
+fn parse_dllcall(line: &str) -> Result<AhkFunction, String> {
+    let start = line.find('(').ok_or("DllCall is missing '('")?;
+    let end = line.rfind(')').ok_or("DllCall is missing ')'")?;
+    if end < start {
+        return Err("DllCall has mismatched parentheses".to_string());
+    }
+    // quoted commas, like in `"Str", "a, b"`, don't split params
+    let params = ahk_v2::split_params(&line[start + 1..end])
+        .into_iter()
+        .map(ahk_v2::unquote)
+        .collect::<Vec<String>>();
+    let Some(name) = params.first() else {
+        return Err("DllCall is missing a function".to_string());
+    };
+
+    let name = name.to_lowercase();
+    let name = name
+        .rsplit(&['\\', '/'][..])
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let Some(translation) = DLLCALL_TRANSLATIONS.iter().find(|t| t.name == name) else {
+        return Err(format!("DllCall function not implemented: {:?}", params[0]));
+    };
+
+    // params after the name alternate between type and value, with an optional
+    // trailing return type
+    let mut values = vec![];
+    for pair in params[1..].chunks_exact(2) {
+        let value = parse_int(&pair[1])
+            .ok_or_else(|| format!("DllCall argument not supported: {:?}", pair[1]))?;
+        values.push(value);
+    }
+    if values.len() > translation.arg_count {
+        eprintln!(
+            "DllCall {:?} takes {} arguments, ignoring the rest",
+            params[0], translation.arg_count
+        );
+    }
+    values.resize(translation.arg_count, 0);
+
+    let mut args = vec![name];
+    args.extend(values.iter().map(|x| x.to_string()));
+    Ok(AhkFunction {
+        func: Some(AhkFunctions::DllCall),
+        args,
+        ..Default::default()
+    })
+}
+
+/// Parses a decimal or `0x` hex integer literal.
+fn parse_int(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => s.parse::<i64>().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+// mouse_event(dwFlags, dx, dy, dwData, dwExtraInfo)
+fn translate_mouse_event(args: &[i64]) -> Vec<MacroEvent> {
+    let flags = MouseFlags::from_bits_retain(args[0] as u16);
+    let has = |flag: MouseFlags| flags.contains(flag);
+    let mut events = vec![];
+
+    if has(MouseFlags::MOUSEEVENTF_MOVE) {
+        let space = if has(MouseFlags::MOUSEEVENTF_ABSOLUTE) {
+            if has(MouseFlags::MOUSEEVENTF_VIRTUALDESK) {
+                eprintln!("mouse_event VIRTUALDESK not implemented, moving on the primary screen");
+            }
+            CoordSpace::Normalized
+        } else {
+            CoordSpace::Relative
+        };
+        events.push(MacroEvent::MouseMove(MouseMoveEvent {
+            x: args[1] as i32,
+            y: args[2] as i32,
+            space,
+        }));
+    }
+
+    // dwData says which X button it is
+    let x_button = if args[3] == 2 {
+        MouseButton::X2
+    } else {
+        MouseButton::X1
+    };
+    let buttons = [
+        MouseButton::Left,
+        MouseButton::Right,
+        MouseButton::Middle,
+        x_button,
+    ];
+    for button in buttons {
+        let (down, up) = button.flags();
+        let action = match (has(down), has(up)) {
+            (true, true) => MouseAction::Click,
+            (true, false) => MouseAction::Down,
+            (false, true) => MouseAction::Up,
+            (false, false) => continue,
+        };
+        events.push(MacroEvent::MouseBtn(MouseButtonEvent { button, action }));
+    }
+
+    if has(MouseFlags::MOUSEEVENTF_WHEEL) || has(MouseFlags::MOUSEEVENTF_HWHEEL) {
+        events.push(MacroEvent::MouseWheel(MouseWheelEvent {
+            delta: args[3] as i32,
+            horizontal: has(MouseFlags::MOUSEEVENTF_HWHEEL),
+        }));
+    }
+    events
+}
+
+// keybd_event(bVk, bScan, dwFlags, dwExtraInfo)
+fn translate_keybd_event(args: &[i64]) -> Vec<MacroEvent> {
+    let flags = KeyboardFlags::from_bits_retain(args[2] as u16);
+    let key_up_down = if flags.contains(KeyboardFlags::KEYEVENTF_KEYUP) {
+        KeyUpDown::Up
+    } else {
+        KeyUpDown::Down
+    };
+    let custom_flags = if flags.contains(KeyboardFlags::KEYEVENTF_EXTENDEDKEY) {
+        Some(KeyboardFlags::KEYEVENTF_EXTENDEDKEY)
+    } else {
+        None
+    };
+    vec![MacroEvent::Keybd(KeyboardEvent {
+        key: Some(KeyCode::from_code((args[0] & 0xFF) as u32)),
+        key_up_down: Some(key_up_down),
+        custom_flags,
+    })]
+}
+
+// SetCursorPos(X, Y)
+fn translate_set_cursor_pos(args: &[i64]) -> Vec<MacroEvent> {
+    vec![MacroEvent::MouseMove(MouseMoveEvent {
+        x: args[0] as i32,
+        y: args[1] as i32,
+        space: CoordSpace::VirtualDesk,
+    })]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::condition::KeyStateMode;
+    use crate::r#macro::MacroState;
+    use crate::screen::FixedScreen;
+    use crate::window::{ActiveWindow, FixedWindow};
+
+    /// Parses `source` as if it were the script `name`.
+    fn parse_script(name: &str, source: &str) -> Macro {
+        let path = std::env::temp_dir().join(format!("ahk-rs-test-{}.ahk", name));
+        std::fs::write(&path, source).unwrap();
+        let mut ahk = AhkFile {
+            path: path.to_string_lossy().into_owned(),
+            blocks: vec![],
+            settings: Default::default(),
+            version: None,
+            layout: Layout::Us,
+        };
+        let m = ahk.parse().unwrap();
+        std::fs::remove_file(path).unwrap();
+        m
+    }
+
+    fn sent_flags(keys: &str) -> Vec<(KeyCode, Option<KeyboardFlags>)> {
+        send_events(keys)
+            .into_iter()
+            .filter_map(|event| match event {
+                MacroEvent::Keybd(event) => Some((event.key.unwrap(), event.custom_flags)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn numpad_keys() {
+        let empty = Some(KeyboardFlags::empty());
+        let extended = Some(KeyboardFlags::KEYEVENTF_EXTENDEDKEY);
+        assert_eq!(sent_flags("{NumpadUp}"), vec![(KeyCode::VK_UP, empty)]);
+        assert_eq!(sent_flags("{NumpadDel}"), vec![(KeyCode::VK_DELETE, empty)]);
+        assert_eq!(sent_flags("{NumpadEnter}"), vec![(KeyCode::VK_RETURN, extended)]);
+        // the dedicated keys are left to work out the flag themselves
+        assert_eq!(sent_flags("{Up}"), vec![(KeyCode::VK_UP, None)]);
+        assert_eq!(sent_flags("{Del}"), vec![(KeyCode::VK_DELETE, None)]);
+        assert_eq!(sent_flags("{NumpadDiv}"), vec![(KeyCode::VK_DIVIDE, None)]);
+    }
+
+    #[test]
+    fn wheel_turns() {
+        let source = "Send {WheelUp 3}\nClick WheelDown\nClick WheelLeft 2\nSend {WheelRight}\n";
+        let wheels = parse_script("wheel", source).blocks[0]
+            .events
+            .iter()
+            .filter_map(|x| match x {
+                MacroEvent::MouseWheel(event) => Some((event.delta, event.horizontal)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(wheels, vec![(360, false), (-120, false), (-240, true), (120, true)]);
+    }
+
+    #[test]
+    fn if_win_active_hotkeys() {
+        let m = parse_script(
+            "ifwinactive",
+            "#IfWinActive ahk_class Notepad
+F1::Send a
+#IfWinNotActive, Untitled
+F2::Send b
+#If
+F3::Send c
+",
+        );
+        let state = |title: &str, class: &str| {
+            MacroState::with_providers(
+                m.settings.clone(),
+                &[],
+                Box::new(FixedWindow(Some(ActiveWindow {
+                    title: title.to_string(),
+                    class: class.to_string(),
+                    ..Default::default()
+                }))),
+                Box::new(FixedScreen::single(1920, 1080)),
+            )
+        };
+        let notepad = state("Untitled - Notepad", "Notepad");
+        let other = state("Document", "Other");
+        let active = |state: &MacroState| {
+            m.blocks
+                .iter()
+                .filter(|x| x.is_active(state))
+                .map(|x| x.hotkey.clone().unwrap()[0])
+                .collect::<Vec<KeyCode>>()
+        };
+        assert_eq!(active(&notepad), vec![KeyCode::VK_F1, KeyCode::VK_F3]);
+        assert_eq!(active(&other), vec![KeyCode::VK_F2, KeyCode::VK_F3]);
+    }
+
+    #[test]
+    fn unsupported_win_title_criteria() {
+        let source = "#IfWinActive ahk_exe notepad.exe
+F1::Send a
+#If WinActive(\"ahk_pid 4\")
+F2::Send b
+";
+        let m = parse_script("ifwinactive-exe", source);
+        assert!(m.blocks.iter().all(|x| x.active_when.is_none()));
+    }
+
+    #[test]
+    fn if_variable_hotkeys() {
+        let m = parse_script("ifvariable", "#If Toggle && !Paused\nF1::Send a\n");
+        let state = MacroState::with_providers(
+            m.settings.clone(),
+            &[],
+            Box::new(FixedWindow(None)),
+            Box::new(FixedScreen::single(1920, 1080)),
+        );
+        assert!(!m.blocks[0].is_active(&state));
+        state.set_var("Toggle", "1".to_string());
+        assert!(m.blocks[0].is_active(&state));
+        state.set_var("Paused", "1".to_string());
+        assert!(!m.blocks[0].is_active(&state));
+    }
+
+    #[test]
+    fn coord_mode_spaces() {
+        let moves = |source: &str| {
+            parse_script("coordmode", source).blocks[0]
+                .events
+                .iter()
+                .filter_map(|x| match x {
+                    MacroEvent::MouseMove(event) => Some(event.space),
+                    _ => None,
+                })
+                .collect::<Vec<CoordSpace>>()
+        };
+        let script = "MouseMove, 10, 20\nClick 30 40\nMouseMove, 5, 5, 0, R\n";
+        let relative = CoordSpace::Relative;
+        let screen = CoordSpace::VirtualDesk;
+        assert_eq!(moves(script), vec![screen, screen, relative]);
+        let window = CoordSpace::Window;
+        let source = format!("CoordMode, Mouse, Window\n{}", script);
+        assert_eq!(moves(&source), vec![window, window, relative]);
+        let client = CoordSpace::Client;
+        let source = format!("CoordMode, Mouse, Client\n{}", script);
+        assert_eq!(moves(&source), vec![client, client, relative]);
+    }
+
+    #[test]
+    fn coord_mode_applies_in_order() {
+        let source = "MouseMove, 1, 1
+CoordMode, Mouse, Window
+MouseMove, 2, 2
+F1::
+MouseMove, 3, 3
+CoordMode, Mouse, Client
+MouseMove, 4, 4
+return
+F2::MouseMove, 5, 5
+";
+        let m = parse_script("coordmode-order", source);
+        let spaces = m
+            .blocks
+            .iter()
+            .map(|block| {
+                block
+                    .events
+                    .iter()
+                    .filter_map(|x| match x {
+                        MacroEvent::MouseMove(event) => Some(event.space),
+                        _ => None,
+                    })
+                    .collect::<Vec<CoordSpace>>()
+            })
+            .collect::<Vec<_>>();
+        let (screen, window, client) =
+            (CoordSpace::VirtualDesk, CoordSpace::Window, CoordSpace::Client);
+        // hotkeys start with the auto-execute section's CoordMode
+        assert_eq!(spaces, vec![vec![screen, window], vec![window, client], vec![window]]);
+        assert_eq!(m.settings.coord_mode, CoordMode::Window);
+    }
+
+    #[test]
+    fn empty_setting_params_keep_their_place() {
+        let settings = |source: &str| parse_script("settings", source).settings;
+        let defaults = MacroSettings::default();
+
+        let s = settings("SetKeyDelay, , 50\nSend a\n");
+        assert_eq!(s.key_delay_us, defaults.key_delay_us);
+        assert_eq!(s.key_press_duration_us, 50_000);
+        let s = settings("SetKeyDelay, 30, 50\nSetKeyDelay, 10\nSetKeyDelay, , -1\nSend a\n");
+        assert_eq!((s.key_delay_us, s.key_press_duration_us), (10_000, 0));
+
+        let s = settings("CoordMode, Mouse, Client\nCoordMode, , Screen\nSend a\n");
+        assert_eq!(s.coord_mode, CoordMode::Screen);
+        let s = settings("CoordMode, , Window\nSend a\n");
+        assert_eq!(s.coord_mode, CoordMode::Window);
+    }
+
+    #[test]
+    fn requires_versions() {
+        let v1 = Some(AhkVersion::V1);
+        let v2 = Some(AhkVersion::V2);
+        for (requirement, version) in [
+            ("AutoHotkey v2.0", v2),
+            ("AutoHotkey v2", v2),
+            ("autohotkey >=2.0", v2),
+            ("AutoHotkey >=v2.0-a 64-bit", v2),
+            ("AutoHotkey >2.0", v2),
+            ("AutoHotkey ^2.1", v2),
+            ("AutoHotkey 2.0.10", v2),
+            ("AutoHotkey v1.1.33+", v1),
+            ("AutoHotkey >=1.1.20", v1),
+            ("AutoHotkey <2.0", v1),
+            ("AutoHotkey <=2.0", v2),
+            ("AutoHotkey v1.2", v1),
+            ("AutoHotkey", None),
+            ("AutoHotkey 64-bit", None),
+            ("SomethingElse v2.0", None),
+        ] {
+            assert_eq!(required_version(requirement), version, "{}", requirement);
+        }
+    }
+
+    /// Writes `files` into a new temp directory and parses its `main.ahk`,
+    /// returning the sleeps, which the files use to mark where they are.
+    fn parse_includes(name: &str, files: &[(&str, &str)]) -> Vec<u64> {
+        let dir = std::env::temp_dir().join(format!("ahk-rs-test-{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (file, source) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, source).unwrap();
+        }
+        let mut ahk = AhkFile {
+            path: dir.join("main.ahk").to_string_lossy().into_owned(),
+            blocks: vec![],
+            settings: Default::default(),
+            version: None,
+            layout: Layout::Us,
+        };
+        let m = ahk.parse().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        m.blocks
+            .iter()
+            .flat_map(|x| &x.events)
+            .filter_map(|x| match x {
+                MacroEvent::SleepMs(ms) => Some(*ms),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn includes() {
+        let main = "Sleep, 1
+#Include *i missing.ahk
+#Include %A_ScriptDir%\\lib\\a.ahk
+#Include lib\\a.ahk
+#IncludeAgain lib\\a.ahk
+Sleep, 4
+";
+        let a = "Sleep, 2\n#Include %A_LineFile%\\..\\b.ahk\n";
+        let files = [("main.ahk", main), ("lib/a.ahk", a), ("lib/b.ahk", "Sleep, 3\n")];
+        // a plain #Include reads a file once, and b is only ever read once
+        assert_eq!(parse_includes("includes", &files), vec![1, 2, 3, 2, 4]);
+
+        let main = "#Include lib\nSleep, 1\n#Include a.ahk\n#Include <a>\nSleep, 4\n";
+        let files = [("main.ahk", main), ("lib/a.ahk", "Sleep, 2\n")];
+        assert_eq!(parse_includes("include-dir", &files), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn include_errors() {
+        let dir = std::env::temp_dir().join("ahk-rs-test-include-errors");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.ahk");
+        let a = dir.join("a.ahk");
+        std::fs::write(&main, "").unwrap();
+        std::fs::write(&a, "").unwrap();
+        let mut ahk = AhkFile {
+            path: main.to_string_lossy().into_owned(),
+            blocks: vec![],
+            settings: Default::default(),
+            version: None,
+            layout: Layout::Us,
+        };
+        let mut include = |target: &str, again: bool| {
+            // as if main.ahk had included a.ahk, which is now including `target`
+            let mut includes = Includes {
+                stack: vec![main.clone(), a.clone()],
+                dir: dir.clone(),
+                ..Default::default()
+            };
+            let mut builder = BlockBuilder::default();
+            ahk.include(&a, target, again, &mut builder, &mut includes)
+        };
+        let (main_name, a_name) = (main.display(), a.display());
+        let cycle = format!("include cycle: {} -> {} -> {}", main_name, a_name, main_name);
+        assert_eq!(include("main.ahk", false), Err(cycle.clone()));
+        assert_eq!(include("main.ahk", true), Err(cycle));
+        assert!(include("a.ahk", false).unwrap_err().starts_with("include cycle"));
+        assert_eq!(
+            include("<lib>", false),
+            Err("library includes not implemented: <lib>".to_string())
+        );
+        assert!(include("missing.ahk", false).unwrap_err().starts_with("included file not found"));
+        assert_eq!(include("*i missing.ahk", false), Ok(()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn set_timer_labels() {
+        let source = "SetTimer, Tick, 100
+SetTimer, Missing, 100
+Loop, 2
+{
+    SetTimer, Nope, Off
+}
+F1::SetTimer, tick, -50
+F2::SetTimer, Tick, Off
+Tick:
+Send a
+return
+";
+        let m = parse_script("settimer", source);
+        let set_timer = |name: &str, command| {
+            MacroEvent::SetTimer(SetTimerEvent {
+                name: name.to_string(),
+                command,
+            })
+        };
+        let start = |period_ms, run_count| TimerCommand::Start {
+            period_ms: Some(period_ms),
+            run_count,
+        };
+        let events = m.blocks.iter().map(|x| x.events.clone()).collect::<Vec<_>>();
+        let empty_loop = MacroEvent::Loop(LoopEvent {
+            count: 2,
+            events: vec![],
+        });
+        assert_eq!(
+            events,
+            vec![
+                vec![set_timer("Tick", start(100, None)), empty_loop],
+                vec![set_timer("tick", start(50, Some(1)))],
+                vec![set_timer("Tick", TimerCommand::Stop)],
+            ]
+        );
+        assert_eq!(m.timers.len(), 1);
+        assert_eq!(m.timers[0].name, "Tick");
+    }
+
+    #[test]
+    fn dll_calls() {
+        let call = |line: &str| parse_dllcall(line).map(|x| x.parse());
+        let move_to = |x, y, space| MacroEvent::MouseMove(MouseMoveEvent { x, y, space });
+        let button = |button, action| MacroEvent::MouseBtn(MouseButtonEvent { button, action });
+
+        let line = "DllCall(\"mouse_event\", \"UInt\", 0x0001, \"Int\", 10, \"Int\", -5)";
+        assert_eq!(call(line), Ok(vec![move_to(10, -5, CoordSpace::Relative)]));
+        let line = "DllCall(\"user32\\mouse_event\", \"UInt\", 0x8007, \"Int\", 100, \"Int\", 200)";
+        let click = vec![
+            move_to(100, 200, CoordSpace::Normalized),
+            button(MouseButton::Left, MouseAction::Click),
+        ];
+        assert_eq!(call(line), Ok(click));
+        let line = "DllCall(\"mouse_event\", \"UInt\", 0x80, \"Int\", 0, \"Int\", 0, \"UInt\", 2)";
+        assert_eq!(call(line), Ok(vec![button(MouseButton::X2, MouseAction::Down)]));
+        let line = r#"DllCall("mouse_event", "UInt", 0x1000, "Int", 0, "Int", 0, "Int", -120)"#;
+        let wheel = MouseWheelEvent {
+            delta: -120,
+            horizontal: true,
+        };
+        assert_eq!(call(line), Ok(vec![MacroEvent::MouseWheel(wheel)]));
+
+        let key = |key_up_down, custom_flags| {
+            MacroEvent::Keybd(KeyboardEvent {
+                key: Some(KeyCode::VK_A),
+                key_up_down: Some(key_up_down),
+                custom_flags,
+            })
+        };
+        let line = r#"r := DllCall("keybd_event", "UChar", 0x41, "UChar", 0, "UInt", 2, "Ptr", 0)"#;
+        assert_eq!(call(line), Ok(vec![key(KeyUpDown::Up, None)]));
+        let line = "DllCall(\"keybd_event\", \"UChar\", 65, \"UChar\", 0, \"UInt\", 1)";
+        let extended = Some(KeyboardFlags::KEYEVENTF_EXTENDEDKEY);
+        assert_eq!(call(line), Ok(vec![key(KeyUpDown::Down, extended)]));
+
+        let line = "DllCall(\"SetCursorPos\", \"int\", 100, \"int\", 200)";
+        assert_eq!(call(line), Ok(vec![move_to(100, 200, CoordSpace::VirtualDesk)]));
+        // a trailing return type has no value
+        let line = "DllCall(\"Sleep\", \"UInt\", 50, \"Int\")";
+        assert_eq!(call(line), Ok(vec![MacroEvent::SleepMs(50)]));
+        assert_eq!(call("DllCall(\"Winmm\\timeBeginPeriod\", \"UInt\", 1)"), Ok(vec![]));
+        assert_eq!(call("DllCall(\"Winmm\\timeEndPeriod\", \"UInt\", 1)"), Ok(vec![]));
+    }
+
+    #[test]
+    fn dll_call_errors() {
+        assert_eq!(
+            parse_dllcall("DllCall(\"MessageBox\", \"Ptr\", 0, \"Str\", \"Hi\")"),
+            Err("DllCall function not implemented: \"MessageBox\"".to_string())
+        );
+        // the comma in the string doesn't split it
+        assert_eq!(
+            parse_dllcall("DllCall(\"Sleep\", \"Str\", \"a, b\")"),
+            Err("DllCall argument not supported: \"a, b\"".to_string())
+        );
+        assert!(parse_dllcall("DllCall()").is_err());
+        assert!(parse_dllcall("DllCall").is_err());
+        // functions that didn't come from parse_dllcall are skipped, not unwrapped
+        let func = |args: &[&str]| AhkFunction {
+            func: Some(AhkFunctions::DllCall),
+            args: args.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        };
+        assert_eq!(func(&["sleep", "x"]).parse(), vec![]);
+        assert_eq!(func(&["sleep"]).parse(), vec![]);
+        assert_eq!(func(&[]).parse(), vec![]);
+    }
+
+    #[test]
+    fn run_targets_and_pid() {
+        let split = |target: &str| split_target(target).unwrap();
+        let words = |words: &[&str]| words.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(split("notepad.exe a.txt"), ("notepad.exe".to_string(), words(&["a.txt"])));
+        assert_eq!(
+            split(r#""C:\Program Files\app.exe" --flag "two words""#),
+            (r"C:\Program Files\app.exe".to_string(), words(&["--flag", "two words"]))
+        );
+        // unquoted paths run up to the last executable extension
+        assert_eq!(
+            split(r"C:\Program Files\app.exe"),
+            (r"C:\Program Files\app.exe".to_string(), words(&[]))
+        );
+        assert_eq!(
+            split(r"C:\My Tools\build.CMD --release C:\out dir"),
+            (r"C:\My Tools\build.CMD".to_string(), words(&["--release", r"C:\out", "dir"]))
+        );
+        assert_eq!(
+            split(r"C:\a.exe.d\run.bat x"),
+            (r"C:\a.exe.d\run.bat".to_string(), words(&["x"]))
+        );
+        assert_eq!(split("python script.py"), ("python".to_string(), words(&["script.py"])));
+        assert_eq!(split_target("  "), None);
+
+        let m = parse_script("run", "RunWait, tool.exe, C:\\, Hide, Pid\nRun, other.exe,,, Pid2\n");
+        let runs = m.blocks[0]
+            .events
+            .iter()
+            .filter_map(|x| match x {
+                MacroEvent::Run(event) => Some(event.clone()),
+                _ => None,
+            })
+            .collect::<Vec<RunEvent>>();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].pid_var.as_deref(), Some("Pid"));
+        assert_eq!(runs[0].output_var, None);
+        assert!(runs[0].wait && runs[0].hide);
+        assert_eq!(runs[1].pid_var.as_deref(), Some("Pid2"));
+        assert_eq!(runs[1].exit_code_var, None);
+    }
+
+    #[test]
+    fn sleeps_without_a_number_are_skipped() {
+        let source = "#Requires AutoHotkey v2.0\nSleep(delay)\nSleep\nSleep(50)\n";
+        let m = parse_script("sleep-v2", source);
+        assert_eq!(m.blocks[0].events, vec![MacroEvent::SleepMs(50)]);
+        let m = parse_script("sleep-v1", "Sleep, %delay%\nSleep\nSleep, 20\n");
+        assert_eq!(m.blocks[0].events, vec![MacroEvent::SleepMs(20)]);
+    }
+
+    #[test]
+    fn key_wait_options() {
+        let wait = |options: &str| {
+            let source = format!("KeyWait, LButton{}\nKeyWait\n", options);
+            let events = parse_script("keywait", &source).blocks[0].events.clone();
+            assert_eq!(events.len(), 1);
+            match events[0] {
+                MacroEvent::WaitForKey(event) => event,
+                ref other => panic!("not a WaitForKey: {:?}", other),
+            }
+        };
+        let event = wait("");
+        assert_eq!(event.key, KeyCode::VK_LBUTTON);
+        assert_eq!((event.state, event.timeout_ms), (KeyUpDown::Up, None));
+        let event = wait(", D");
+        assert_eq!((event.state, event.timeout_ms), (KeyUpDown::Down, None));
+        // L only asks for the logical state, which is all there is
+        let event = wait(", L");
+        assert_eq!((event.state, event.timeout_ms), (KeyUpDown::Up, None));
+        let event = wait(", D T0.5");
+        assert_eq!((event.state, event.timeout_ms), (KeyUpDown::Down, Some(500)));
+        let event = wait(", t3 l");
+        assert_eq!((event.state, event.timeout_ms), (KeyUpDown::Up, Some(3000)));
+        assert_eq!(wait(", Tx").timeout_ms, None);
+    }
+
+    #[test]
+    fn get_key_state_conditions() {
+        let condition = |source: &str| {
+            match &parse_script("getkeystate", source).blocks[0].events[0] {
+                MacroEvent::If(event) => event.condition.clone(),
+                other => panic!("not an If: {:?}", other),
+            }
+        };
+        let shift = Condition::KeyState(KeyCode::VK_SHIFT, KeyStateMode::Down);
+        let source = "if GetKeyState(\"Shift\", \"P\")\n    Send a\n";
+        assert_eq!(condition(source), shift);
+        let source = "if GetKeyState(\"Shift\")\n    Send a\n";
+        assert_eq!(condition(source), shift);
+        let source = "if !GetKeyState(\"CapsLock\", \"T\") && GetKeyState(\"Shift\", \"P\") {\n\
+            Send a\n}\n";
+        let caps = Condition::KeyState(KeyCode::VK_CAPITAL, KeyStateMode::Toggled);
+        assert_eq!(condition(source), Condition::And(vec![Condition::Not(Box::new(caps)), shift]));
+        assert!(parse_script("getkeystate", "if GetKeyState(\"Shift\", \"X\")\n    Send a\n")
+            .blocks[0]
+            .events
+            .is_empty());
     }
 }
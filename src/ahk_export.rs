@@ -0,0 +1,551 @@
+// Generates an AutoHotkey v1 script from a Macro, the reverse of ahk.rs.
+// The output only uses commands that AhkFile can parse back.
+
+use std::fmt::Write;
+
+use crate::{
+    condition::{Condition, KeyStateMode},
+    keycodes::{KeyCode, KeyUpDown, KeyboardFlags},
+    layout::{self, Layout},
+    macro_events::{
+        CoordSpace, MacroEvent, MouseAction, MouseButton, ToggleMode, VariableValue, WHEEL_DELTA,
+    },
+    r#macro::{CoordMode, Macro, MacroBlock, MacroSettings, SingleInstance},
+    timer::{MacroTimer, TimerCommand},
+};
+
+/// Writes a macro as an AHK v1 script, naming keys by what they type on
+/// `layout`.
+pub fn to_ahk(m: &Macro, layout: Layout) -> String {
+    layout::with_layout(layout, || write_macro(m))
+}
+
+fn write_macro(m: &Macro) -> String {
+    let mut out = String::new();
+    write_settings(&mut out, &m.settings);
+    // timers that start with the macro are turned on by the auto-execute section
+    for timer in m.timers.iter().filter(|x| x.enabled) {
+        let command = TimerCommand::Start {
+            period_ms: Some(timer.period_ms),
+            run_count: timer.run_count,
+        };
+        writeln!(out, "{}", set_timer(&timer.name, &command)).unwrap();
+    }
+
+    let mut max_threads = 1;
+    let mut active_when = None;
+    // the auto-execute section ends at the first hotkey, so blocks without
+    // one go first
+    let (auto_exec, hotkeys): (Vec<&MacroBlock>, Vec<&MacroBlock>) =
+        m.blocks.iter().partition(|x| x.hotkey.is_none());
+    for block in auto_exec.into_iter().chain(hotkeys) {
+        if block.hotkey.is_some() && block.max_threads != max_threads {
+            max_threads = block.max_threads;
+            writeln!(out, "#MaxThreadsPerHotkey {}", max_threads).unwrap();
+        }
+        if block.hotkey.is_some() && block.active_when.as_ref() != active_when {
+            active_when = block.active_when.as_ref();
+            match active_when {
+                Some(active_when) => writeln!(out, "#If {}", condition(active_when)).unwrap(),
+                None => out.push_str("#If\n"),
+            }
+        }
+        write_block(&mut out, block, m.settings.coord_mode);
+    }
+    if active_when.is_some() {
+        out.push_str("#If\n");
+    }
+    if m.settings.max_threads_per_hotkey != max_threads {
+        writeln!(
+            out,
+            "#MaxThreadsPerHotkey {}",
+            m.settings.max_threads_per_hotkey
+        )
+        .unwrap();
+    }
+    for timer in &m.timers {
+        write_timer(&mut out, timer, m.settings.coord_mode);
+    }
+    out
+}
+
+fn write_settings(out: &mut String, settings: &MacroSettings) {
+    let default = MacroSettings::default();
+    let single_instance = match settings.single_instance {
+        SingleInstance::Force => "Force",
+        SingleInstance::Ignore => "Ignore",
+        SingleInstance::Prompt => "Prompt",
+        SingleInstance::Off => "Off",
+    };
+    writeln!(out, "#SingleInstance {}", single_instance).unwrap();
+
+    // AHK delays are whole milliseconds
+    if settings.key_press_duration_us != default.key_press_duration_us {
+        writeln!(
+            out,
+            "SetKeyDelay, {}, {}",
+            settings.key_delay_us / 1000,
+            settings.key_press_duration_us / 1000
+        )
+        .unwrap();
+    } else if settings.key_delay_us != default.key_delay_us {
+        writeln!(out, "SetKeyDelay, {}", settings.key_delay_us / 1000).unwrap();
+    }
+    if settings.mouse_delay_us != default.mouse_delay_us {
+        writeln!(out, "SetMouseDelay, {}", settings.mouse_delay_us / 1000).unwrap();
+    }
+    writeln!(out, "CoordMode, Mouse, {}", coord_mode_name(settings.coord_mode)).unwrap();
+    out.push('\n');
+}
+
+fn coord_mode_name(coord_mode: CoordMode) -> &'static str {
+    match coord_mode {
+        CoordMode::Screen => "Screen",
+        CoordMode::Window => "Window",
+        CoordMode::Client => "Client",
+    }
+}
+
+/// Each hotkey and timer starts out in `coord_mode`, the auto-execute
+/// section's CoordMode.
+fn write_block(out: &mut String, block: &MacroBlock, coord_mode: CoordMode) {
+    if let Some(hotkey) = &block.hotkey {
+        writeln!(out, "{}::", hotkey_name(hotkey)).unwrap();
+    }
+    if block.suspend_exempt && !matches!(block.events.first(), Some(MacroEvent::Suspend(_))) {
+        out.push_str("Suspend, Permit\n");
+    }
+    write_body(out, &block.events, 0, coord_mode);
+    out.push_str("return\n\n");
+}
+
+fn write_timer(out: &mut String, timer: &MacroTimer, coord_mode: CoordMode) {
+    writeln!(out, "{}:", timer.name).unwrap();
+    write_body(out, &timer.events, 0, coord_mode);
+    out.push_str("return\n\n");
+}
+
+fn set_timer(name: &str, command: &TimerCommand) -> String {
+    let period = match command {
+        TimerCommand::Start {
+            period_ms: None, ..
+        } => "On".to_string(),
+        TimerCommand::Start {
+            period_ms: Some(period_ms),
+            run_count,
+        } => {
+            if run_count.is_some_and(|x| x != 1) {
+                eprintln!(
+                    "Timer {:?} runs {:?} times, AHK can only run it once or forever",
+                    name, run_count
+                );
+            }
+            match run_count {
+                Some(_) => format!("-{}", period_ms),
+                None => period_ms.to_string(),
+            }
+        }
+        TimerCommand::Stop => "Off".to_string(),
+    };
+    format!("SetTimer, {}, {}", name, period)
+}
+
+/// Writes the events of a block or a Loop or If body. A CoordMode changed in
+/// them is changed back at the end, so it doesn't leak into the lines after
+/// a body or, from the auto-execute section, into every hotkey.
+fn write_body(out: &mut String, events: &[MacroEvent], depth: usize, coord_mode: CoordMode) {
+    let mut body_coord_mode = coord_mode;
+    for event in events {
+        write_event(out, event, depth, &mut body_coord_mode);
+    }
+    if body_coord_mode != coord_mode {
+        let indent = "    ".repeat(depth);
+        writeln!(out, "{}CoordMode, Mouse, {}", indent, coord_mode_name(coord_mode)).unwrap();
+    }
+}
+
+/// Writes one event, starting with a CoordMode line if it's an absolute
+/// mouse move in another space than `coord_mode`.
+fn write_event(out: &mut String, event: &MacroEvent, depth: usize, coord_mode: &mut CoordMode) {
+    let indent = "    ".repeat(depth);
+    match event {
+        MacroEvent::SleepMs(ms) | MacroEvent::PreciseSleep(ms) | MacroEvent::LossySleep(ms) => {
+            writeln!(out, "{}Sleep, {}", indent, ms).unwrap();
+        }
+        MacroEvent::Keybd(keybd_event) => {
+            let key = keybd_event.key.unwrap_or(KeyCode::VK_NONE);
+            let extended = keybd_event
+                .custom_flags
+                .map(|x| x.contains(KeyboardFlags::KEYEVENTF_EXTENDEDKEY));
+            // the numpad's keys are only told apart by the extended flag
+            let key = match (key.numpad_name(), extended) {
+                _ if key == KeyCode::VK_RETURN && extended == Some(true) => {
+                    "NumpadEnter".to_string()
+                }
+                (Some(name), Some(false)) => name.to_string(),
+                _ => key.to_ahk_name(),
+            };
+            match keybd_event.key_up_down {
+                Some(KeyUpDown::Down) => writeln!(out, "{}Send {{{} down}}", indent, key),
+                Some(KeyUpDown::Up) => writeln!(out, "{}Send {{{} up}}", indent, key),
+                None => writeln!(out, "{}Send {{{}}}", indent, key),
+            }
+            .unwrap();
+        }
+        MacroEvent::MouseMove(mouse_move_event) => {
+            let space = match mouse_move_event.space {
+                // the primary screen's top left is 0, 0 on the virtual desktop too
+                CoordSpace::Pixels | CoordSpace::VirtualDesk => Some(CoordMode::Screen),
+                CoordSpace::Window => Some(CoordMode::Window),
+                CoordSpace::Client => Some(CoordMode::Client),
+                CoordSpace::Relative => None,
+                CoordSpace::Normalized => {
+                    eprintln!(
+                        "MouseMove to {}, {} is in normalized units, AHK only takes pixels",
+                        mouse_move_event.x, mouse_move_event.y
+                    );
+                    return;
+                }
+            };
+            if space.is_some_and(|x| x != *coord_mode) {
+                *coord_mode = space.unwrap();
+                writeln!(out, "{}CoordMode, Mouse, {}", indent, coord_mode_name(*coord_mode))
+                    .unwrap();
+            }
+            writeln!(
+                out,
+                "{}MouseMove, {}, {}{}",
+                indent,
+                mouse_move_event.x,
+                mouse_move_event.y,
+                if space.is_some() { "" } else { ", 0, R" }
+            )
+            .unwrap();
+        }
+        MacroEvent::MouseBtn(mouse_btn_event) => {
+            let button = match mouse_btn_event.button {
+                MouseButton::Left => "Left",
+                MouseButton::Right => "Right",
+                MouseButton::Middle => "Middle",
+                MouseButton::X1 => "X1",
+                MouseButton::X2 => "X2",
+            };
+            let action = match mouse_btn_event.action {
+                MouseAction::Down => " Down",
+                MouseAction::Up => " Up",
+                MouseAction::Click => "",
+                MouseAction::DoubleClick => " 2",
+            };
+            writeln!(out, "{}Click {}{}", indent, button, action).unwrap();
+        }
+        MacroEvent::MouseWheel(mouse_wheel_event) => {
+            let direction = match (mouse_wheel_event.horizontal, mouse_wheel_event.delta < 0) {
+                (false, false) => "WheelUp",
+                (false, true) => "WheelDown",
+                (true, false) => "WheelRight",
+                (true, true) => "WheelLeft",
+            };
+            let notches = (mouse_wheel_event.delta.abs() / WHEEL_DELTA).max(1);
+            writeln!(out, "{}Click {} {}", indent, direction, notches).unwrap();
+        }
+        MacroEvent::Run(run_event) => {
+            let target = std::iter::once(&run_event.program)
+                .chain(&run_event.args)
+                .map(|x| quote_word(x))
+                .collect::<Vec<String>>()
+                .join(" ");
+            let mut params = vec![
+                target,
+                run_event.working_dir.clone().unwrap_or_default(),
+                if run_event.hide { "Hide" } else { "" }.to_string(),
+                run_event.pid_var.clone().unwrap_or_default(),
+            ];
+            if let Some(output_var) = &run_event.output_var {
+                eprintln!("RunWait output variable has no AHK equivalent: {:?}", output_var);
+            }
+            while params.last().is_some_and(|x| x.is_empty()) {
+                params.pop();
+            }
+            let params = params
+                .iter()
+                .map(|x| x.replace(',', "`,"))
+                .collect::<Vec<String>>()
+                .join(", ");
+            let command = if run_event.wait { "RunWait" } else { "Run" };
+            writeln!(out, "{}{}, {}", indent, command, params).unwrap();
+        }
+        MacroEvent::Loop(loop_event) => {
+            writeln!(out, "{}Loop, {}", indent, loop_event.count).unwrap();
+            writeln!(out, "{}{{", indent).unwrap();
+            write_body(out, &loop_event.events, depth + 1, *coord_mode);
+            writeln!(out, "{}}}", indent).unwrap();
+        }
+        MacroEvent::If(if_event) => {
+            writeln!(out, "{}if {}", indent, condition(&if_event.condition)).unwrap();
+            writeln!(out, "{}{{", indent).unwrap();
+            write_body(out, &if_event.events, depth + 1, *coord_mode);
+            writeln!(out, "{}}}", indent).unwrap();
+            if !if_event.else_events.is_empty() {
+                writeln!(out, "{}else", indent).unwrap();
+                writeln!(out, "{}{{", indent).unwrap();
+                write_body(out, &if_event.else_events, depth + 1, *coord_mode);
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+        }
+        MacroEvent::WaitForKey(wait_for_key_event) => {
+            let mut options = vec![];
+            if wait_for_key_event.state == KeyUpDown::Down {
+                options.push("D".to_string());
+            }
+            if let Some(timeout_ms) = wait_for_key_event.timeout_ms {
+                options.push(format!("T{}", timeout_ms as f64 / 1000.0));
+            }
+            write!(
+                out,
+                "{}KeyWait, {}",
+                indent,
+                wait_for_key_event.key.to_ahk_name()
+            )
+            .unwrap();
+            if !options.is_empty() {
+                write!(out, ", {}", options.join(" ")).unwrap();
+            }
+            out.push('\n');
+        }
+        MacroEvent::SetTimer(set_timer_event) => {
+            writeln!(
+                out,
+                "{}{}",
+                indent,
+                set_timer(&set_timer_event.name, &set_timer_event.command)
+            )
+            .unwrap();
+        }
+        MacroEvent::SetVariable(set_variable_event) => {
+            let value = match &set_variable_event.value {
+                VariableValue::Text(text) => format!("\"{}\"", text.replace('"', "\"\"")),
+                VariableValue::Not(name) => format!("!{}", name),
+            };
+            writeln!(out, "{}{} := {}", indent, set_variable_event.name, value).unwrap();
+        }
+        MacroEvent::Suspend(mode) => {
+            writeln!(out, "{}Suspend, {}", indent, toggle_mode(*mode)).unwrap();
+        }
+        MacroEvent::Pause(mode) => {
+            writeln!(out, "{}Pause, {}", indent, toggle_mode(*mode)).unwrap();
+        }
+        MacroEvent::Reload => {
+            writeln!(out, "{}Reload", indent).unwrap();
+        }
+        MacroEvent::ExitApp => {
+            writeln!(out, "{}ExitApp", indent).unwrap();
+        }
+    }
+}
+
+/// Quotes a Run target word if it wouldn't survive being split on spaces.
+fn quote_word(word: &str) -> String {
+    if word.is_empty() || word.contains(char::is_whitespace) {
+        format!("\"{}\"", word)
+    } else {
+        word.to_string()
+    }
+}
+
+fn toggle_mode(mode: ToggleMode) -> &'static str {
+    match mode {
+        ToggleMode::On => "On",
+        ToggleMode::Off => "Off",
+        ToggleMode::Toggle => "Toggle",
+    }
+}
+
+fn condition(condition: &Condition) -> String {
+    match condition {
+        Condition::KeyState(key, mode) => {
+            let mode = match mode {
+                KeyStateMode::Down => "P",
+                KeyStateMode::Toggled => "T",
+            };
+            format!("GetKeyState(\"{}\", \"{}\")", key.to_ahk_name(), mode)
+        }
+        Condition::Variable(name) => name.clone(),
+        Condition::WinActive(win_title) => format!("WinActive(\"{}\")", win_title),
+        Condition::Not(condition) => format!("!({})", self::condition(condition)),
+        Condition::And(conditions) => format!(
+            "({})",
+            conditions
+                .iter()
+                .map(self::condition)
+                .collect::<Vec<String>>()
+                .join(" && ")
+        ),
+        Condition::Or(conditions) => format!(
+            "({})",
+            conditions
+                .iter()
+                .map(self::condition)
+                .collect::<Vec<String>>()
+                .join(" || ")
+        ),
+    }
+}
+
+/// Builds a hotkey label like `^<+a` or `a & b` from a list of keys.
+fn hotkey_name(hotkey: &[KeyCode]) -> String {
+    let mut modifiers = String::new();
+    let mut keys = vec![];
+    for key in hotkey {
+        match key {
+            KeyCode::VK_CONTROL => modifiers.push('^'),
+            KeyCode::VK_LCONTROL => modifiers.push_str("<^"),
+            KeyCode::VK_RCONTROL => modifiers.push_str(">^"),
+            KeyCode::VK_MENU => modifiers.push('!'),
+            KeyCode::VK_LMENU => modifiers.push_str("<!"),
+            KeyCode::VK_RMENU => modifiers.push_str(">!"),
+            KeyCode::VK_SHIFT => modifiers.push('+'),
+            KeyCode::VK_LSHIFT => modifiers.push_str("<+"),
+            KeyCode::VK_RSHIFT => modifiers.push_str(">+"),
+            KeyCode::VK_LWIN => modifiers.push('#'),
+            KeyCode::VK_RWIN => modifiers.push_str(">#"),
+            // a bare `;` would start a comment
+            _ if Layout::current().char_for(*key) == Some(';') => keys.push("`;".to_string()),
+            _ => keys.push(key.to_ahk_name()),
+        }
+    }
+    // AHK combinations are limited to two keys
+    if keys.len() > 2 {
+        eprintln!("Hotkey has more than two keys, dropping {:?}", &keys[2..]);
+        keys.truncate(2);
+    }
+    format!("{}{}", modifiers, keys.join(" & "))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ahk::AhkFile;
+    use std::path::Path;
+
+    fn parse(path: &Path) -> Macro {
+        let mut ahk = AhkFile {
+            path: path.to_string_lossy().into_owned(),
+            blocks: vec![],
+            settings: Default::default(),
+            version: None,
+            layout: Layout::Us,
+        };
+        ahk.parse().unwrap()
+    }
+
+    #[test]
+    fn semicolon_hotkeys_are_escaped() {
+        let name = |layout, keys: &[KeyCode]| layout::with_layout(layout, || hotkey_name(keys));
+        let us_semicolon = [KeyCode::VK_CONTROL, KeyCode::VK_OEM_1];
+        assert_eq!(name(Layout::Us, &us_semicolon), "^`;");
+        // the same key types ü on German layouts, and ; has a key of its own on French ones
+        assert_eq!(name(Layout::German, &us_semicolon), "^ü");
+        assert_eq!(name(Layout::French, &[KeyCode::VK_OEM_PERIOD]), "`;");
+        assert_eq!(name(Layout::French, &[KeyCode::VK_OEM_1]), "$");
+    }
+
+    fn export_and_parse(m: &Macro) -> (String, Macro) {
+        let script = to_ahk(m, Layout::Us);
+        let exported = std::env::temp_dir().join("ahk-rs-test-recording.ahk");
+        std::fs::write(&exported, &script).unwrap();
+        let reparsed = parse(&exported);
+        std::fs::remove_file(exported).unwrap();
+        (script, reparsed)
+    }
+
+    #[test]
+    fn recording_round_trip() {
+        use crate::keycodes::KeyUpDown;
+        use crate::macro_events::{
+            KeyboardEvent, LoopEvent, MouseButtonEvent, MouseMoveEvent, MouseWheelEvent,
+        };
+
+        let move_to = |x, y, space| MacroEvent::MouseMove(MouseMoveEvent { x, y, space });
+        let button = |button, action| MacroEvent::MouseBtn(MouseButtonEvent { button, action });
+        let key = |key, up_down| {
+            MacroEvent::Keybd(KeyboardEvent {
+                key: Some(key),
+                key_up_down: Some(up_down),
+                custom_flags: None,
+            })
+        };
+        let block = |hotkey, events| MacroBlock {
+            hotkey,
+            events,
+            running: false,
+            max_threads: 1,
+            suspend_exempt: false,
+            active_when: None,
+        };
+        let recording = vec![
+            move_to(100, 200, CoordSpace::VirtualDesk),
+            MacroEvent::SleepMs(15),
+            button(MouseButton::Left, MouseAction::Down),
+            button(MouseButton::Left, MouseAction::Up),
+            move_to(10, 20, CoordSpace::Window),
+            MacroEvent::MouseWheel(MouseWheelEvent::notches(3, false)),
+            MacroEvent::MouseWheel(MouseWheelEvent::notches(-1, false)),
+            MacroEvent::MouseWheel(MouseWheelEvent::notches(2, true)),
+            MacroEvent::MouseWheel(MouseWheelEvent::notches(-1, true)),
+            move_to(-3, 4, CoordSpace::Relative),
+            key(KeyCode::VK_XBUTTON1, KeyUpDown::Down),
+            key(KeyCode::VK_XBUTTON1, KeyUpDown::Up),
+            button(MouseButton::X2, MouseAction::Click),
+            button(MouseButton::X1, MouseAction::DoubleClick),
+            MacroEvent::Loop(LoopEvent {
+                count: 2,
+                events: vec![move_to(5, 5, CoordSpace::Client), move_to(6, 6, CoordSpace::Window)],
+            }),
+            move_to(7, 8, CoordSpace::Window),
+            move_to(0, 0, CoordSpace::VirtualDesk),
+        ];
+        let m = Macro {
+            name: "recording".to_string(),
+            blocks: vec![
+                block(Some(vec![KeyCode::VK_F1]), recording.clone()),
+                // blocks without a hotkey run at the start, wherever they are
+                block(None, vec![move_to(1, 2, CoordSpace::Client)]),
+            ],
+            settings: MacroSettings {
+                coord_mode: CoordMode::Window,
+                ..Default::default()
+            },
+            timers: vec![],
+        };
+
+        let (script, reparsed) = export_and_parse(&m);
+        let expected = vec![m.blocks[1].clone(), m.blocks[0].clone()];
+        assert_eq!(reparsed.blocks, expected, "{}", script);
+        assert_eq!(reparsed.settings.coord_mode, CoordMode::Window, "{}", script);
+        assert_eq!(to_ahk(&reparsed, Layout::Us), script);
+
+        // moves on the primary screen are the same pixels on the virtual desktop
+        let mut pixels = m.clone();
+        pixels.blocks[0].events[0] = move_to(100, 200, CoordSpace::Pixels);
+        assert_eq!(to_ahk(&pixels, Layout::Us), script);
+    }
+
+    #[test]
+    fn parse_export_round_trip() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/round_trip.ahk");
+        let parsed = parse(&fixture);
+        assert_eq!(parsed.blocks.iter().filter(|x| x.hotkey.is_some()).count(), 3);
+
+        let script = to_ahk(&parsed, Layout::Us);
+        let exported = std::env::temp_dir().join("ahk-rs-test-round-trip.ahk");
+        std::fs::write(&exported, &script).unwrap();
+        let reparsed = parse(&exported);
+        std::fs::remove_file(exported).unwrap();
+
+        assert_eq!(reparsed.settings, parsed.settings, "{}", script);
+        assert_eq!(reparsed.blocks, parsed.blocks, "{}", script);
+        assert_eq!(reparsed.timers, parsed.timers, "{}", script);
+        assert_eq!(to_ahk(&reparsed, Layout::Us), script);
+    }
+}
@@ -0,0 +1,158 @@
+// AutoHotkey v2 front end. v2 lines are lowered into the equivalent v1
+// command line (`Sleep(100)` -> `Sleep 100`, `Send("{a}")` -> `Send {a}`) so
+// the rest of the parser only has to understand one syntax.
+
+/// Lowers one logical v2 line into v1 command syntax.
+pub fn lower_line(line: &str) -> String {
+    let line = line.trim();
+
+    // an opening brace on the same line (`Loop 5 {`) is kept as its own word
+    match line.strip_suffix('{') {
+        Some(rest) if !rest.trim().is_empty() => format!("{} {{", lower_statement(rest.trim_end())),
+        _ => lower_statement(line),
+    }
+}
+
+fn lower_statement(line: &str) -> String {
+    // hotkeys, labels and braces are the same in both versions
+    if line.ends_with(':') || line.contains("::") || line.starts_with('{') || line.starts_with('}')
+    {
+        return line.to_string();
+    }
+    if let Some(directive) = line.strip_prefix('#') {
+        let (name, params) = split_name(directive);
+        if params.is_empty() {
+            return line.to_string();
+        }
+        return format!("#{} {}", name, unquote(params));
+    }
+
+    let (name, params) = split_name(line);
+    if name.eq_ignore_ascii_case("dllcall") || name.is_empty() {
+        // DllCall already uses function syntax in v1
+        return line.to_string();
+    }
+    let params = match call_params(params) {
+        Some(inner) => inner,
+        None => params.trim_start_matches(',').trim_start(),
+    };
+    let params = split_params(params)
+        .iter()
+        .map(|x| unquote(x))
+        .collect::<Vec<String>>();
+
+    if params.is_empty() {
+        name.to_string()
+    } else {
+        format!("{} {}", name, params.join(", "))
+    }
+}
+
+/// The inside of `params` if it is one pair of parentheses, as in a function
+/// call. `(a) || (b)` starts and ends with them, but they aren't a pair.
+fn call_params(params: &str) -> Option<&str> {
+    let inner = params.strip_prefix('(')?.strip_suffix(')')?;
+    let mut quote = None;
+    let mut depth = 0;
+    let mut escaped = false;
+    for c in inner.chars() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (c, quote) {
+            ('`', _) => escaped = true,
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => {}
+            ('(', None) => depth += 1,
+            (')', None) if depth == 0 => return None,
+            (')', None) => depth -= 1,
+            _ => {}
+        }
+    }
+    Some(inner)
+}
+
+/// Splits a line into its leading identifier and everything after it.
+fn split_name(line: &str) -> (&str, &str) {
+    let end = line
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(line.len());
+    (&line[..end], line[end..].trim())
+}
+
+/// Splits function parameters on commas that aren't inside quotes or brackets.
+pub fn split_params(params: &str) -> Vec<&str> {
+    let mut split = vec![];
+    let mut quote = None;
+    let mut depth = 0;
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in params.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (c, quote) {
+            ('`', _) => escaped = true,
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => {}
+            ('(' | '[' | '{', None) => depth += 1,
+            (')' | ']' | '}', None) => depth -= 1,
+            (',', None) if depth == 0 => {
+                split.push(params[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    let last = params[start..].trim();
+    if !last.is_empty() || !split.is_empty() {
+        split.push(last);
+    }
+    split
+}
+
+/// Turns a v2 string literal into its text. Anything else (numbers, variable
+/// names) is returned unchanged.
+pub fn unquote(param: &str) -> String {
+    let param = param.trim();
+    for q in ['"', '\''] {
+        if param.len() >= 2 && param.starts_with(q) && param.ends_with(q) {
+            return param[1..param.len() - 1].replace(&format!("`{}", q), &q.to_string());
+        }
+    }
+    param.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowers_calls() {
+        assert_eq!(lower_line("Sleep(100)"), "Sleep 100");
+        assert_eq!(lower_line("Send(\"{a}\")"), "Send {a}");
+        assert_eq!(lower_line("Send \"a, b\""), "Send a, b");
+        assert_eq!(lower_line("MouseMove(10, 20, 0, \"R\")"), "MouseMove 10, 20, 0, R");
+        assert_eq!(lower_line("ExitApp()"), "ExitApp");
+        assert_eq!(lower_line("ExitApp"), "ExitApp");
+        // nested calls stay whole
+        assert_eq!(lower_line("Sleep(Max(1, (2)))"), "Sleep Max(1, (2))");
+        assert_eq!(lower_line("Send(Format(\"{}\", \")\"))"), "Send Format(\"{}\", \")\")");
+    }
+
+    #[test]
+    fn lowers_blocks_and_conditions() {
+        assert_eq!(lower_line("Loop 5 {"), "Loop 5 {");
+        assert_eq!(lower_line("Loop(5) {"), "Loop 5 {");
+        assert_eq!(lower_line("if (a) {"), "if a {");
+        // the parens around each side aren't one pair, so they're kept
+        assert_eq!(lower_line("if (a) || (b) {"), "if (a) || (b) {");
+        assert_eq!(lower_line("if (a || (b))"), "if a || (b)");
+        assert_eq!(lower_line("^a::"), "^a::");
+        assert_eq!(lower_line("#Include \"lib.ahk\""), "#Include lib.ahk");
+    }
+}
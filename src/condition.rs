@@ -0,0 +1,161 @@
+// Conditions for `if` statements and `#If` hotkeys, parsed from AHK
+// expressions like `GetKeyState("Shift", "P") && !WinActive("ahk_class Notepad")`.
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{keycodes::KeyCode, r#macro::MacroState};
+
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub enum Condition {
+    KeyState(KeyCode, KeyStateMode),
+    /// A variable that isn't empty or 0.
+    Variable(String),
+    /// The active window matches a WinTitle.
+    WinActive(String),
+    Not(Box<Condition>),
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+}
+
+/// Which state `GetKeyState` reads.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub enum KeyStateMode {
+    /// Whether the key is held down. AHK's logical and physical ("P") states
+    /// are the same here since there's no keyboard hook to tell them apart.
+    Down,
+    /// Whether a lock key like CapsLock is on ("T").
+    Toggled,
+}
+
+impl Condition {
+    pub fn evaluate(&self, state: &MacroState) -> bool {
+        match self {
+            Condition::KeyState(key, KeyStateMode::Down) => key.is_down(),
+            Condition::KeyState(key, KeyStateMode::Toggled) => key.is_toggled(),
+            Condition::Variable(name) => state.var_is_true(name),
+            Condition::WinActive(win_title) => state
+                .windows
+                .active_window()
+                .is_some_and(|x| x.matches(win_title)),
+            Condition::Not(condition) => !condition.evaluate(state),
+            Condition::And(conditions) => conditions.iter().all(|x| x.evaluate(state)),
+            Condition::Or(conditions) => conditions.iter().any(|x| x.evaluate(state)),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { rest: s };
+        let condition = parser.or()?;
+        if !parser.rest.trim().is_empty() {
+            return Err(format!("unexpected {:?} in condition", parser.rest.trim()));
+        }
+        Ok(condition)
+    }
+}
+
+/// Recursive descent over `||`, `&&`, `!`, parentheses, function calls and
+/// variables.
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    /// Consumes `token` (case-insensitively) if the input starts with it.
+    fn eat(&mut self, token: &str) -> bool {
+        let rest = self.rest.trim_start();
+        match rest.get(..token.len()) {
+            Some(start) if start.eq_ignore_ascii_case(token) => {
+                self.rest = &rest[token.len()..];
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Consumes a keyword operator, which must be followed by whitespace.
+    fn eat_word(&mut self, word: &str) -> bool {
+        let rest = self.rest.trim_start();
+        let followed_by_space = rest
+            .get(word.len()..)
+            .is_some_and(|x| x.starts_with(char::is_whitespace));
+        followed_by_space && self.eat(word)
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut conditions = vec![self.and()?];
+        while self.eat("||") || self.eat_word("or") {
+            conditions.push(self.and()?);
+        }
+        Ok(match conditions.len() {
+            1 => conditions.pop().unwrap(),
+            _ => Condition::Or(conditions),
+        })
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut conditions = vec![self.unary()?];
+        while self.eat("&&") || self.eat_word("and") {
+            conditions.push(self.unary()?);
+        }
+        Ok(match conditions.len() {
+            1 => conditions.pop().unwrap(),
+            _ => Condition::And(conditions),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Condition, String> {
+        if self.eat("!") || self.eat_word("not") {
+            return Ok(Condition::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let condition = self.or()?;
+            if !self.eat(")") {
+                return Err("missing ')' in condition".to_string());
+            }
+            return Ok(condition);
+        }
+        self.call()
+    }
+
+    fn call(&mut self) -> Result<Condition, String> {
+        let rest = self.rest.trim_start();
+        let name_end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if name_end == 0 {
+            return Err(format!("condition not implemented: {:?}", rest));
+        }
+        if !rest[name_end..].starts_with('(') {
+            self.rest = &rest[name_end..];
+            return Ok(Condition::Variable(rest[..name_end].to_string()));
+        }
+        let name = &rest[..name_end];
+        let args_end = rest.find(')').ok_or("missing ')' in condition")?;
+        let args = rest[name_end + 1..args_end]
+            .split(',')
+            .map(|x| x.trim().trim_matches('"'))
+            .collect::<Vec<&str>>();
+        self.rest = &rest[args_end + 1..];
+
+        if name.eq_ignore_ascii_case("winactive") {
+            crate::window::check_win_title(args[0])?;
+            return Ok(Condition::WinActive(args[0].to_string()));
+        }
+        if !name.eq_ignore_ascii_case("getkeystate") {
+            return Err(format!("function not implemented in condition: {:?}", name));
+        }
+        let key = crate::ahk::key_from_name(args[0]);
+        let mode = match args.get(1).map(|x| x.to_lowercase()).as_deref() {
+            None | Some("") | Some("p") => KeyStateMode::Down,
+            Some("t") => KeyStateMode::Toggled,
+            Some(other) => return Err(format!("invalid GetKeyState mode: {:?}", other)),
+        };
+        Ok(Condition::KeyState(key, mode))
+    }
+}
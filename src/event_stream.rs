@@ -0,0 +1,223 @@
+// The event lists of binary macro files, packed as a stream of records: the
+// sleep before the event as a varint, an opcode, then the event's fields.
+// Recordings are mostly key presses with a sleep between them, which this
+// packs into 3 or 4 bytes each. Events without an opcode of their own are
+// written as MessagePack.
+
+use crate::{
+    keycodes::{KeyCode, KeyUpDown},
+    macro_events::{
+        CoordSpace, KeyboardEvent, LoopEvent, MacroEvent, MouseAction, MouseButton,
+        MouseButtonEvent, MouseMoveEvent, MouseWheelEvent,
+    },
+};
+
+const END: u8 = 0;
+// a sleep followed by another sleep, with nothing to attach the first to
+const SLEEP: u8 = 1;
+const KEY_TAP: u8 = 2;
+const KEY_DOWN: u8 = 3;
+const KEY_UP: u8 = 4;
+const MOUSE_MOVE: u8 = 5;
+const MOUSE_BUTTON: u8 = 6;
+const MOUSE_WHEEL: u8 = 7;
+const LOOP: u8 = 8;
+const OTHER: u8 = 9;
+
+// the enums' variants by the index written for them
+const COORD_SPACES: [CoordSpace; 6] = [
+    CoordSpace::Pixels,
+    CoordSpace::VirtualDesk,
+    CoordSpace::Window,
+    CoordSpace::Client,
+    CoordSpace::Normalized,
+    CoordSpace::Relative,
+];
+const MOUSE_BUTTONS: [MouseButton; 5] = [
+    MouseButton::Left,
+    MouseButton::Right,
+    MouseButton::Middle,
+    MouseButton::X1,
+    MouseButton::X2,
+];
+const MOUSE_ACTIONS: [MouseAction; 4] = [
+    MouseAction::Down,
+    MouseAction::Up,
+    MouseAction::Click,
+    MouseAction::DoubleClick,
+];
+
+/// Appends `events` to `out`, ending with an END record.
+pub fn encode(events: &[MacroEvent], out: &mut Vec<u8>) -> Result<(), String> {
+    // a SleepMs is written as the delay of the record after it, as ms + 1
+    // so 0 can mean there wasn't one
+    let mut sleep = None;
+    let delay = |sleep: Option<u64>| match sleep {
+        Some(ms) => ms.checked_add(1).ok_or("sleep is too long for a binary file"),
+        None => Ok(0),
+    };
+    for event in events {
+        if let MacroEvent::SleepMs(ms) = event {
+            if let Some(ms) = sleep.replace(*ms) {
+                write_varint(out, delay(Some(ms))?);
+                out.push(SLEEP);
+            }
+            continue;
+        }
+        write_varint(out, delay(sleep.take())?);
+        match event {
+            MacroEvent::Keybd(KeyboardEvent {
+                key: Some(key),
+                key_up_down,
+                custom_flags: None,
+            }) => {
+                out.push(match key_up_down {
+                    None => KEY_TAP,
+                    Some(KeyUpDown::Down) => KEY_DOWN,
+                    Some(KeyUpDown::Up) => KEY_UP,
+                });
+                write_varint(out, key.code() as u64);
+            }
+            MacroEvent::MouseMove(event) => {
+                out.push(MOUSE_MOVE);
+                write_varint(out, zigzag(event.x));
+                write_varint(out, zigzag(event.y));
+                out.push(index_of(&COORD_SPACES, event.space));
+            }
+            MacroEvent::MouseBtn(event) => {
+                out.push(MOUSE_BUTTON);
+                let button = index_of(&MOUSE_BUTTONS, event.button);
+                out.push(button * 4 + index_of(&MOUSE_ACTIONS, event.action));
+            }
+            MacroEvent::MouseWheel(event) => {
+                out.push(MOUSE_WHEEL);
+                write_varint(out, zigzag(event.delta));
+                out.push(event.horizontal as u8);
+            }
+            MacroEvent::Loop(event) => {
+                out.push(LOOP);
+                write_varint(out, event.count as u64);
+                encode(&event.events, out)?;
+            }
+            event => {
+                out.push(OTHER);
+                let bytes = rmp_serde::to_vec_named(event).map_err(|e| e.to_string())?;
+                write_varint(out, bytes.len() as u64);
+                out.extend_from_slice(&bytes);
+            }
+        }
+    }
+    write_varint(out, delay(sleep)?);
+    out.push(END);
+    Ok(())
+}
+
+/// Reads events up to the next END record, advancing `bytes` past it.
+pub fn decode(bytes: &mut &[u8]) -> Result<Vec<MacroEvent>, String> {
+    let mut events = vec![];
+    loop {
+        let delay = read_varint(bytes)?;
+        if delay > 0 {
+            events.push(MacroEvent::SleepMs(delay - 1));
+        }
+        let event = match read_u8(bytes)? {
+            END => return Ok(events),
+            SLEEP => continue,
+            op @ (KEY_TAP | KEY_DOWN | KEY_UP) => {
+                let code = u32::try_from(read_varint(bytes)?).map_err(|_| "invalid key code")?;
+                MacroEvent::Keybd(KeyboardEvent {
+                    key: Some(KeyCode::from_code(code)),
+                    key_up_down: match op {
+                        KEY_DOWN => Some(KeyUpDown::Down),
+                        KEY_UP => Some(KeyUpDown::Up),
+                        _ => None,
+                    },
+                    custom_flags: None,
+                })
+            }
+            MOUSE_MOVE => MacroEvent::MouseMove(MouseMoveEvent {
+                x: unzigzag(read_varint(bytes)?)?,
+                y: unzigzag(read_varint(bytes)?)?,
+                space: variant(&COORD_SPACES, read_u8(bytes)?)?,
+            }),
+            MOUSE_BUTTON => {
+                let byte = read_u8(bytes)?;
+                MacroEvent::MouseBtn(MouseButtonEvent {
+                    button: variant(&MOUSE_BUTTONS, byte / 4)?,
+                    action: variant(&MOUSE_ACTIONS, byte % 4)?,
+                })
+            }
+            MOUSE_WHEEL => MacroEvent::MouseWheel(MouseWheelEvent {
+                delta: unzigzag(read_varint(bytes)?)?,
+                horizontal: read_u8(bytes)? != 0,
+            }),
+            LOOP => {
+                let count = u32::try_from(read_varint(bytes)?).map_err(|_| "invalid loop count")?;
+                MacroEvent::Loop(LoopEvent {
+                    count,
+                    events: decode(bytes)?,
+                })
+            }
+            OTHER => {
+                let len = read_varint(bytes)? as usize;
+                if bytes.len() < len {
+                    return Err("event runs past the end of the file".to_string());
+                }
+                let (event, rest) = bytes.split_at(len);
+                *bytes = rest;
+                rmp_serde::from_slice(event).map_err(|e| e.to_string())?
+            }
+            op => return Err(format!("unknown event opcode {}", op)),
+        };
+        events.push(event);
+    }
+}
+
+fn index_of<T: PartialEq>(variants: &[T], value: T) -> u8 {
+    variants.iter().position(|x| *x == value).unwrap() as u8
+}
+
+fn variant<T: Copy>(variants: &[T], index: u8) -> Result<T, String> {
+    variants
+        .get(index as usize)
+        .copied()
+        .ok_or_else(|| format!("invalid variant index {}", index))
+}
+
+// LEB128: 7 bits a byte, low bits first, with the high bit set on all but
+// the last byte
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+pub fn read_varint(bytes: &mut &[u8]) -> Result<u64, String> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(bytes)?;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("varint is too long".to_string())
+}
+
+fn read_u8(bytes: &mut &[u8]) -> Result<u8, String> {
+    let (&byte, rest) = bytes.split_first().ok_or("events end early")?;
+    *bytes = rest;
+    Ok(byte)
+}
+
+// small negative numbers as small varints: 0, -1, 1, -2, 2...
+fn zigzag(value: i32) -> u64 {
+    ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+fn unzigzag(value: u64) -> Result<i32, String> {
+    let value = u32::try_from(value).map_err(|_| "coordinate is out of range")?;
+    Ok((value >> 1) as i32 ^ -((value & 1) as i32))
+}
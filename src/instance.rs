@@ -0,0 +1,124 @@
+// Enforces `#SingleInstance`. A running macro holds a lock on a file named
+// after its script, so a second instance of the same script finds the lock
+// taken. Its process id is in a .pid file next to the lock, since Windows
+// doesn't let other processes read a locked file.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+};
+
+use crate::r#macro::SingleInstance;
+
+/// The lock file for the script at `script`, in the temp directory.
+pub fn lock_path(script: &Path) -> PathBuf {
+    let script = std::fs::canonicalize(script).unwrap_or_else(|_| script.to_path_buf());
+    let hash = crc32fast::hash(script.to_string_lossy().to_lowercase().as_bytes());
+    std::env::temp_dir().join(format!("ahk-rs-{:08x}.lock", hash))
+}
+
+/// Claims the lock at `lock_path` as `mode` says to. Returns the locked file,
+/// which has to be kept open for as long as the macro runs, or None if this
+/// instance should exit and leave the running one be. `Off` never waits for
+/// or takes the lock. `Prompt` asks on the console whether to replace the
+/// running instance.
+pub fn claim(lock_path: &Path, mode: SingleInstance) -> std::io::Result<Option<File>> {
+    let pid_path = lock_path.with_extension("pid");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path)?;
+    if mode == SingleInstance::Off {
+        return Ok(Some(file));
+    }
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let replace = match mode {
+                SingleInstance::Force => true,
+                SingleInstance::Prompt => ask_to_replace(),
+                SingleInstance::Ignore | SingleInstance::Off => false,
+            };
+            if !replace {
+                return Ok(None);
+            }
+            let pid = std::fs::read_to_string(&pid_path).unwrap_or_default();
+            match pid.trim().parse::<u32>() {
+                Ok(pid) => terminate(pid)?,
+                Err(_) => eprintln!("{}: no process id to replace", pid_path.display()),
+            }
+            // the lock is let go of when the old process exits
+            file.lock()?;
+        }
+        Err(TryLockError::Error(e)) => return Err(e),
+    }
+    std::fs::write(pid_path, std::process::id().to_string())?;
+    Ok(Some(file))
+}
+
+fn ask_to_replace() -> bool {
+    println!("An older instance of this macro is already running. Replace it? [y/N]");
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    answer.trim().eq_ignore_ascii_case("y")
+}
+
+#[cfg(windows)]
+fn terminate(pid: u32) -> std::io::Result<()> {
+    use windows::Win32::Foundation::CloseHandle;
+    use windows::Win32::System::Threading::{OpenProcess, TerminateProcess, PROCESS_TERMINATE};
+
+    unsafe {
+        let process = OpenProcess(PROCESS_TERMINATE, false, pid)?;
+        let result = TerminateProcess(process, 0);
+        let _ = CloseHandle(process);
+        result?;
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn terminate(pid: u32) -> std::io::Result<()> {
+    let status = std::process::Command::new("kill").arg(pid.to_string()).status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("failed to stop process {}", pid)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_instances() {
+        let lock = std::env::temp_dir().join("ahk-rs-test-instance.lock");
+        let _ = std::fs::remove_file(&lock);
+        let first = claim(&lock, SingleInstance::Ignore).unwrap().unwrap();
+        let pid = std::fs::read_to_string(lock.with_extension("pid")).unwrap();
+        assert_eq!(pid, std::process::id().to_string());
+
+        // locks are per open file, so this acts like another process
+        assert!(claim(&lock, SingleInstance::Ignore).unwrap().is_none());
+        assert!(claim(&lock, SingleInstance::Off).unwrap().is_some());
+
+        drop(first);
+        let second = claim(&lock, SingleInstance::Ignore).unwrap();
+        assert!(second.is_some());
+        drop(second);
+        std::fs::remove_file(&lock).unwrap();
+        std::fs::remove_file(lock.with_extension("pid")).unwrap();
+    }
+
+    #[test]
+    fn lock_paths() {
+        let script = Path::new("C:\\Scripts\\macro.ahk");
+        assert_eq!(lock_path(script), lock_path(Path::new("c:\\scripts\\MACRO.ahk")));
+        assert_ne!(lock_path(script), lock_path(Path::new("C:\\Scripts\\other.ahk")));
+        assert_eq!(lock_path(script).parent(), Some(std::env::temp_dir().as_path()));
+    }
+}
@@ -1,10 +1,64 @@
-use serde::{Deserialize, Serialize};
-use windows::Win32::UI::Input::KeyboardAndMouse::{VkKeyScanW, MapVirtualKeyW, MAPVK_VSC_TO_VK_EX};
+use std::cell::Cell;
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq, Hash)]
-#[allow(non_camel_case_types)]
-#[repr(u32)]
-pub enum KeyCode {
+use bitflags::{bitflags, Flags};
+use serde::de::{self, EnumAccess, SeqAccess, VariantAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use windows::Win32::UI::Input::KeyboardAndMouse::{GetAsyncKeyState, GetKeyState};
+
+use crate::layout::Layout;
+
+// Defines KeyCode along with its conversions to and from virtual key codes
+// and VK_* names, so they all come from the one list.
+macro_rules! key_codes {
+    ($($name:ident = $code:literal,)*) => {
+        #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+        #[allow(non_camel_case_types)]
+        pub enum KeyCode {
+            $($name,)*
+            /// A virtual key code without a variant, like 0x07.
+            /// `KeyCode::from_code` only makes these for codes that have no
+            /// variant.
+            Unknown(u32),
+        }
+
+        impl KeyCode {
+            /// The Win32 virtual key code.
+            pub const fn code(self) -> u32 {
+                match self {
+                    $(KeyCode::$name => $code,)*
+                    KeyCode::Unknown(code) => code,
+                }
+            }
+
+            /// Codes without a variant become `KeyCode::Unknown`.
+            pub fn from_code(code: u32) -> KeyCode {
+                KeyCode::try_from(code).unwrap_or(KeyCode::Unknown(code))
+            }
+
+            /// Looks up a `VK_*` name.
+            pub fn from_vk_name(name: &str) -> Option<KeyCode> {
+                match name {
+                    $(stringify!($name) => Some(KeyCode::$name),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl TryFrom<u32> for KeyCode {
+            type Error = u32;
+
+            /// Fails with the code if it has no variant.
+            fn try_from(code: u32) -> Result<Self, u32> {
+                match code {
+                    $($code => Ok(KeyCode::$name),)*
+                    _ => Err(code),
+                }
+            }
+        }
+    };
+}
+
+key_codes! {
     VK_NONE = 0x00,
     VK_LBUTTON = 0x01,
     VK_RBUTTON = 0x02,
@@ -124,6 +178,11 @@ pub enum KeyCode {
     VK_F24 = 0x87,
     VK_NUMLOCK = 0x90,
     VK_SCROLL = 0x91,
+    // AHK's codes for the wheel, which Windows doesn't give virtual keys
+    VK_WHEEL_LEFT = 0x9C,
+    VK_WHEEL_RIGHT = 0x9D,
+    VK_WHEEL_DOWN = 0x9E,
+    VK_WHEEL_UP = 0x9F,
     VK_LSHIFT = 0xA0,
     VK_RSHIFT = 0xA1,
     VK_LCONTROL = 0xA2,
@@ -175,217 +234,36 @@ pub enum KeyCode {
 }
 
 impl std::str::FromStr for KeyCode {
-    type Err = ();
-    fn from_str(key: &str) -> Result<Self, Self::Err> {
-        let mut key_code = KeyCode::VK_NONE;
-        if key.starts_with("VK_") {
-            return Ok(KeyCode::str_match(key));
-        }
+    type Err = String;
 
-        if key.len() == 1 {
-            key_code = KeyCode::from_char(key.chars().next().unwrap());
-        } else if key.starts_with('F') && key.len() == 2 {
-            let function_key_number: u32 = key.chars().skip(1).collect::<String>().parse().unwrap();
-            if (1..=12).contains(&function_key_number) {
-                key_code = (111 + function_key_number).into();
-            }
-        } else {
-            let wch = key.chars().next().unwrap() as u16;
-            let vk_key_scan_result = unsafe { VkKeyScanW(wch) as i32 };
-    
-            if vk_key_scan_result != -1 {
-                let scan_code = vk_key_scan_result & 0xFF;
-                let mapped_vk = unsafe { MapVirtualKeyW(scan_code as u32, MAPVK_VSC_TO_VK_EX) };
-                key_code = mapped_vk.into();
+    /// Reads a key name the way AHK does, see `KeyCode::from_ahk_name`.
+    /// Other single characters are looked up in the keyboard layout.
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        let mut chars = key.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            if let Some(key_code) = KeyCode::from_char(c) {
+                return Ok(key_code);
             }
         }
-        Ok(key_code)
+        KeyCode::from_ahk_name(key).ok_or_else(|| format!("unknown key {:?}", key))
     }
 }
 
-
 impl KeyCode {
-    pub fn from_char(c: char) -> Self {
-        let ret = unsafe {
-            VkKeyScanW(c as u16)
-        };
-        let keycode = ret as u8;
-        KeyCode::from(keycode as u32)
-    }
-
-
-    pub fn str_match(s: &str) -> Self {
-        let u = match s {
-            "VK_NONE" => 0x00,
-            "VK_LBUTTON" => 0x01,
-            "VK_RBUTTON" => 0x02,
-            "VK_CANCEL" => 0x03,
-            "VK_MBUTTON" => 0x04,
-            "VK_XBUTTON1" => 0x05,
-            "VK_XBUTTON2" => 0x06,
-            "VK_BACK" => 0x08,
-            "VK_TAB" => 0x09,
-            "VK_CLEAR" => 0x0C,
-            "VK_RETURN" => 0x0D,
-            "VK_SHIFT" => 0x10,
-            "VK_CONTROL" => 0x11,
-            "VK_MENU" => 0x12,
-            "VK_PAUSE" => 0x13,
-            "VK_CAPITAL" => 0x14,
-            "VK_ESCAPE" => 0x1B,
-            "VK_CONVERT" => 0x1C,
-            "VK_NONCONVERT" => 0x1D,
-            "VK_ACCEPT" => 0x1E,
-            "VK_MODECHANGE" => 0x1F,
-            "VK_SPACE" => 0x20,
-            "VK_PRIOR" => 0x21,
-            "VK_NEXT" => 0x22,
-            "VK_END" => 0x23,
-            "VK_HOME" => 0x24,
-            "VK_LEFT" => 0x25,
-            "VK_UP" => 0x26,
-            "VK_RIGHT" => 0x27,
-            "VK_DOWN" => 0x28,
-            "VK_SELECT" => 0x29,
-            "VK_PRINT" => 0x2A,
-            "VK_EXECUTE" => 0x2B,
-            "VK_SNAPSHOT" => 0x2C,
-            "VK_INSERT" => 0x2D,
-            "VK_DELETE" => 0x2E,
-            "VK_HELP" => 0x2F,
-            "VK_0" => 0x30,
-            "VK_1" => 0x31,
-            "VK_2" => 0x32,
-            "VK_3" => 0x33,
-            "VK_4" => 0x34,
-            "VK_5" => 0x35,
-            "VK_6" => 0x36,
-            "VK_7" => 0x37,
-            "VK_8" => 0x38,
-            "VK_9" => 0x39,
-            "VK_A" => 0x41,
-            "VK_B" => 0x42,
-            "VK_C" => 0x43,
-            "VK_D" => 0x44,
-            "VK_E" => 0x45,
-            "VK_F" => 0x46,
-            "VK_G" => 0x47,
-            "VK_H" => 0x48,
-            "VK_I" => 0x49,
-            "VK_J" => 0x4A,
-            "VK_K" => 0x4B,
-            "VK_L" => 0x4C,
-            "VK_M" => 0x4D,
-            "VK_N" => 0x4E,
-            "VK_O" => 0x4F,
-            "VK_P" => 0x50,
-            "VK_Q" => 0x51,
-            "VK_R" => 0x52,
-            "VK_S" => 0x53,
-            "VK_T" => 0x54,
-            "VK_U" => 0x55,
-            "VK_V" => 0x56,
-            "VK_W" => 0x57,
-            "VK_X" => 0x58,
-            "VK_Y" => 0x59,
-            "VK_Z" => 0x5A,
-            "VK_LWIN" => 0x5B,
-            "VK_RWIN" => 0x5C,
-            "VK_APPS" => 0x5D,
-            "VK_SLEEP" => 0x5F,
-            "VK_NUMPAD0" => 0x60,
-            "VK_NUMPAD1" => 0x61,
-            "VK_NUMPAD2" => 0x62,
-            "VK_NUMPAD3" => 0x63,
-            "VK_NUMPAD4" => 0x64,
-            "VK_NUMPAD5" => 0x65,
-            "VK_NUMPAD6" => 0x66,
-            "VK_NUMPAD7" => 0x67,
-            "VK_NUMPAD8" => 0x68,
-            "VK_NUMPAD9" => 0x69,
-            "VK_MULTIPLY" => 0x6A,
-            "VK_ADD" => 0x6B,
-            "VK_SEPARATOR" => 0x6C,
-            "VK_SUBTRACT" => 0x6D,
-            "VK_DECIMAL" => 0x6E,
-            "VK_DIVIDE" => 0x6F,
-            "VK_F1" => 0x70,
-            "VK_F2" => 0x71,
-            "VK_F3" => 0x72,
-            "VK_F4" => 0x73,
-            "VK_F5" => 0x74,
-            "VK_F6" => 0x75,
-            "VK_F7" => 0x76,
-            "VK_F8" => 0x77,
-            "VK_F9" => 0x78,
-            "VK_F10" => 0x79,
-            "VK_F11" => 0x7A,
-            "VK_F12" => 0x7B,
-            "VK_F13" => 0x7C,
-            "VK_F14" => 0x7D,
-            "VK_F15" => 0x7E,
-            "VK_F16" => 0x7F,
-            "VK_F17" => 0x80,
-            "VK_F18" => 0x81,
-            "VK_F19" => 0x82,
-            "VK_F20" => 0x83,
-            "VK_F21" => 0x84,
-            "VK_F22" => 0x85,
-            "VK_F23" => 0x86,
-            "VK_F24" => 0x87,
-            "VK_NUMLOCK" => 0x90,
-            "VK_SCROLL" => 0x91,
-            "VK_LSHIFT" => 0xA0,
-            "VK_RSHIFT" => 0xA1,
-            "VK_LCONTROL" => 0xA2,
-            "VK_RCONTROL" => 0xA3,
-            "VK_LMENU" => 0xA4,
-            "VK_RMENU" => 0xA5,
-            "VK_BROWSER_BACK" => 0xA6,
-            "VK_BROWSER_FORWARD" => 0xA7,
-            "VK_BROWSER_REFRESH" => 0xA8,
-            "VK_BROWSER_STOP" => 0xA9,
-            "VK_BROWSER_SEARCH" => 0xAA,
-            "VK_BROWSER_FAVORITES" => 0xAB,
-            "VK_BROWSER_HOME" => 0xAC,
-            "VK_VOLUME_MUTE" => 0xAD,
-            "VK_VOLUME_DOWN" => 0xAE,
-            "VK_VOLUME_UP" => 0xAF,
-            "VK_MEDIA_NEXT_TRACK" => 0xB0,
-            "VK_MEDIA_PREV_TRACK" => 0xB1,
-            "VK_MEDIA_STOP" => 0xB2,
-            "VK_MEDIA_PLAY_PAUSE" => 0xB3,
-            "VK_LAUNCH_MAIL" => 0xB4,
-            "VK_LAUNCH_MEDIA_SELECT" => 0xB5,
-            "VK_LAUNCH_APP1" => 0xB6,
-            "VK_LAUNCH_APP2" => 0xB7,
-            "VK_OEM_1" => 0xBA,
-            "VK_OEM_PLUS" => 0xBB,
-            "VK_OEM_COMMA" => 0xBC,
-            "VK_OEM_MINUS" => 0xBD,
-            "VK_OEM_PERIOD" => 0xBE,
-            "VK_OEM_2" => 0xBF,
-            "VK_OEM_3" => 0xC0,
-            "VK_OEM_4" => 0xDB,
-            "VK_OEM_5" => 0xDC,
-            "VK_OEM_6" => 0xDD,
-            "VK_OEM_7" => 0xDE,
-            "VK_OEM_8" => 0xDF,
-            "VK_OEM_102" => 0xE2,
-            "VK_PROCESSKEY" => 0xE5,
-            "VK_PACKET" => 0xE7,
-            "VK_ATTN" => 0xF6,
-            "VK_CRSEL" => 0xF7,
-            "VK_EXSEL" => 0xF8,
-            "VK_EREOF" => 0xF9,
-            "VK_PLAY" => 0xFA,
-            "VK_ZOOM" => 0xFB,
-            "VK_NONAME" => 0xFC,
-            "VK_PA1" => 0xFD,
-            "VK_OEM_CLEAR" => 0xFE,
-            _ => 0,
-        };
-        KeyCode::from(u)
+    pub fn is_down(self) -> bool {
+        let ret = unsafe { GetAsyncKeyState(self.code() as i32) };
+        ret & 0x8000u16 as i16 != 0
+    }
+
+    // for lock keys like caps lock
+    pub fn is_toggled(self) -> bool {
+        let ret = unsafe { GetKeyState(self.code() as i32) };
+        ret & 1 != 0
+    }
+
+    /// The key that types `c` on the current keyboard layout, if any.
+    pub fn from_char(c: char) -> Option<Self> {
+        Layout::current().key_for(c).map(|(key, _)| key)
     }
 }
 
@@ -397,122 +275,1259 @@ impl Display for KeyCode {
 
 impl From<KeyCode> for u32 {
     fn from(key: KeyCode) -> Self {
-        key as u32
+        key.code()
     }
 }
 
 impl From<KeyCode> for u16 {
     fn from(key: KeyCode) -> Self {
-        key as u16
+        key.code() as u16
     }
 }
 
-impl From<u32> for KeyCode {
-    fn from(key: u32) -> Self {
-        unsafe { std::mem::transmute(key) }
+// Set 1 scan codes of the keys on a US keyboard, extended keys have 0xE0 in
+// the high byte. Where keys share a scan code the first is what it's read as.
+// NumLock is 0x45 sent as an extended key, as Windows has it. Pause isn't
+// here: its E1 1D 45 can't be sent with SendInput, so it's always sent as a
+// virtual key, and 0x45 on its own isn't read as either of them.
+const SCAN_CODES: &[(KeyCode, u16)] = &[
+    (KeyCode::VK_ESCAPE, 0x01),
+    (KeyCode::VK_1, 0x02),
+    (KeyCode::VK_2, 0x03),
+    (KeyCode::VK_3, 0x04),
+    (KeyCode::VK_4, 0x05),
+    (KeyCode::VK_5, 0x06),
+    (KeyCode::VK_6, 0x07),
+    (KeyCode::VK_7, 0x08),
+    (KeyCode::VK_8, 0x09),
+    (KeyCode::VK_9, 0x0A),
+    (KeyCode::VK_0, 0x0B),
+    (KeyCode::VK_OEM_MINUS, 0x0C),
+    (KeyCode::VK_OEM_PLUS, 0x0D),
+    (KeyCode::VK_BACK, 0x0E),
+    (KeyCode::VK_TAB, 0x0F),
+    (KeyCode::VK_Q, 0x10),
+    (KeyCode::VK_W, 0x11),
+    (KeyCode::VK_E, 0x12),
+    (KeyCode::VK_R, 0x13),
+    (KeyCode::VK_T, 0x14),
+    (KeyCode::VK_Y, 0x15),
+    (KeyCode::VK_U, 0x16),
+    (KeyCode::VK_I, 0x17),
+    (KeyCode::VK_O, 0x18),
+    (KeyCode::VK_P, 0x19),
+    (KeyCode::VK_OEM_4, 0x1A),
+    (KeyCode::VK_OEM_6, 0x1B),
+    (KeyCode::VK_RETURN, 0x1C),
+    (KeyCode::VK_LCONTROL, 0x1D),
+    (KeyCode::VK_CONTROL, 0x1D),
+    (KeyCode::VK_A, 0x1E),
+    (KeyCode::VK_S, 0x1F),
+    (KeyCode::VK_D, 0x20),
+    (KeyCode::VK_F, 0x21),
+    (KeyCode::VK_G, 0x22),
+    (KeyCode::VK_H, 0x23),
+    (KeyCode::VK_J, 0x24),
+    (KeyCode::VK_K, 0x25),
+    (KeyCode::VK_L, 0x26),
+    (KeyCode::VK_OEM_1, 0x27),
+    (KeyCode::VK_OEM_7, 0x28),
+    (KeyCode::VK_OEM_3, 0x29),
+    (KeyCode::VK_LSHIFT, 0x2A),
+    (KeyCode::VK_SHIFT, 0x2A),
+    (KeyCode::VK_OEM_5, 0x2B),
+    (KeyCode::VK_Z, 0x2C),
+    (KeyCode::VK_X, 0x2D),
+    (KeyCode::VK_C, 0x2E),
+    (KeyCode::VK_V, 0x2F),
+    (KeyCode::VK_B, 0x30),
+    (KeyCode::VK_N, 0x31),
+    (KeyCode::VK_M, 0x32),
+    (KeyCode::VK_OEM_COMMA, 0x33),
+    (KeyCode::VK_OEM_PERIOD, 0x34),
+    (KeyCode::VK_OEM_2, 0x35),
+    (KeyCode::VK_RSHIFT, 0x36),
+    (KeyCode::VK_MULTIPLY, 0x37),
+    (KeyCode::VK_LMENU, 0x38),
+    (KeyCode::VK_MENU, 0x38),
+    (KeyCode::VK_SPACE, 0x39),
+    (KeyCode::VK_CAPITAL, 0x3A),
+    (KeyCode::VK_F1, 0x3B),
+    (KeyCode::VK_F2, 0x3C),
+    (KeyCode::VK_F3, 0x3D),
+    (KeyCode::VK_F4, 0x3E),
+    (KeyCode::VK_F5, 0x3F),
+    (KeyCode::VK_F6, 0x40),
+    (KeyCode::VK_F7, 0x41),
+    (KeyCode::VK_F8, 0x42),
+    (KeyCode::VK_F9, 0x43),
+    (KeyCode::VK_F10, 0x44),
+    (KeyCode::VK_SCROLL, 0x46),
+    (KeyCode::VK_NUMPAD7, 0x47),
+    (KeyCode::VK_NUMPAD8, 0x48),
+    (KeyCode::VK_NUMPAD9, 0x49),
+    (KeyCode::VK_SUBTRACT, 0x4A),
+    (KeyCode::VK_NUMPAD4, 0x4B),
+    (KeyCode::VK_NUMPAD5, 0x4C),
+    (KeyCode::VK_CLEAR, 0x4C),
+    (KeyCode::VK_NUMPAD6, 0x4D),
+    (KeyCode::VK_ADD, 0x4E),
+    (KeyCode::VK_NUMPAD1, 0x4F),
+    (KeyCode::VK_NUMPAD2, 0x50),
+    (KeyCode::VK_NUMPAD3, 0x51),
+    (KeyCode::VK_NUMPAD0, 0x52),
+    (KeyCode::VK_DECIMAL, 0x53),
+    (KeyCode::VK_OEM_102, 0x56),
+    (KeyCode::VK_F11, 0x57),
+    (KeyCode::VK_F12, 0x58),
+    (KeyCode::VK_F13, 0x64),
+    (KeyCode::VK_F14, 0x65),
+    (KeyCode::VK_F15, 0x66),
+    (KeyCode::VK_F16, 0x67),
+    (KeyCode::VK_F17, 0x68),
+    (KeyCode::VK_F18, 0x69),
+    (KeyCode::VK_F19, 0x6A),
+    (KeyCode::VK_F20, 0x6B),
+    (KeyCode::VK_F21, 0x6C),
+    (KeyCode::VK_F22, 0x6D),
+    (KeyCode::VK_F23, 0x6E),
+    (KeyCode::VK_F24, 0x76),
+    (KeyCode::VK_MEDIA_PREV_TRACK, 0xE010),
+    (KeyCode::VK_MEDIA_NEXT_TRACK, 0xE019),
+    (KeyCode::VK_RCONTROL, 0xE01D),
+    (KeyCode::VK_VOLUME_MUTE, 0xE020),
+    (KeyCode::VK_LAUNCH_APP2, 0xE021),
+    (KeyCode::VK_MEDIA_PLAY_PAUSE, 0xE022),
+    (KeyCode::VK_MEDIA_STOP, 0xE024),
+    (KeyCode::VK_VOLUME_DOWN, 0xE02E),
+    (KeyCode::VK_VOLUME_UP, 0xE030),
+    (KeyCode::VK_BROWSER_HOME, 0xE032),
+    (KeyCode::VK_DIVIDE, 0xE035),
+    (KeyCode::VK_SNAPSHOT, 0xE037),
+    (KeyCode::VK_RMENU, 0xE038),
+    (KeyCode::VK_CANCEL, 0xE046),
+    (KeyCode::VK_NUMLOCK, 0xE045),
+    (KeyCode::VK_HOME, 0xE047),
+    (KeyCode::VK_UP, 0xE048),
+    (KeyCode::VK_PRIOR, 0xE049),
+    (KeyCode::VK_LEFT, 0xE04B),
+    (KeyCode::VK_RIGHT, 0xE04D),
+    (KeyCode::VK_END, 0xE04F),
+    (KeyCode::VK_DOWN, 0xE050),
+    (KeyCode::VK_NEXT, 0xE051),
+    (KeyCode::VK_INSERT, 0xE052),
+    (KeyCode::VK_DELETE, 0xE053),
+    (KeyCode::VK_LWIN, 0xE05B),
+    (KeyCode::VK_RWIN, 0xE05C),
+    (KeyCode::VK_APPS, 0xE05D),
+    (KeyCode::VK_SLEEP, 0xE05F),
+    (KeyCode::VK_BROWSER_SEARCH, 0xE065),
+    (KeyCode::VK_BROWSER_FAVORITES, 0xE066),
+    (KeyCode::VK_BROWSER_REFRESH, 0xE067),
+    (KeyCode::VK_BROWSER_STOP, 0xE068),
+    (KeyCode::VK_BROWSER_FORWARD, 0xE069),
+    (KeyCode::VK_BROWSER_BACK, 0xE06A),
+    (KeyCode::VK_LAUNCH_APP1, 0xE06B),
+    (KeyCode::VK_LAUNCH_MAIL, 0xE06C),
+    (KeyCode::VK_LAUNCH_MEDIA_SELECT, 0xE06D),
+];
+
+impl KeyCode {
+    /// The key's scan code, with 0xE0 in the high byte for extended keys.
+    pub fn scan_code(self) -> Option<u16> {
+        SCAN_CODES.iter().find(|(key, _)| *key == self).map(|(_, code)| *code)
+    }
+
+    pub fn from_scan_code(code: u16) -> Option<KeyCode> {
+        SCAN_CODES.iter().find(|(_, x)| *x == code).map(|(key, _)| *key)
+    }
+
+    /// Whether the key is sent with `KEYEVENTF_EXTENDEDKEY`, like the arrows,
+    /// Insert and Delete, right Ctrl and Alt or the numpad's `/`. Without it
+    /// they're the numpad's or left hand side's keys.
+    pub fn is_extended(self) -> bool {
+        self.scan_code().is_some_and(|code| code >> 8 == 0xE0)
     }
 }
 
+// Linux evdev KEY_* and BTN_* codes. As with the scan codes, keys that
+// share a code are read as the first one, so Shift, Ctrl and Alt come back
+// as the left ones and the numpad's Enter as Enter.
+const EVDEV_CODES: &[(KeyCode, u16)] = &[
+    (KeyCode::VK_ESCAPE, 1),
+    (KeyCode::VK_1, 2),
+    (KeyCode::VK_2, 3),
+    (KeyCode::VK_3, 4),
+    (KeyCode::VK_4, 5),
+    (KeyCode::VK_5, 6),
+    (KeyCode::VK_6, 7),
+    (KeyCode::VK_7, 8),
+    (KeyCode::VK_8, 9),
+    (KeyCode::VK_9, 10),
+    (KeyCode::VK_0, 11),
+    (KeyCode::VK_OEM_MINUS, 12),
+    (KeyCode::VK_OEM_PLUS, 13),
+    (KeyCode::VK_BACK, 14),
+    (KeyCode::VK_TAB, 15),
+    (KeyCode::VK_Q, 16),
+    (KeyCode::VK_W, 17),
+    (KeyCode::VK_E, 18),
+    (KeyCode::VK_R, 19),
+    (KeyCode::VK_T, 20),
+    (KeyCode::VK_Y, 21),
+    (KeyCode::VK_U, 22),
+    (KeyCode::VK_I, 23),
+    (KeyCode::VK_O, 24),
+    (KeyCode::VK_P, 25),
+    (KeyCode::VK_OEM_4, 26),
+    (KeyCode::VK_OEM_6, 27),
+    (KeyCode::VK_RETURN, 28),
+    (KeyCode::VK_LCONTROL, 29),
+    (KeyCode::VK_CONTROL, 29),
+    (KeyCode::VK_A, 30),
+    (KeyCode::VK_S, 31),
+    (KeyCode::VK_D, 32),
+    (KeyCode::VK_F, 33),
+    (KeyCode::VK_G, 34),
+    (KeyCode::VK_H, 35),
+    (KeyCode::VK_J, 36),
+    (KeyCode::VK_K, 37),
+    (KeyCode::VK_L, 38),
+    (KeyCode::VK_OEM_1, 39),
+    (KeyCode::VK_OEM_7, 40),
+    (KeyCode::VK_OEM_3, 41),
+    (KeyCode::VK_LSHIFT, 42),
+    (KeyCode::VK_SHIFT, 42),
+    (KeyCode::VK_OEM_5, 43),
+    (KeyCode::VK_Z, 44),
+    (KeyCode::VK_X, 45),
+    (KeyCode::VK_C, 46),
+    (KeyCode::VK_V, 47),
+    (KeyCode::VK_B, 48),
+    (KeyCode::VK_N, 49),
+    (KeyCode::VK_M, 50),
+    (KeyCode::VK_OEM_COMMA, 51),
+    (KeyCode::VK_OEM_PERIOD, 52),
+    (KeyCode::VK_OEM_2, 53),
+    (KeyCode::VK_RSHIFT, 54),
+    (KeyCode::VK_MULTIPLY, 55),
+    (KeyCode::VK_LMENU, 56),
+    (KeyCode::VK_MENU, 56),
+    (KeyCode::VK_SPACE, 57),
+    (KeyCode::VK_CAPITAL, 58),
+    (KeyCode::VK_F1, 59),
+    (KeyCode::VK_F2, 60),
+    (KeyCode::VK_F3, 61),
+    (KeyCode::VK_F4, 62),
+    (KeyCode::VK_F5, 63),
+    (KeyCode::VK_F6, 64),
+    (KeyCode::VK_F7, 65),
+    (KeyCode::VK_F8, 66),
+    (KeyCode::VK_F9, 67),
+    (KeyCode::VK_F10, 68),
+    (KeyCode::VK_NUMLOCK, 69),
+    (KeyCode::VK_SCROLL, 70),
+    (KeyCode::VK_NUMPAD7, 71),
+    (KeyCode::VK_NUMPAD8, 72),
+    (KeyCode::VK_NUMPAD9, 73),
+    (KeyCode::VK_SUBTRACT, 74),
+    (KeyCode::VK_NUMPAD4, 75),
+    (KeyCode::VK_NUMPAD5, 76),
+    (KeyCode::VK_NUMPAD6, 77),
+    (KeyCode::VK_ADD, 78),
+    (KeyCode::VK_NUMPAD1, 79),
+    (KeyCode::VK_NUMPAD2, 80),
+    (KeyCode::VK_NUMPAD3, 81),
+    (KeyCode::VK_NUMPAD0, 82),
+    (KeyCode::VK_DECIMAL, 83),
+    (KeyCode::VK_OEM_102, 86),
+    (KeyCode::VK_F11, 87),
+    (KeyCode::VK_F12, 88),
+    (KeyCode::VK_RETURN, 96),
+    (KeyCode::VK_RCONTROL, 97),
+    (KeyCode::VK_DIVIDE, 98),
+    (KeyCode::VK_SNAPSHOT, 99),
+    (KeyCode::VK_RMENU, 100),
+    (KeyCode::VK_HOME, 102),
+    (KeyCode::VK_UP, 103),
+    (KeyCode::VK_PRIOR, 104),
+    (KeyCode::VK_LEFT, 105),
+    (KeyCode::VK_RIGHT, 106),
+    (KeyCode::VK_END, 107),
+    (KeyCode::VK_DOWN, 108),
+    (KeyCode::VK_NEXT, 109),
+    (KeyCode::VK_INSERT, 110),
+    (KeyCode::VK_DELETE, 111),
+    (KeyCode::VK_VOLUME_MUTE, 113),
+    (KeyCode::VK_VOLUME_DOWN, 114),
+    (KeyCode::VK_VOLUME_UP, 115),
+    (KeyCode::VK_PAUSE, 119),
+    (KeyCode::VK_LWIN, 125),
+    (KeyCode::VK_RWIN, 126),
+    (KeyCode::VK_APPS, 127),
+    (KeyCode::VK_BROWSER_STOP, 128),
+    (KeyCode::VK_HELP, 138),
+    (KeyCode::VK_LAUNCH_APP2, 140),
+    (KeyCode::VK_SLEEP, 142),
+    (KeyCode::VK_LAUNCH_MAIL, 155),
+    (KeyCode::VK_BROWSER_FAVORITES, 156),
+    (KeyCode::VK_LAUNCH_APP1, 157),
+    (KeyCode::VK_BROWSER_BACK, 158),
+    (KeyCode::VK_BROWSER_FORWARD, 159),
+    (KeyCode::VK_MEDIA_NEXT_TRACK, 163),
+    (KeyCode::VK_MEDIA_PLAY_PAUSE, 164),
+    (KeyCode::VK_MEDIA_PREV_TRACK, 165),
+    (KeyCode::VK_MEDIA_STOP, 166),
+    (KeyCode::VK_BROWSER_HOME, 172),
+    (KeyCode::VK_BROWSER_REFRESH, 173),
+    (KeyCode::VK_F13, 183),
+    (KeyCode::VK_F14, 184),
+    (KeyCode::VK_F15, 185),
+    (KeyCode::VK_F16, 186),
+    (KeyCode::VK_F17, 187),
+    (KeyCode::VK_F18, 188),
+    (KeyCode::VK_F19, 189),
+    (KeyCode::VK_F20, 190),
+    (KeyCode::VK_F21, 191),
+    (KeyCode::VK_F22, 192),
+    (KeyCode::VK_F23, 193),
+    (KeyCode::VK_F24, 194),
+    (KeyCode::VK_BROWSER_SEARCH, 217),
+    (KeyCode::VK_LAUNCH_MEDIA_SELECT, 226),
+    (KeyCode::VK_LBUTTON, 0x110),
+    (KeyCode::VK_RBUTTON, 0x111),
+    (KeyCode::VK_MBUTTON, 0x112),
+    (KeyCode::VK_XBUTTON1, 0x113),
+    (KeyCode::VK_XBUTTON2, 0x114),
+];
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
-#[allow(non_camel_case_types)]
-#[repr(u16)]
-pub enum KeyboardFlags {
-    NONE = 0x00,
-    KEYEVENTF_EXTENDEDKEY = 0x01,
-    KEYEVENTF_KEYUP = 0x02,
-    KEYEVENTF_UNICODE = 0x04,
-    KEYEVENTF_SCANCODE = 0x08,
+// USB HID usages, with the usage page in the high 16 bits: 0x07 for the
+// keyboard, 0x0C for the media keys, 0x09 for mouse buttons and 0x01 for
+// Sleep.
+const HID_USAGES: &[(KeyCode, u32)] = &[
+    (KeyCode::VK_A, 0x07_0004),
+    (KeyCode::VK_B, 0x07_0005),
+    (KeyCode::VK_C, 0x07_0006),
+    (KeyCode::VK_D, 0x07_0007),
+    (KeyCode::VK_E, 0x07_0008),
+    (KeyCode::VK_F, 0x07_0009),
+    (KeyCode::VK_G, 0x07_000A),
+    (KeyCode::VK_H, 0x07_000B),
+    (KeyCode::VK_I, 0x07_000C),
+    (KeyCode::VK_J, 0x07_000D),
+    (KeyCode::VK_K, 0x07_000E),
+    (KeyCode::VK_L, 0x07_000F),
+    (KeyCode::VK_M, 0x07_0010),
+    (KeyCode::VK_N, 0x07_0011),
+    (KeyCode::VK_O, 0x07_0012),
+    (KeyCode::VK_P, 0x07_0013),
+    (KeyCode::VK_Q, 0x07_0014),
+    (KeyCode::VK_R, 0x07_0015),
+    (KeyCode::VK_S, 0x07_0016),
+    (KeyCode::VK_T, 0x07_0017),
+    (KeyCode::VK_U, 0x07_0018),
+    (KeyCode::VK_V, 0x07_0019),
+    (KeyCode::VK_W, 0x07_001A),
+    (KeyCode::VK_X, 0x07_001B),
+    (KeyCode::VK_Y, 0x07_001C),
+    (KeyCode::VK_Z, 0x07_001D),
+    (KeyCode::VK_1, 0x07_001E),
+    (KeyCode::VK_2, 0x07_001F),
+    (KeyCode::VK_3, 0x07_0020),
+    (KeyCode::VK_4, 0x07_0021),
+    (KeyCode::VK_5, 0x07_0022),
+    (KeyCode::VK_6, 0x07_0023),
+    (KeyCode::VK_7, 0x07_0024),
+    (KeyCode::VK_8, 0x07_0025),
+    (KeyCode::VK_9, 0x07_0026),
+    (KeyCode::VK_0, 0x07_0027),
+    (KeyCode::VK_RETURN, 0x07_0028),
+    (KeyCode::VK_ESCAPE, 0x07_0029),
+    (KeyCode::VK_BACK, 0x07_002A),
+    (KeyCode::VK_TAB, 0x07_002B),
+    (KeyCode::VK_SPACE, 0x07_002C),
+    (KeyCode::VK_OEM_MINUS, 0x07_002D),
+    (KeyCode::VK_OEM_PLUS, 0x07_002E),
+    (KeyCode::VK_OEM_4, 0x07_002F),
+    (KeyCode::VK_OEM_6, 0x07_0030),
+    (KeyCode::VK_OEM_5, 0x07_0031),
+    (KeyCode::VK_OEM_1, 0x07_0033),
+    (KeyCode::VK_OEM_7, 0x07_0034),
+    (KeyCode::VK_OEM_3, 0x07_0035),
+    (KeyCode::VK_OEM_COMMA, 0x07_0036),
+    (KeyCode::VK_OEM_PERIOD, 0x07_0037),
+    (KeyCode::VK_OEM_2, 0x07_0038),
+    (KeyCode::VK_CAPITAL, 0x07_0039),
+    (KeyCode::VK_F1, 0x07_003A),
+    (KeyCode::VK_F2, 0x07_003B),
+    (KeyCode::VK_F3, 0x07_003C),
+    (KeyCode::VK_F4, 0x07_003D),
+    (KeyCode::VK_F5, 0x07_003E),
+    (KeyCode::VK_F6, 0x07_003F),
+    (KeyCode::VK_F7, 0x07_0040),
+    (KeyCode::VK_F8, 0x07_0041),
+    (KeyCode::VK_F9, 0x07_0042),
+    (KeyCode::VK_F10, 0x07_0043),
+    (KeyCode::VK_F11, 0x07_0044),
+    (KeyCode::VK_F12, 0x07_0045),
+    (KeyCode::VK_SNAPSHOT, 0x07_0046),
+    (KeyCode::VK_SCROLL, 0x07_0047),
+    (KeyCode::VK_PAUSE, 0x07_0048),
+    (KeyCode::VK_INSERT, 0x07_0049),
+    (KeyCode::VK_HOME, 0x07_004A),
+    (KeyCode::VK_PRIOR, 0x07_004B),
+    (KeyCode::VK_DELETE, 0x07_004C),
+    (KeyCode::VK_END, 0x07_004D),
+    (KeyCode::VK_NEXT, 0x07_004E),
+    (KeyCode::VK_RIGHT, 0x07_004F),
+    (KeyCode::VK_LEFT, 0x07_0050),
+    (KeyCode::VK_DOWN, 0x07_0051),
+    (KeyCode::VK_UP, 0x07_0052),
+    (KeyCode::VK_NUMLOCK, 0x07_0053),
+    (KeyCode::VK_DIVIDE, 0x07_0054),
+    (KeyCode::VK_MULTIPLY, 0x07_0055),
+    (KeyCode::VK_SUBTRACT, 0x07_0056),
+    (KeyCode::VK_ADD, 0x07_0057),
+    (KeyCode::VK_RETURN, 0x07_0058),
+    (KeyCode::VK_NUMPAD1, 0x07_0059),
+    (KeyCode::VK_NUMPAD2, 0x07_005A),
+    (KeyCode::VK_NUMPAD3, 0x07_005B),
+    (KeyCode::VK_NUMPAD4, 0x07_005C),
+    (KeyCode::VK_NUMPAD5, 0x07_005D),
+    (KeyCode::VK_NUMPAD6, 0x07_005E),
+    (KeyCode::VK_NUMPAD7, 0x07_005F),
+    (KeyCode::VK_NUMPAD8, 0x07_0060),
+    (KeyCode::VK_NUMPAD9, 0x07_0061),
+    (KeyCode::VK_NUMPAD0, 0x07_0062),
+    (KeyCode::VK_DECIMAL, 0x07_0063),
+    (KeyCode::VK_OEM_102, 0x07_0064),
+    (KeyCode::VK_APPS, 0x07_0065),
+    (KeyCode::VK_F13, 0x07_0068),
+    (KeyCode::VK_F14, 0x07_0069),
+    (KeyCode::VK_F15, 0x07_006A),
+    (KeyCode::VK_F16, 0x07_006B),
+    (KeyCode::VK_F17, 0x07_006C),
+    (KeyCode::VK_F18, 0x07_006D),
+    (KeyCode::VK_F19, 0x07_006E),
+    (KeyCode::VK_F20, 0x07_006F),
+    (KeyCode::VK_F21, 0x07_0070),
+    (KeyCode::VK_F22, 0x07_0071),
+    (KeyCode::VK_F23, 0x07_0072),
+    (KeyCode::VK_F24, 0x07_0073),
+    (KeyCode::VK_HELP, 0x07_0075),
+    (KeyCode::VK_CLEAR, 0x07_009C),
+    (KeyCode::VK_LCONTROL, 0x07_00E0),
+    (KeyCode::VK_CONTROL, 0x07_00E0),
+    (KeyCode::VK_LSHIFT, 0x07_00E1),
+    (KeyCode::VK_SHIFT, 0x07_00E1),
+    (KeyCode::VK_LMENU, 0x07_00E2),
+    (KeyCode::VK_MENU, 0x07_00E2),
+    (KeyCode::VK_LWIN, 0x07_00E3),
+    (KeyCode::VK_RCONTROL, 0x07_00E4),
+    (KeyCode::VK_RSHIFT, 0x07_00E5),
+    (KeyCode::VK_RMENU, 0x07_00E6),
+    (KeyCode::VK_RWIN, 0x07_00E7),
+    (KeyCode::VK_SLEEP, 0x01_0082),
+    (KeyCode::VK_LBUTTON, 0x09_0001),
+    (KeyCode::VK_RBUTTON, 0x09_0002),
+    (KeyCode::VK_MBUTTON, 0x09_0003),
+    (KeyCode::VK_XBUTTON1, 0x09_0004),
+    (KeyCode::VK_XBUTTON2, 0x09_0005),
+    (KeyCode::VK_MEDIA_NEXT_TRACK, 0x0C_00B5),
+    (KeyCode::VK_MEDIA_PREV_TRACK, 0x0C_00B6),
+    (KeyCode::VK_MEDIA_STOP, 0x0C_00B7),
+    (KeyCode::VK_MEDIA_PLAY_PAUSE, 0x0C_00CD),
+    (KeyCode::VK_VOLUME_MUTE, 0x0C_00E2),
+    (KeyCode::VK_VOLUME_UP, 0x0C_00E9),
+    (KeyCode::VK_VOLUME_DOWN, 0x0C_00EA),
+    (KeyCode::VK_LAUNCH_MEDIA_SELECT, 0x0C_0183),
+    (KeyCode::VK_LAUNCH_MAIL, 0x0C_018A),
+    (KeyCode::VK_LAUNCH_APP2, 0x0C_0192),
+    (KeyCode::VK_LAUNCH_APP1, 0x0C_0194),
+    (KeyCode::VK_BROWSER_SEARCH, 0x0C_0221),
+    (KeyCode::VK_BROWSER_HOME, 0x0C_0223),
+    (KeyCode::VK_BROWSER_BACK, 0x0C_0224),
+    (KeyCode::VK_BROWSER_FORWARD, 0x0C_0225),
+    (KeyCode::VK_BROWSER_STOP, 0x0C_0226),
+    (KeyCode::VK_BROWSER_REFRESH, 0x0C_0227),
+    (KeyCode::VK_BROWSER_FAVORITES, 0x0C_022A),
+];
+
+impl KeyCode {
+    /// The Linux evdev code for the key, like `KEY_A` (30).
+    pub fn evdev_code(self) -> Option<u16> {
+        EVDEV_CODES.iter().find(|(key, _)| *key == self).map(|(_, code)| *code)
+    }
+
+    pub fn from_evdev_code(code: u16) -> Option<KeyCode> {
+        EVDEV_CODES.iter().find(|(_, x)| *x == code).map(|(key, _)| *key)
+    }
+
+    /// The USB HID usage for the key, with the usage page in the high 16
+    /// bits, like 0x07_0004 for A.
+    pub fn hid_usage(self) -> Option<u32> {
+        HID_USAGES.iter().find(|(key, _)| *key == self).map(|(_, usage)| *usage)
+    }
+
+    pub fn from_hid_usage(usage: u32) -> Option<KeyCode> {
+        HID_USAGES.iter().find(|(_, x)| *x == usage).map(|(key, _)| *key)
+    }
 }
 
-impl BitOr for KeyboardFlags {
-    type Output = Self;
 
-    fn bitor(self, rhs: Self) -> Self::Output {
-        unsafe { std::mem::transmute(self as u16 | rhs as u16) }
+
+bitflags! {
+    /// KEYBDINPUT's dwFlags.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+    pub struct KeyboardFlags: u16 {
+        const KEYEVENTF_EXTENDEDKEY = 0x01;
+        const KEYEVENTF_KEYUP = 0x02;
+        const KEYEVENTF_UNICODE = 0x04;
+        const KEYEVENTF_SCANCODE = 0x08;
     }
 }
 
-impl BitOrAssign for KeyboardFlags {
-    fn bitor_assign(&mut self, rhs: Self) {
-        *self = *self | rhs;
+use std::fmt::Display;
+
+bitflags! {
+    /// MOUSEINPUT's dwFlags.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+    pub struct MouseFlags: u16 {
+        const MOUSEEVENTF_MOVE = 0x01;
+        const MOUSEEVENTF_LEFTDOWN = 0x02;
+        const MOUSEEVENTF_LEFTUP = 0x04;
+        const MOUSEEVENTF_RIGHTDOWN = 0x08;
+        const MOUSEEVENTF_RIGHTUP = 0x10;
+        const MOUSEEVENTF_MIDDLEDOWN = 0x20;
+        const MOUSEEVENTF_MIDDLEUP = 0x40;
+        const MOUSEEVENTF_XDOWN = 0x80;
+        const MOUSEEVENTF_XUP = 0x100;
+        const MOUSEEVENTF_WHEEL = 0x800;
+        const MOUSEEVENTF_HWHEEL = 0x1000;
+        const MOUSEEVENTF_MOVE_NOCOALESCE = 0x2000;
+        const MOUSEEVENTF_VIRTUALDESK = 0x4000;
+        const MOUSEEVENTF_ABSOLUTE = 0x8000;
     }
 }
 
-use std::{ops::{BitOr, BitOrAssign, BitAndAssign, BitAnd}, fmt::Display};
+
 #[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
 #[allow(non_camel_case_types)]
 #[repr(u16)]
-pub enum MouseFlags {
+pub enum MouseData {
     NONE = 0x00,
-    MOUSEEVENTF_MOVE = 0x01,
-    MOUSEEVENTF_LEFTDOWN = 0x02,
-    MOUSEEVENTF_LEFTUP = 0x04,
-    MOUSEEVENTF_RIGHTDOWN = 0x08,
-    MOUSEEVENTF_RIGHTUP = 0x10,
-    MOUSEEVENTF_MIDDLEDOWN = 0x20,
-    MOUSEEVENTF_MIDDLEUP = 0x40,
-    MOUSEEVENTF_XDOWN = 0x80,
-    MOUSEEVENTF_XUP = 0x100,
-    MOUSEEVENTF_WHEEL = 0x800,
-    MOUSEEVENTF_HWHEEL = 0x1000,
-    MOUSEEVENTF_MOVE_NOCOALESCE = 0x2000,
-    MOUSEEVENTF_VIRTUALDESK = 0x4000,
-    MOUSEEVENTF_ABSOLUTE = 0x8000
+    XBUTTON1 = 0x01,
+    XBUTTON2 = 0x02,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyUpDown {
+    Down,
+    Up,
+}
+
+// Macro files name keys, buttons and key states like AHK does (`LCtrl`, `/`,
+// `LButton down`) rather than by their Win32 constants, and flags as lists
+// of names. The old names are still read, and so are the bare RON
+// identifiers files used to have.
+
+// AHK's name for each key that has one, the rest are written as VK_*
+// in macro files and vkXX in scripts
+const AHK_KEY_NAMES: &[(KeyCode, &str)] = &[
+    (KeyCode::VK_LBUTTON, "LButton"),
+    (KeyCode::VK_RBUTTON, "RButton"),
+    (KeyCode::VK_CANCEL, "CtrlBreak"),
+    (KeyCode::VK_MBUTTON, "MButton"),
+    (KeyCode::VK_XBUTTON1, "XButton1"),
+    (KeyCode::VK_XBUTTON2, "XButton2"),
+    (KeyCode::VK_BACK, "Backspace"),
+    (KeyCode::VK_TAB, "Tab"),
+    (KeyCode::VK_CLEAR, "NumpadClear"),
+    (KeyCode::VK_RETURN, "Enter"),
+    (KeyCode::VK_SHIFT, "Shift"),
+    (KeyCode::VK_CONTROL, "Ctrl"),
+    (KeyCode::VK_MENU, "Alt"),
+    (KeyCode::VK_PAUSE, "Pause"),
+    (KeyCode::VK_CAPITAL, "CapsLock"),
+    (KeyCode::VK_ESCAPE, "Esc"),
+    (KeyCode::VK_SPACE, "Space"),
+    (KeyCode::VK_PRIOR, "PgUp"),
+    (KeyCode::VK_NEXT, "PgDn"),
+    (KeyCode::VK_END, "End"),
+    (KeyCode::VK_HOME, "Home"),
+    (KeyCode::VK_LEFT, "Left"),
+    (KeyCode::VK_UP, "Up"),
+    (KeyCode::VK_RIGHT, "Right"),
+    (KeyCode::VK_DOWN, "Down"),
+    (KeyCode::VK_SNAPSHOT, "PrintScreen"),
+    (KeyCode::VK_INSERT, "Insert"),
+    (KeyCode::VK_DELETE, "Delete"),
+    (KeyCode::VK_HELP, "Help"),
+    (KeyCode::VK_0, "0"),
+    (KeyCode::VK_1, "1"),
+    (KeyCode::VK_2, "2"),
+    (KeyCode::VK_3, "3"),
+    (KeyCode::VK_4, "4"),
+    (KeyCode::VK_5, "5"),
+    (KeyCode::VK_6, "6"),
+    (KeyCode::VK_7, "7"),
+    (KeyCode::VK_8, "8"),
+    (KeyCode::VK_9, "9"),
+    (KeyCode::VK_A, "a"),
+    (KeyCode::VK_B, "b"),
+    (KeyCode::VK_C, "c"),
+    (KeyCode::VK_D, "d"),
+    (KeyCode::VK_E, "e"),
+    (KeyCode::VK_F, "f"),
+    (KeyCode::VK_G, "g"),
+    (KeyCode::VK_H, "h"),
+    (KeyCode::VK_I, "i"),
+    (KeyCode::VK_J, "j"),
+    (KeyCode::VK_K, "k"),
+    (KeyCode::VK_L, "l"),
+    (KeyCode::VK_M, "m"),
+    (KeyCode::VK_N, "n"),
+    (KeyCode::VK_O, "o"),
+    (KeyCode::VK_P, "p"),
+    (KeyCode::VK_Q, "q"),
+    (KeyCode::VK_R, "r"),
+    (KeyCode::VK_S, "s"),
+    (KeyCode::VK_T, "t"),
+    (KeyCode::VK_U, "u"),
+    (KeyCode::VK_V, "v"),
+    (KeyCode::VK_W, "w"),
+    (KeyCode::VK_X, "x"),
+    (KeyCode::VK_Y, "y"),
+    (KeyCode::VK_Z, "z"),
+    (KeyCode::VK_LWIN, "LWin"),
+    (KeyCode::VK_RWIN, "RWin"),
+    (KeyCode::VK_APPS, "AppsKey"),
+    (KeyCode::VK_SLEEP, "Sleep"),
+    (KeyCode::VK_NUMPAD0, "Numpad0"),
+    (KeyCode::VK_NUMPAD1, "Numpad1"),
+    (KeyCode::VK_NUMPAD2, "Numpad2"),
+    (KeyCode::VK_NUMPAD3, "Numpad3"),
+    (KeyCode::VK_NUMPAD4, "Numpad4"),
+    (KeyCode::VK_NUMPAD5, "Numpad5"),
+    (KeyCode::VK_NUMPAD6, "Numpad6"),
+    (KeyCode::VK_NUMPAD7, "Numpad7"),
+    (KeyCode::VK_NUMPAD8, "Numpad8"),
+    (KeyCode::VK_NUMPAD9, "Numpad9"),
+    (KeyCode::VK_MULTIPLY, "NumpadMult"),
+    (KeyCode::VK_ADD, "NumpadAdd"),
+    (KeyCode::VK_SUBTRACT, "NumpadSub"),
+    (KeyCode::VK_DECIMAL, "NumpadDot"),
+    (KeyCode::VK_DIVIDE, "NumpadDiv"),
+    (KeyCode::VK_F1, "F1"),
+    (KeyCode::VK_F2, "F2"),
+    (KeyCode::VK_F3, "F3"),
+    (KeyCode::VK_F4, "F4"),
+    (KeyCode::VK_F5, "F5"),
+    (KeyCode::VK_F6, "F6"),
+    (KeyCode::VK_F7, "F7"),
+    (KeyCode::VK_F8, "F8"),
+    (KeyCode::VK_F9, "F9"),
+    (KeyCode::VK_F10, "F10"),
+    (KeyCode::VK_F11, "F11"),
+    (KeyCode::VK_F12, "F12"),
+    (KeyCode::VK_F13, "F13"),
+    (KeyCode::VK_F14, "F14"),
+    (KeyCode::VK_F15, "F15"),
+    (KeyCode::VK_F16, "F16"),
+    (KeyCode::VK_F17, "F17"),
+    (KeyCode::VK_F18, "F18"),
+    (KeyCode::VK_F19, "F19"),
+    (KeyCode::VK_F20, "F20"),
+    (KeyCode::VK_F21, "F21"),
+    (KeyCode::VK_F22, "F22"),
+    (KeyCode::VK_F23, "F23"),
+    (KeyCode::VK_F24, "F24"),
+    (KeyCode::VK_NUMLOCK, "NumLock"),
+    (KeyCode::VK_SCROLL, "ScrollLock"),
+    (KeyCode::VK_WHEEL_LEFT, "WheelLeft"),
+    (KeyCode::VK_WHEEL_RIGHT, "WheelRight"),
+    (KeyCode::VK_WHEEL_DOWN, "WheelDown"),
+    (KeyCode::VK_WHEEL_UP, "WheelUp"),
+    (KeyCode::VK_LSHIFT, "LShift"),
+    (KeyCode::VK_RSHIFT, "RShift"),
+    (KeyCode::VK_LCONTROL, "LCtrl"),
+    (KeyCode::VK_RCONTROL, "RCtrl"),
+    (KeyCode::VK_LMENU, "LAlt"),
+    (KeyCode::VK_RMENU, "RAlt"),
+    (KeyCode::VK_BROWSER_BACK, "Browser_Back"),
+    (KeyCode::VK_BROWSER_FORWARD, "Browser_Forward"),
+    (KeyCode::VK_BROWSER_REFRESH, "Browser_Refresh"),
+    (KeyCode::VK_BROWSER_STOP, "Browser_Stop"),
+    (KeyCode::VK_BROWSER_SEARCH, "Browser_Search"),
+    (KeyCode::VK_BROWSER_FAVORITES, "Browser_Favorites"),
+    (KeyCode::VK_BROWSER_HOME, "Browser_Home"),
+    (KeyCode::VK_VOLUME_MUTE, "Volume_Mute"),
+    (KeyCode::VK_VOLUME_DOWN, "Volume_Down"),
+    (KeyCode::VK_VOLUME_UP, "Volume_Up"),
+    (KeyCode::VK_MEDIA_NEXT_TRACK, "Media_Next"),
+    (KeyCode::VK_MEDIA_PREV_TRACK, "Media_Prev"),
+    (KeyCode::VK_MEDIA_STOP, "Media_Stop"),
+    (KeyCode::VK_MEDIA_PLAY_PAUSE, "Media_Play_Pause"),
+    (KeyCode::VK_LAUNCH_MAIL, "Launch_Mail"),
+    (KeyCode::VK_LAUNCH_MEDIA_SELECT, "Launch_Media"),
+    (KeyCode::VK_LAUNCH_APP1, "Launch_App1"),
+    (KeyCode::VK_LAUNCH_APP2, "Launch_App2"),
+    // the OEM keys are named by what they type on a US layout, like in AHK
+    (KeyCode::VK_OEM_1, ";"),
+    (KeyCode::VK_OEM_PLUS, "="),
+    (KeyCode::VK_OEM_COMMA, ","),
+    (KeyCode::VK_OEM_MINUS, "-"),
+    (KeyCode::VK_OEM_PERIOD, "."),
+    (KeyCode::VK_OEM_2, "/"),
+    (KeyCode::VK_OEM_3, "`"),
+    (KeyCode::VK_OEM_4, "["),
+    (KeyCode::VK_OEM_5, "\\"),
+    (KeyCode::VK_OEM_6, "]"),
+    (KeyCode::VK_OEM_7, "'"),
+];
+
+// other names AHK reads, but never writes
+const AHK_KEY_ALIASES: &[(KeyCode, &str)] = &[
+    (KeyCode::VK_BACK, "BS"),
+    (KeyCode::VK_RETURN, "Return"),
+    (KeyCode::VK_CONTROL, "Control"),
+    (KeyCode::VK_LCONTROL, "LControl"),
+    (KeyCode::VK_RCONTROL, "RControl"),
+    (KeyCode::VK_ESCAPE, "Escape"),
+    (KeyCode::VK_INSERT, "Ins"),
+    (KeyCode::VK_DELETE, "Del"),
+    // the numpad sends the same keys as the ones elsewhere on the keyboard,
+    // only the extended flag tells them apart. Send marks Enter as extended
+    // and the rest as not, see KeyCode::numpad_name.
+    (KeyCode::VK_RETURN, "NumpadEnter"),
+    (KeyCode::VK_INSERT, "NumpadIns"),
+    (KeyCode::VK_END, "NumpadEnd"),
+    (KeyCode::VK_DOWN, "NumpadDown"),
+    (KeyCode::VK_NEXT, "NumpadPgDn"),
+    (KeyCode::VK_LEFT, "NumpadLeft"),
+    (KeyCode::VK_RIGHT, "NumpadRight"),
+    (KeyCode::VK_HOME, "NumpadHome"),
+    (KeyCode::VK_UP, "NumpadUp"),
+    (KeyCode::VK_PRIOR, "NumpadPgUp"),
+    (KeyCode::VK_DELETE, "NumpadDel"),
+];
+
+const MOUSE_FLAG_NAMES: &[(MouseFlags, &str)] = &[
+    (MouseFlags::MOUSEEVENTF_MOVE, "Move"),
+    (MouseFlags::MOUSEEVENTF_LEFTDOWN, "LButton down"),
+    (MouseFlags::MOUSEEVENTF_LEFTUP, "LButton up"),
+    (MouseFlags::MOUSEEVENTF_RIGHTDOWN, "RButton down"),
+    (MouseFlags::MOUSEEVENTF_RIGHTUP, "RButton up"),
+    (MouseFlags::MOUSEEVENTF_MIDDLEDOWN, "MButton down"),
+    (MouseFlags::MOUSEEVENTF_MIDDLEUP, "MButton up"),
+    (MouseFlags::MOUSEEVENTF_XDOWN, "XButton down"),
+    (MouseFlags::MOUSEEVENTF_XUP, "XButton up"),
+    (MouseFlags::MOUSEEVENTF_WHEEL, "Wheel"),
+    (MouseFlags::MOUSEEVENTF_HWHEEL, "HWheel"),
+    (MouseFlags::MOUSEEVENTF_MOVE_NOCOALESCE, "MoveNoCoalesce"),
+    (MouseFlags::MOUSEEVENTF_VIRTUALDESK, "VirtualDesk"),
+    (MouseFlags::MOUSEEVENTF_ABSOLUTE, "Absolute"),
+];
+
+const KEYBOARD_FLAG_NAMES: &[(KeyboardFlags, &str)] = &[
+    (KeyboardFlags::KEYEVENTF_EXTENDEDKEY, "ExtendedKey"),
+    (KeyboardFlags::KEYEVENTF_KEYUP, "KeyUp"),
+    (KeyboardFlags::KEYEVENTF_UNICODE, "Unicode"),
+    (KeyboardFlags::KEYEVENTF_SCANCODE, "ScanCode"),
+];
+
+thread_local! {
+    // the version of the macro file being read, while it's an older one
+    static READING_VERSION: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// Runs `f` to read a file saved as `version`, so names are read the way
+/// that version wrote them. RON can't read the bare identifiers older files
+/// have with the same code as strings.
+pub fn with_file_version<T>(version: u32, f: impl FnOnce() -> T) -> T {
+    let was_reading = READING_VERSION.with(|x| x.replace(Some(version)));
+    let result = f();
+    READING_VERSION.with(|x| x.set(was_reading));
+    result
+}
+
+// whether the file being read is from before `version`
+fn written_before(version: u32) -> bool {
+    READING_VERSION.with(|x| x.get()).is_some_and(|x| x < version)
+}
+
+struct NameVisitor;
+
+impl<'de> Visitor<'de> for NameVisitor {
+    type Value = String;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a key name")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<String, E> {
+        Ok(name.to_string())
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<String, A::Error> {
+        let (VariantName(name), variant) = data.variant()?;
+        variant.unit_variant()?;
+        Ok(name)
+    }
+}
+
+// the old names were unit variants, and the variant is the one place RON
+// reads an identifier in struct fields too
+struct VariantName(String);
+
+impl<'de> Deserialize<'de> for VariantName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_identifier(NameVisitor).map(VariantName)
+    }
+}
+
+// keys, mouse flags and key states were identifiers before version 2
+fn deserialize_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    if written_before(2) {
+        deserializer.deserialize_enum("KeyName", &[], NameVisitor)
+    } else {
+        deserializer.deserialize_str(NameVisitor)
+    }
+}
+
+fn find_name<T: Copy>(names: &[(T, &str)], name: &str) -> Option<T> {
+    names
+        .iter()
+        .find(|(_, x)| x.eq_ignore_ascii_case(name))
+        .map(|(value, _)| *value)
+}
+
+impl KeyCode {
+    /// Looks up AHK's name for a key, like `Enter`, `esc` or `Numpad0`,
+    /// ignoring case. `VK_*` names and AHK's `vkXX` codes work too.
+    pub fn from_ahk_name(name: &str) -> Option<KeyCode> {
+        find_name(AHK_KEY_NAMES, name)
+            .or_else(|| find_name(AHK_KEY_ALIASES, name))
+            .or_else(|| KeyCode::from_vk_name(&name.to_ascii_uppercase()))
+            .or_else(|| {
+                name.get(..2)
+                    .filter(|x| x.eq_ignore_ascii_case("vk"))
+                    .and_then(|_| u32::from_str_radix(&name[2..], 16).ok())
+                    .map(KeyCode::from_code)
+            })
+    }
+
+    /// AHK's name for the key, or `vkXX` if it doesn't have one. Keys that
+    /// type a character are named by what they type on the current layout,
+    /// like AHK does.
+    pub fn to_ahk_name(self) -> String {
+        let types_char = matches!(
+            self.code(),
+            0x30..=0x39 | 0x41..=0x5A | 0xBA..=0xC0 | 0xDB..=0xDF | 0xE2
+        );
+        let name = if types_char {
+            Layout::current().char_for(self).map(String::from)
+        } else {
+            AHK_KEY_NAMES.iter().find(|(key, _)| *key == self).map(|(_, name)| name.to_string())
+        };
+        name.unwrap_or_else(|| format!("vk{:02X}", self.code()))
+    }
+
+    /// AHK's name for the numpad key that sends this extended key without
+    /// the extended flag, like `NumpadUp` for the up arrow.
+    pub fn numpad_name(self) -> Option<&'static str> {
+        if !self.is_extended() {
+            return None;
+        }
+        AHK_KEY_ALIASES
+            .iter()
+            .find(|(key, name)| *key == self && name.starts_with("Numpad"))
+            .map(|(_, name)| *name)
+    }
 }
 
-impl From<MouseFlags> for u16 {
-    fn from(val: MouseFlags) -> Self {
-        val as u16
+impl Serialize for KeyCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match AHK_KEY_NAMES.iter().find(|(key, _)| key == self) {
+            Some((_, name)) => serializer.serialize_str(name),
+            None if matches!(self, KeyCode::Unknown(_)) => {
+                serializer.serialize_str(&self.to_ahk_name())
+            }
+            None => serializer.serialize_str(&format!("{:?}", self)),
+        }
     }
 }
 
-impl From<u16> for MouseFlags {
-    fn from(key: u16) -> Self {
-        unsafe { std::mem::transmute(key) }
+impl<'de> Deserialize<'de> for KeyCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = deserialize_name(deserializer)?;
+        KeyCode::from_ahk_name(&name)
+            .ok_or_else(|| de::Error::custom(format!("unknown key {:?}", name)))
     }
 }
 
-impl BitOr for MouseFlags {
-    type Output = Self;
+// bits without a name are written as a hex number
+fn serialize_flags<F, S>(flags: F, names: &[(F, &str)], serializer: S) -> Result<S::Ok, S::Error>
+where
+    F: Flags<Bits = u16> + Copy,
+    S: Serializer,
+{
+    let mut list = vec![];
+    let mut rest = flags.bits();
+    for (flag, name) in names {
+        if flags.contains(*flag) {
+            list.push(name.to_string());
+            rest &= !flag.bits();
+        }
+    }
+    if rest != 0 {
+        list.push(format!("{:#06x}", rest));
+    }
+    serializer.collect_seq(list)
+}
 
-    fn bitor(self, rhs: Self) -> Self::Output {
-        unsafe { std::mem::transmute(self as u16 | rhs as u16) }
+fn flag_from_name<F: Flags<Bits = u16> + Copy>(names: &[(F, &str)], name: &str) -> Option<F> {
+    find_name(names, name)
+        // the Win32 names older files used
+        .or_else(|| F::from_name(name))
+        .or_else(|| (name == "NONE").then(F::empty))
+        .or_else(|| {
+            let bits = name.strip_prefix("0x")?;
+            u16::from_str_radix(bits, 16).ok().map(F::from_bits_retain)
+        })
+}
+
+struct FlagsVisitor<F: 'static> {
+    names: &'static [(F, &'static str)],
+}
+
+impl<F: Flags<Bits = u16> + Copy> FlagsVisitor<F> {
+    fn flag<E: de::Error>(&self, name: &str) -> Result<F, E> {
+        flag_from_name(self.names, name)
+            .ok_or_else(|| de::Error::custom(format!("unknown flag {:?}", name)))
     }
 }
 
-impl BitOrAssign for MouseFlags {
-    fn bitor_assign(&mut self, rhs: Self) {
-        *self = *self | rhs;
+impl<'de, F: Flags<Bits = u16> + Copy> Visitor<'de> for FlagsVisitor<F> {
+    type Value = F;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a list of flags")
+    }
+
+    // a single flag, like MouseBtn had before it was a list
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<F, E> {
+        self.flag(name)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<F, A::Error> {
+        let mut flags = F::empty();
+        while let Some(name) = seq.next_element::<String>()? {
+            flags.insert(self.flag(&name)?);
+        }
+        Ok(flags)
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<F, A::Error> {
+        let (VariantName(name), variant) = data.variant()?;
+        variant.unit_variant()?;
+        self.flag(&name)
     }
 }
 
-impl BitAndAssign for MouseFlags {
-    fn bitand_assign(&mut self, rhs: Self) {
-        *self = *self & rhs;
+// `identifiers_before` is the version the flags stopped being identifiers
+fn deserialize_flags<'de, D, F>(
+    deserializer: D,
+    names: &'static [(F, &'static str)],
+    identifiers_before: u32,
+) -> Result<F, D::Error>
+where
+    D: Deserializer<'de>,
+    F: Flags<Bits = u16> + Copy,
+{
+    let visitor = FlagsVisitor { names };
+    if written_before(identifiers_before) {
+        deserializer.deserialize_enum("Flags", &[], visitor)
+    } else {
+        deserializer.deserialize_any(visitor)
     }
 }
 
-impl BitAnd for MouseFlags {
-    type Output = Self;
+impl Serialize for MouseFlags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_flags(*self, MOUSE_FLAG_NAMES, serializer)
+    }
+}
 
-    fn bitand(self, rhs: Self) -> Self::Output {
-        unsafe { std::mem::transmute(self as u16 & rhs as u16) }
+impl<'de> Deserialize<'de> for MouseFlags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_flags(deserializer, MOUSE_FLAG_NAMES, 2)
     }
 }
 
+impl Serialize for KeyboardFlags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_flags(*self, KEYBOARD_FLAG_NAMES, serializer)
+    }
+}
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
-#[allow(non_camel_case_types)]
-#[repr(u16)]
-pub enum MouseData {
-    NONE = 0x00,
-    XBUTTON1 = 0x01,
-    XBUTTON2 = 0x02,
+impl<'de> Deserialize<'de> for KeyboardFlags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_flags(deserializer, KEYBOARD_FLAG_NAMES, 3)
+    }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
-pub enum KeyUpDown {
-    Down,
-    Up,
-}
\ No newline at end of file
+impl Serialize for KeyUpDown {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match self {
+            KeyUpDown::Down => "down",
+            KeyUpDown::Up => "up",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyUpDown {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = deserialize_name(deserializer)?;
+        match name.to_lowercase().as_str() {
+            "down" => Ok(KeyUpDown::Down),
+            "up" => Ok(KeyUpDown::Up),
+            _ => Err(de::Error::custom(format!("expected down or up, found {:?}", name))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // virtual key codes up to 0xFF that KeyCode has no variant for
+    const UNASSIGNED: &[std::ops::RangeInclusive<u32>] = &[
+        0x07..=0x07,
+        0x0A..=0x0B,
+        0x0E..=0x0F,
+        0x15..=0x1A,
+        0x3A..=0x40,
+        0x5E..=0x5E,
+        0x88..=0x8F,
+        0x92..=0x9B,
+        0xB8..=0xB9,
+        0xC1..=0xDA,
+        0xE0..=0xE1,
+        0xE3..=0xE4,
+        0xE6..=0xE6,
+        0xE8..=0xF5,
+        0xFF..=0xFF,
+    ];
+
+    #[test]
+    fn from_code_round_trips() {
+        for code in 0..=255 {
+            assert_eq!(KeyCode::from_code(code).code(), code, "{:#04X}", code);
+        }
+    }
+
+    #[test]
+    fn try_from_fails_only_without_a_variant() {
+        for code in 0..=255 {
+            let unassigned = UNASSIGNED.iter().any(|range| range.contains(&code));
+            match KeyCode::try_from(code) {
+                Ok(key) => {
+                    assert!(!unassigned, "{:#04X} became {:?}", code, key);
+                    assert!(!matches!(key, KeyCode::Unknown(_)));
+                    assert_eq!(KeyCode::from_code(code), key);
+                }
+                Err(err) => {
+                    assert!(unassigned, "{:#04X} has no variant", code);
+                    assert_eq!(err, code);
+                    assert_eq!(KeyCode::from_code(code), KeyCode::Unknown(code));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mouse_flags_from_bits() {
+        // since MouseFlags became a set, ORed flags are a valid value
+        let down = MouseFlags::MOUSEEVENTF_LEFTDOWN | MouseFlags::MOUSEEVENTF_ABSOLUTE;
+        assert_eq!(MouseFlags::from_bits(0x8002), Some(down));
+        // but bits that aren't a MOUSEEVENTF_* flag are still rejected
+        assert_eq!(MouseFlags::from_bits(0x0200), None);
+        assert_eq!(MouseFlags::from_bits(0x0402), None);
+        for flag in MouseFlags::all().iter() {
+            assert_eq!(MouseFlags::from_bits(flag.bits()), Some(flag));
+        }
+    }
+
+    // keys that share a code with a key earlier in the tables, and so are
+    // read back as it
+    const SHARED: &[(KeyCode, KeyCode)] = &[
+        (KeyCode::VK_SHIFT, KeyCode::VK_LSHIFT),
+        (KeyCode::VK_CONTROL, KeyCode::VK_LCONTROL),
+        (KeyCode::VK_MENU, KeyCode::VK_LMENU),
+    ];
+
+    fn read_back_as(key: KeyCode) -> KeyCode {
+        SHARED
+            .iter()
+            .find(|(shared, _)| *shared == key)
+            .map_or(key, |(_, first)| *first)
+    }
+
+    #[test]
+    fn scan_codes_round_trip() {
+        for (key, _) in SCAN_CODES {
+            let code = key.scan_code().unwrap();
+            let read_back = match key {
+                KeyCode::VK_CLEAR => KeyCode::VK_NUMPAD5,
+                key => read_back_as(*key),
+            };
+            assert_eq!(KeyCode::from_scan_code(code), Some(read_back), "{:?}", key);
+        }
+        assert_eq!(KeyCode::VK_NUMLOCK.scan_code(), Some(0xE045));
+        assert!(KeyCode::VK_NUMLOCK.is_extended());
+        assert_eq!(KeyCode::from_scan_code(0xE045), Some(KeyCode::VK_NUMLOCK));
+        assert_eq!(KeyCode::VK_PAUSE.scan_code(), None);
+        assert_eq!(KeyCode::from_scan_code(0x45), None);
+        assert_eq!(KeyCode::VK_SCROLL.scan_code(), Some(0x46));
+        assert_eq!(KeyCode::VK_CANCEL.scan_code(), Some(0xE046));
+    }
+
+    #[test]
+    fn evdev_codes_round_trip() {
+        for (key, _) in EVDEV_CODES {
+            let code = key.evdev_code().unwrap();
+            assert_eq!(KeyCode::from_evdev_code(code), Some(read_back_as(*key)), "{:?}", key);
+        }
+        assert_eq!(KeyCode::VK_SHIFT.evdev_code(), Some(42));
+        assert_eq!(KeyCode::VK_CONTROL.evdev_code(), Some(29));
+        assert_eq!(KeyCode::VK_MENU.evdev_code(), Some(56));
+        // Enter and the numpad's Enter
+        assert_eq!(KeyCode::from_evdev_code(28), Some(KeyCode::VK_RETURN));
+        assert_eq!(KeyCode::from_evdev_code(96), Some(KeyCode::VK_RETURN));
+        assert_eq!(KeyCode::VK_RETURN.evdev_code(), Some(28));
+    }
+
+    #[test]
+    fn hid_usages_round_trip() {
+        for (key, _) in HID_USAGES {
+            let usage = key.hid_usage().unwrap();
+            assert_eq!(KeyCode::from_hid_usage(usage), Some(read_back_as(*key)), "{:?}", key);
+        }
+        assert_eq!(KeyCode::VK_SHIFT.hid_usage(), Some(0x07_00E1));
+        assert_eq!(KeyCode::VK_CONTROL.hid_usage(), Some(0x07_00E0));
+        assert_eq!(KeyCode::VK_MENU.hid_usage(), Some(0x07_00E2));
+        assert_eq!(KeyCode::from_hid_usage(0x07_0028), Some(KeyCode::VK_RETURN));
+        assert_eq!(KeyCode::from_hid_usage(0x07_0058), Some(KeyCode::VK_RETURN));
+        assert_eq!(KeyCode::VK_RETURN.hid_usage(), Some(0x07_0028));
+    }
+
+    #[test]
+    fn ahk_names() {
+        let key = |name: &str| KeyCode::from_ahk_name(name);
+        for (n, name) in (10..=24).map(|n| (n, format!("F{}", n))) {
+            assert_eq!(key(&name).map(KeyCode::code), Some(0x70 + n - 1), "{}", name);
+        }
+        assert_eq!(key("NumpadEnter"), Some(KeyCode::VK_RETURN));
+        assert_eq!(key("Media_Play_Pause"), Some(KeyCode::VK_MEDIA_PLAY_PAUSE));
+        assert_eq!(key("media_play_pause"), Some(KeyCode::VK_MEDIA_PLAY_PAUSE));
+        assert_eq!(key("eNtEr"), Some(KeyCode::VK_RETURN));
+        assert_eq!(key("ESC"), Some(KeyCode::VK_ESCAPE));
+        assert_eq!(key("vk0D"), Some(KeyCode::VK_RETURN));
+        assert_eq!(key("VK07"), Some(KeyCode::Unknown(0x07)));
+        assert_eq!(key("vkzz"), None);
+        assert_eq!(key("NotAKey"), None);
+
+        assert_eq!(KeyCode::VK_RETURN.to_ahk_name(), "Enter");
+        assert_eq!(KeyCode::Unknown(0x07).to_ahk_name(), "vk07");
+        for (key, name) in AHK_KEY_NAMES {
+            assert_eq!(KeyCode::from_ahk_name(name), Some(*key), "{}", name);
+            let round_trip = crate::layout::with_layout(Layout::Us, || key.to_ahk_name());
+            assert_eq!(round_trip, *name);
+        }
+    }
+
+    fn ron_round_trip<T>(value: T, ron: &str)
+    where
+        T: Serialize + for<'de> Deserialize<'de> + PartialEq + std::fmt::Debug,
+    {
+        assert_eq!(ron::to_string(&value).unwrap(), ron);
+        assert_eq!(ron::from_str::<T>(ron).unwrap(), value);
+    }
+
+    #[test]
+    fn key_names_round_trip() {
+        ron_round_trip(KeyCode::VK_LCONTROL, "\"LCtrl\"");
+        ron_round_trip(KeyCode::VK_OEM_2, "\"/\"");
+        ron_round_trip(KeyCode::VK_NUMPAD0, "\"Numpad0\"");
+        // keys without a name are written as AHK's vkXX
+        ron_round_trip(KeyCode::Unknown(0x07), "\"vk07\"");
+        ron_round_trip(KeyCode::Unknown(0xFF), "\"vkFF\"");
+        ron_round_trip(
+            MouseFlags::MOUSEEVENTF_LEFTDOWN,
+            "[\"LButton down\"]",
+        );
+        ron_round_trip(KeyboardFlags::KEYEVENTF_KEYUP, "[\"KeyUp\"]");
+        // bits without a name are written in hex
+        ron_round_trip(
+            MouseFlags::MOUSEEVENTF_LEFTDOWN | MouseFlags::from_bits_retain(0x0200),
+            "[\"LButton down\",\"0x0200\"]",
+        );
+        ron_round_trip(KeyUpDown::Down, "\"down\"");
+    }
+
+    #[test]
+    fn names_ignore_case() {
+        let key = |ron: &str| ron::from_str::<KeyCode>(ron).unwrap();
+        assert_eq!(key("\"lctrl\""), KeyCode::VK_LCONTROL);
+        assert_eq!(key("\"ENTER\""), KeyCode::VK_RETURN);
+        assert_eq!(key("\"VK07\""), KeyCode::Unknown(0x07));
+        assert_eq!(key("\"vk_lcontrol\""), KeyCode::VK_LCONTROL);
+        assert!(ron::from_str::<KeyCode>("\"NotAKey\"").is_err());
+        let flags = ron::from_str::<MouseFlags>("[\"lbutton DOWN\", \"absolute\"]").unwrap();
+        assert_eq!(flags, MouseFlags::MOUSEEVENTF_LEFTDOWN | MouseFlags::MOUSEEVENTF_ABSOLUTE);
+        assert_eq!(ron::from_str::<KeyUpDown>("\"UP\"").unwrap(), KeyUpDown::Up);
+    }
+
+    #[test]
+    fn mouse_flags_serde() {
+        let down = MouseFlags::MOUSEEVENTF_LEFTDOWN | MouseFlags::MOUSEEVENTF_ABSOLUTE;
+        ron_round_trip(down, "[\"LButton down\",\"Absolute\"]");
+        assert_eq!(serde_json::to_string(&down).unwrap(), r#"["LButton down","Absolute"]"#);
+        let json = serde_json::from_str::<MouseFlags>(r#"["LButton down", "Absolute"]"#);
+        assert_eq!(json.unwrap(), down);
+        ron_round_trip(MouseFlags::empty(), "[]");
+
+        let left = MouseFlags::MOUSEEVENTF_LEFTDOWN;
+        // a single flag was a unit variant, an identifier in RON
+        let ron = with_file_version(1, || ron::from_str::<MouseFlags>("MOUSEEVENTF_LEFTDOWN"));
+        assert_eq!(ron.unwrap(), left);
+        // and a bare string in JSON
+        let json = r#""MOUSEEVENTF_LEFTDOWN""#;
+        let json = with_file_version(1, || serde_json::from_str::<MouseFlags>(json));
+        assert_eq!(json.unwrap(), left);
+        // a bare string of one name is still read in the current version
+        assert_eq!(ron::from_str::<MouseFlags>("\"LButton down\"").unwrap(), left);
+        assert_eq!(serde_json::from_str::<MouseFlags>(r#""Absolute""#).unwrap(), down - left);
+        assert!(ron::from_str::<MouseFlags>("\"LButton sideways\"").is_err());
+    }
+
+    #[test]
+    fn reads_legacy_names() {
+        // version 1 files have Win32 names as bare identifiers
+        let key = with_file_version(1, || ron::from_str::<KeyCode>("VK_LCONTROL"));
+        assert_eq!(key.unwrap(), KeyCode::VK_LCONTROL);
+        let flags = with_file_version(1, || ron::from_str::<MouseFlags>("MOUSEEVENTF_LEFTDOWN"));
+        assert_eq!(flags.unwrap(), MouseFlags::MOUSEEVENTF_LEFTDOWN);
+        let flags = with_file_version(2, || ron::from_str::<KeyboardFlags>("KEYEVENTF_KEYUP"));
+        assert_eq!(flags.unwrap(), KeyboardFlags::KEYEVENTF_KEYUP);
+        // and the current version can't have them
+        assert!(ron::from_str::<KeyCode>("VK_LCONTROL").is_err());
+        assert!(with_file_version(2, || ron::from_str::<KeyCode>("VK_LCONTROL")).is_err());
+        // Win32 names in strings and hex bits still work in any version
+        assert_eq!(
+            ron::from_str::<MouseFlags>("[\"MOUSEEVENTF_LEFTDOWN\", \"0x8000\"]").unwrap(),
+            MouseFlags::MOUSEEVENTF_LEFTDOWN | MouseFlags::MOUSEEVENTF_ABSOLUTE
+        );
+    }
+}
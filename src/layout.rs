@@ -0,0 +1,312 @@
+// Keyboard layouts for typing characters, so scripts convert the same way on
+// every machine instead of going by the layout Windows happens to be using.
+
+use std::cell::Cell;
+
+use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
+
+use crate::keycodes::KeyCode;
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub enum Layout {
+    #[default]
+    Us,
+    Uk,
+    German,
+    French,
+    /// Whatever layout Windows is using.
+    #[cfg(windows)]
+    System,
+}
+
+bitflags! {
+    /// The modifiers held to type a character, the same bits `VkKeyScanW`
+    /// returns in its high byte. AltGr is Ctrl+Alt.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+    pub struct Modifiers: u8 {
+        const SHIFT = 0x01;
+        const CTRL = 0x02;
+        const ALT = 0x04;
+    }
+}
+
+impl Modifiers {
+    /// The keys to hold down for these modifiers.
+    pub fn keys(self) -> Vec<KeyCode> {
+        let mut keys = vec![];
+        if self.contains(Modifiers::SHIFT) {
+            keys.push(KeyCode::VK_SHIFT);
+        }
+        if self.contains(Modifiers::CTRL) {
+            keys.push(KeyCode::VK_CONTROL);
+        }
+        if self.contains(Modifiers::ALT) {
+            keys.push(KeyCode::VK_MENU);
+        }
+        keys
+    }
+}
+
+// What each key types by itself, with Shift and with AltGr. A space is a
+// dead key or nothing, dead keys don't type anything by themselves.
+// Letters are the same everywhere so they aren't listed unless AltGr types
+// something with them.
+type LayoutTable = &'static [(KeyCode, &'static str)];
+
+const US: LayoutTable = &[
+    (KeyCode::VK_OEM_3, "`~"),
+    (KeyCode::VK_1, "1!"),
+    (KeyCode::VK_2, "2@"),
+    (KeyCode::VK_3, "3#"),
+    (KeyCode::VK_4, "4$"),
+    (KeyCode::VK_5, "5%"),
+    (KeyCode::VK_6, "6^"),
+    (KeyCode::VK_7, "7&"),
+    (KeyCode::VK_8, "8*"),
+    (KeyCode::VK_9, "9("),
+    (KeyCode::VK_0, "0)"),
+    (KeyCode::VK_OEM_MINUS, "-_"),
+    (KeyCode::VK_OEM_PLUS, "=+"),
+    (KeyCode::VK_OEM_4, "[{"),
+    (KeyCode::VK_OEM_6, "]}"),
+    (KeyCode::VK_OEM_5, "\\|"),
+    (KeyCode::VK_OEM_1, ";:"),
+    (KeyCode::VK_OEM_7, "'\""),
+    (KeyCode::VK_OEM_COMMA, ",<"),
+    (KeyCode::VK_OEM_PERIOD, ".>"),
+    (KeyCode::VK_OEM_2, "/?"),
+];
+
+const UK: LayoutTable = &[
+    (KeyCode::VK_OEM_8, "`¬¦"),
+    (KeyCode::VK_1, "1!"),
+    (KeyCode::VK_2, "2\""),
+    (KeyCode::VK_3, "3£"),
+    (KeyCode::VK_4, "4$€"),
+    (KeyCode::VK_5, "5%"),
+    (KeyCode::VK_6, "6^"),
+    (KeyCode::VK_7, "7&"),
+    (KeyCode::VK_8, "8*"),
+    (KeyCode::VK_9, "9("),
+    (KeyCode::VK_0, "0)"),
+    (KeyCode::VK_OEM_MINUS, "-_"),
+    (KeyCode::VK_OEM_PLUS, "=+"),
+    (KeyCode::VK_OEM_4, "[{"),
+    (KeyCode::VK_OEM_6, "]}"),
+    (KeyCode::VK_OEM_1, ";:"),
+    (KeyCode::VK_OEM_3, "'@"),
+    (KeyCode::VK_OEM_7, "#~"),
+    (KeyCode::VK_OEM_5, "\\|"),
+    (KeyCode::VK_OEM_COMMA, ",<"),
+    (KeyCode::VK_OEM_PERIOD, ".>"),
+    (KeyCode::VK_OEM_2, "/?"),
+];
+
+const GERMAN: LayoutTable = &[
+    (KeyCode::VK_OEM_5, " °"),
+    (KeyCode::VK_1, "1!"),
+    (KeyCode::VK_2, "2\"²"),
+    (KeyCode::VK_3, "3§³"),
+    (KeyCode::VK_4, "4$"),
+    (KeyCode::VK_5, "5%"),
+    (KeyCode::VK_6, "6&"),
+    (KeyCode::VK_7, "7/{"),
+    (KeyCode::VK_8, "8(["),
+    (KeyCode::VK_9, "9)]"),
+    (KeyCode::VK_0, "0=}"),
+    (KeyCode::VK_OEM_4, "ß?\\"),
+    (KeyCode::VK_Q, "qQ@"),
+    (KeyCode::VK_E, "eE€"),
+    (KeyCode::VK_OEM_1, "üÜ"),
+    (KeyCode::VK_OEM_PLUS, "+*~"),
+    (KeyCode::VK_OEM_3, "öÖ"),
+    (KeyCode::VK_OEM_7, "äÄ"),
+    (KeyCode::VK_OEM_2, "#'"),
+    (KeyCode::VK_OEM_102, "<>|"),
+    (KeyCode::VK_M, "mMµ"),
+    (KeyCode::VK_OEM_COMMA, ",;"),
+    (KeyCode::VK_OEM_PERIOD, ".:"),
+    (KeyCode::VK_OEM_MINUS, "-_"),
+];
+
+const FRENCH: LayoutTable = &[
+    (KeyCode::VK_OEM_7, "²"),
+    (KeyCode::VK_1, "&1"),
+    (KeyCode::VK_2, "é2"),
+    (KeyCode::VK_3, "\"3#"),
+    (KeyCode::VK_4, "'4{"),
+    (KeyCode::VK_5, "(5["),
+    (KeyCode::VK_6, "-6|"),
+    (KeyCode::VK_7, "è7"),
+    (KeyCode::VK_8, "_8\\"),
+    (KeyCode::VK_9, "ç9^"),
+    (KeyCode::VK_0, "à0@"),
+    (KeyCode::VK_OEM_4, ")°]"),
+    (KeyCode::VK_OEM_PLUS, "=+}"),
+    (KeyCode::VK_E, "eE€"),
+    (KeyCode::VK_OEM_1, "$£¤"),
+    (KeyCode::VK_OEM_3, "ù%"),
+    (KeyCode::VK_OEM_5, "*µ"),
+    (KeyCode::VK_OEM_102, "<>"),
+    (KeyCode::VK_OEM_COMMA, ",?"),
+    (KeyCode::VK_OEM_PERIOD, ";."),
+    (KeyCode::VK_OEM_2, ":/"),
+    (KeyCode::VK_OEM_8, "!§"),
+];
+
+// the modifiers for each column of a layout table
+const COLUMNS: [Modifiers; 3] = [
+    Modifiers::empty(),
+    Modifiers::SHIFT,
+    Modifiers::CTRL.union(Modifiers::ALT),
+];
+
+thread_local! {
+    static CURRENT: Cell<Layout> = const { Cell::new(Layout::Us) };
+}
+
+/// Runs `f` with `layout` as the one characters are typed with.
+pub fn with_layout<T>(layout: Layout, f: impl FnOnce() -> T) -> T {
+    let was = CURRENT.with(|x| x.replace(layout));
+    let result = f();
+    CURRENT.with(|x| x.set(was));
+    result
+}
+
+impl Layout {
+    pub fn from_name(name: &str) -> Option<Layout> {
+        match name.to_lowercase().as_str() {
+            "us" => Some(Layout::Us),
+            "uk" | "gb" => Some(Layout::Uk),
+            "de" | "german" => Some(Layout::German),
+            "fr" | "french" => Some(Layout::French),
+            #[cfg(windows)]
+            "system" => Some(Layout::System),
+            _ => None,
+        }
+    }
+
+    /// The layout set by `with_layout`, US if there isn't one.
+    pub fn current() -> Layout {
+        CURRENT.with(|x| x.get())
+    }
+
+    fn table(self) -> LayoutTable {
+        match self {
+            Layout::Us => US,
+            #[cfg(windows)]
+            Layout::System => US,
+            Layout::Uk => UK,
+            Layout::German => GERMAN,
+            Layout::French => FRENCH,
+        }
+    }
+
+    /// The key that types `c` and the modifiers to hold while pressing it.
+    pub fn key_for(self, c: char) -> Option<(KeyCode, Modifiers)> {
+        #[cfg(windows)]
+        if self == Layout::System {
+            return system_key_for(c);
+        }
+        for (key, chars) in self.table() {
+            if let Some(column) = chars.chars().position(|x| x == c && x != ' ') {
+                return Some((*key, COLUMNS[column]));
+            }
+        }
+        match c {
+            'a'..='z' => Some((
+                KeyCode::from_code(c.to_ascii_uppercase() as u32),
+                Modifiers::empty(),
+            )),
+            'A'..='Z' => Some((KeyCode::from_code(c as u32), Modifiers::SHIFT)),
+            ' ' => Some((KeyCode::VK_SPACE, Modifiers::empty())),
+            '\t' => Some((KeyCode::VK_TAB, Modifiers::empty())),
+            '\n' => Some((KeyCode::VK_RETURN, Modifiers::empty())),
+            _ => None,
+        }
+    }
+
+    /// What `key` types by itself, if it types anything.
+    pub fn char_for(self, key: KeyCode) -> Option<char> {
+        #[cfg(windows)]
+        if self == Layout::System {
+            return system_char_for(key);
+        }
+        if let Some((_, chars)) = self.table().iter().find(|(x, _)| *x == key) {
+            return chars.chars().next().filter(|x| *x != ' ');
+        }
+        match key.code() {
+            code @ 0x41..=0x5A => char::from_u32(code).map(|x| x.to_ascii_lowercase()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(windows)]
+fn system_key_for(c: char) -> Option<(KeyCode, Modifiers)> {
+    use windows::Win32::UI::Input::KeyboardAndMouse::VkKeyScanW;
+
+    let ret = unsafe { VkKeyScanW(c as u16) };
+    if ret == -1 {
+        return None;
+    }
+    let modifiers = Modifiers::from_bits_truncate((ret >> 8) as u8);
+    Some((KeyCode::from_code((ret & 0xFF) as u32), modifiers))
+}
+
+#[cfg(windows)]
+fn system_char_for(key: KeyCode) -> Option<char> {
+    use windows::Win32::UI::Input::KeyboardAndMouse::{MapVirtualKeyW, MAPVK_VK_TO_CHAR};
+
+    let ret = unsafe { MapVirtualKeyW(key.code(), MAPVK_VK_TO_CHAR) };
+    // the high bit is set for dead keys
+    if ret == 0 || ret & 0x8000_0000 != 0 {
+        return None;
+    }
+    char::from_u32(ret).map(|x| x.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALT_GR: Modifiers = Modifiers::CTRL.union(Modifiers::ALT);
+
+    #[test]
+    fn alt_gr_characters() {
+        assert_eq!(Layout::German.key_for('@'), Some((KeyCode::VK_Q, ALT_GR)));
+        assert_eq!(Layout::German.key_for('{'), Some((KeyCode::VK_7, ALT_GR)));
+        assert_eq!(Layout::German.key_for('q'), Some((KeyCode::VK_Q, Modifiers::empty())));
+        assert_eq!(Layout::German.key_for('Q'), Some((KeyCode::VK_Q, Modifiers::SHIFT)));
+        assert_eq!(Layout::Uk.key_for('€'), Some((KeyCode::VK_4, ALT_GR)));
+        assert_eq!(Layout::Uk.key_for('$'), Some((KeyCode::VK_4, Modifiers::SHIFT)));
+        assert_eq!(Layout::French.key_for('@'), Some((KeyCode::VK_0, ALT_GR)));
+        assert_eq!(Layout::Us.key_for('€'), None);
+        // AltGr doesn't change what the key types by itself
+        assert_eq!(Layout::German.char_for(KeyCode::VK_Q), Some('q'));
+        assert_eq!(Layout::Uk.char_for(KeyCode::VK_4), Some('4'));
+    }
+
+    #[test]
+    fn dead_keys_are_gaps() {
+        // the German ^ key is dead by itself but types ° with Shift
+        assert_eq!(Layout::German.char_for(KeyCode::VK_OEM_5), None);
+        assert_eq!(Layout::German.key_for('°'), Some((KeyCode::VK_OEM_5, Modifiers::SHIFT)));
+        assert_eq!(Layout::German.key_for('^'), None);
+        // the gap isn't a space
+        assert_eq!(Layout::German.key_for(' '), Some((KeyCode::VK_SPACE, Modifiers::empty())));
+        // keys with fewer columns don't type anything past them
+        assert_eq!(Layout::French.key_for('²'), Some((KeyCode::VK_OEM_7, Modifiers::empty())));
+        assert_eq!(Layout::French.key_for('~'), None);
+    }
+
+    #[test]
+    fn layouts_by_name() {
+        assert_eq!(Layout::from_name("DE"), Some(Layout::German));
+        assert_eq!(Layout::from_name("gb"), Some(Layout::Uk));
+        assert_eq!(Layout::from_name("dvorak"), None);
+        #[cfg(not(windows))]
+        assert_eq!(Layout::from_name("system"), None);
+    }
+}
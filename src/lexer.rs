@@ -0,0 +1,217 @@
+// Turns the raw text of an .ahk file into logical lines: comments are removed
+// and continuation sections/lines are joined onto the line they continue.
+// Escape sequences are left alone for the parser to deal with.
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Line {
+    /// 1-based line number in the source file where the logical line starts.
+    pub number: usize,
+    pub text: String,
+}
+
+/// Splits `source` into logical lines. `v2` selects v2 comment rules, where a
+/// `;` inside a quoted string doesn't start a comment.
+pub fn logical_lines(source: &str, v2: bool) -> Vec<Line> {
+    let mut lines: Vec<Line> = vec![];
+    let mut raw_lines = source.lines().enumerate();
+    let mut in_block_comment = false;
+
+    while let Some((index, raw)) = raw_lines.next() {
+        let trimmed = raw.trim();
+
+        if in_block_comment {
+            if let Some(rest) = trimmed.strip_prefix("*/") {
+                in_block_comment = false;
+                push_line(&mut lines, index + 1, rest, v2);
+            } else if trimmed.ends_with("*/") {
+                in_block_comment = false;
+            }
+            continue;
+        }
+        if trimmed.starts_with("/*") {
+            in_block_comment = !(trimmed.len() > 2 && trimmed.ends_with("*/"));
+            continue;
+        }
+
+        if trimmed.starts_with('(') && !trimmed.contains(')') && !lines.is_empty() {
+            let options = ContinuationOptions::parse(&trimmed[1..], v2);
+            let mut content = vec![];
+            let mut rest = "";
+            for (_, raw) in raw_lines.by_ref() {
+                if let Some(after) = raw.trim_start().strip_prefix(')') {
+                    rest = after;
+                    break;
+                }
+                content.push(options.apply(raw));
+            }
+            let last = lines.last_mut().unwrap();
+            last.text.push_str(&content.join(&options.join));
+            last.text.push_str(strip_comment(rest, v2).trim_end());
+            continue;
+        }
+
+        let code = strip_comment(raw, v2).trim();
+        if let Some(last) = lines.last_mut() {
+            if continues_previous(code) {
+                if !code.starts_with(',') {
+                    last.text.push(' ');
+                }
+                last.text.push_str(code);
+                continue;
+            }
+        }
+        push_line(&mut lines, index + 1, code, v2);
+    }
+    lines
+}
+
+fn push_line(lines: &mut Vec<Line>, number: usize, text: &str, v2: bool) {
+    let text = strip_comment(text, v2).trim();
+    if text.is_empty() {
+        return;
+    }
+    lines.push(Line {
+        number,
+        text: text.to_string(),
+    });
+}
+
+/// Removes a `;` comment, which is either the whole line or starts after a
+/// space or tab. Escaped `` `; `` is kept, and in v2 so are semicolons inside
+/// quoted strings. v1 has no such exception: `MsgBox "a ;b"` is `MsgBox "a`.
+fn strip_comment(line: &str, v2: bool) -> &str {
+    let mut quote = None;
+    let mut prev = None;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' | '\'' if v2 && prev != Some('`') => match quote {
+                None => quote = Some(c),
+                Some(q) if q == c => quote = None,
+                Some(_) => {}
+            },
+            ';' if quote.is_none() && matches!(prev, None | Some(' ') | Some('\t')) => {
+                return &line[..i];
+            }
+            _ => {}
+        }
+        prev = Some(c);
+    }
+    line
+}
+
+/// Lines starting with an operator or comma are joined onto the previous one.
+fn continues_previous(code: &str) -> bool {
+    let lower = code.to_lowercase();
+    code.starts_with(',')
+        || code.starts_with("&&")
+        || code.starts_with("||")
+        || (code.starts_with('.') && !code.starts_with(".."))
+        || lower.starts_with("and ")
+        || lower.starts_with("or ")
+}
+
+/// Options given after the `(` that opens a continuation section.
+struct ContinuationOptions {
+    join: String,
+    ltrim: bool,
+    rtrim: bool,
+    comments: bool,
+    v2: bool,
+}
+
+impl ContinuationOptions {
+    fn parse(options: &str, v2: bool) -> Self {
+        let mut parsed = ContinuationOptions {
+            join: "\n".to_string(),
+            ltrim: false,
+            rtrim: true,
+            comments: false,
+            v2,
+        };
+        for option in options.split_whitespace() {
+            let lower = option.to_lowercase();
+            if lower.starts_with("join") {
+                parsed.join = option[4..]
+                    .replace("`s", " ")
+                    .replace("`t", "\t")
+                    .replace("`n", "\n");
+            } else if lower == "ltrim" {
+                parsed.ltrim = true;
+            } else if lower == "ltrim0" {
+                parsed.ltrim = false;
+            } else if lower == "rtrim0" {
+                parsed.rtrim = false;
+            } else if matches!(lower.as_str(), "comments" | "comment" | "com" | "c") {
+                parsed.comments = true;
+            }
+        }
+        parsed
+    }
+
+    fn apply<'a>(&self, line: &'a str) -> &'a str {
+        let mut line = if self.comments {
+            strip_comment(line, self.v2)
+        } else {
+            line
+        };
+        if self.ltrim {
+            line = line.trim_start();
+        }
+        if self.rtrim {
+            line = line.trim_end();
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(source: &str, v2: bool) -> Vec<String> {
+        logical_lines(source, v2).into_iter().map(|x| x.text).collect()
+    }
+
+    #[test]
+    fn strips_comments() {
+        let source = "Send a ; comment\n; whole line\nSend b;c\nSend `; d";
+        assert_eq!(texts(source, false), ["Send a", "Send b;c", "Send `; d"]);
+        // v1 doesn't know about quotes here
+        assert_eq!(texts("MsgBox \"a ;b\"", false), ["MsgBox \"a"]);
+        assert_eq!(texts("MsgBox \"a ;b\" ; c", true), ["MsgBox \"a ;b\""]);
+        assert_eq!(texts("MsgBox 'it ;s' ; c", true), ["MsgBox 'it ;s'"]);
+        assert_eq!(texts("MsgBox \"`\" ;b\"", true), ["MsgBox \"`\" ;b\""]);
+    }
+
+    #[test]
+    fn skips_block_comments() {
+        let source = "Send a\n/*\nSend b\n*/\nSend c\n/* one line */\n  /*\nx\n*/ Send d";
+        let lines = logical_lines(source, false);
+        let numbers: Vec<_> = lines.iter().map(|x| (x.number, x.text.as_str())).collect();
+        assert_eq!(numbers, [(1, "Send a"), (5, "Send c"), (9, "Send d")]);
+    }
+
+    #[test]
+    fn joins_continuation_sections() {
+        let source = "x =\n(\n  one\n  two ; kept\n)\nSend a";
+        assert_eq!(texts(source, false), ["x =  one\n  two ; kept", "Send a"]);
+
+        let source = "x =\n( LTrim Join`s Comments\n  one ; dropped\n  two\n) ; end\nSend a";
+        assert_eq!(texts(source, false), ["x =one two", "Send a"]);
+
+        let source = "x := \"\n( Join, C\na ;b\nc\n)\"";
+        assert_eq!(texts(source, false), ["x := \"a,c\""]);
+    }
+
+    #[test]
+    fn joins_continuation_lines() {
+        let source = "Send a\n, b\nif x\n&& y\n|| z\nx := a\n. b\nif p\nAND q\nor r\n.. no";
+        assert_eq!(
+            texts(source, false),
+            ["Send a, b", "if x && y || z", "x := a . b", "if p AND q or r", ".. no"]
+        );
+        assert_eq!(logical_lines("a\n, b\nc", false)[1].number, 3);
+        // a word that only starts with the operator isn't one
+        assert_eq!(texts("x\norder", false), ["x", "order"]);
+    }
+}
@@ -0,0 +1,19 @@
+#![deny(clippy::correctness, clippy::suspicious, clippy::complexity)]
+pub mod ahk;
+pub mod ahk_export;
+pub mod ahk_v2;
+pub mod condition;
+mod event_stream;
+pub mod instance;
+pub mod keycodes;
+pub mod layout;
+pub mod lexer;
+pub mod r#macro;
+pub mod macro_events;
+pub mod macro_file;
+pub mod recorder;
+pub mod screen;
+pub mod timer;
+pub mod window;
+
+pub use crate::keycodes::KeyCode;
@@ -1,12 +1,22 @@
-use std::collections::HashSet;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 // use std::sync::Arc;
 // use tokio::sync::Mutex;
 // use tokio::sync::Semaphore;
 // use tokio::sync::mpsc;
 
-use crate::{macro_events::MacroEvent, KeyCode};
+use crate::{
+    condition::Condition,
+    macro_events::{CoordSpace, MacroEvent, ToggleMode},
+    screen::{self, ScreenGeometry},
+    timer::{MacroTimer, Timers},
+    window::{self, WindowProvider},
+    KeyCode,
+};
 use serde::{Deserialize, Serialize};
-use windows::Win32::UI::Input::KeyboardAndMouse::GetAsyncKeyState;
 
 // #[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
 // pub struct MacroSer {
@@ -18,13 +28,236 @@ use windows::Win32::UI::Input::KeyboardAndMouse::GetAsyncKeyState;
 pub struct Macro {
     pub name: String,
     pub blocks: Vec<MacroBlock>,
+    #[serde(default)]
+    pub settings: MacroSettings,
+    #[serde(default)]
+    pub timers: Vec<MacroTimer>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
 pub struct MacroBlock {
     pub hotkey: Option<Vec<KeyCode>>,
     pub events: Vec<MacroEvent>,
+    #[serde(default)]
     pub running: bool,
+    // how many times the hotkey can be running at once (#MaxThreadsPerHotkey)
+    #[serde(default = "default_max_threads")]
+    pub max_threads: u32,
+    // still runs while hotkeys are suspended (a hotkey starting with `Suspend`)
+    #[serde(default)]
+    pub suspend_exempt: bool,
+    // the hotkey only fires while this holds (#If, #IfWinActive)
+    #[serde(default)]
+    pub active_when: Option<Condition>,
+}
+
+impl MacroBlock {
+    /// Whether the block's `#If` condition holds, if it has one.
+    pub fn is_active(&self, state: &MacroState) -> bool {
+        self.active_when.as_ref().is_none_or(|x| x.evaluate(state))
+    }
+}
+
+fn default_max_threads() -> u32 {
+    1
+}
+
+/// Script-wide settings from directives and commands like `SetKeyDelay`.
+/// Delays are in microseconds. Unlike in AHK, where SetKeyDelay and
+/// SetMouseDelay take effect when they run, these apply to the whole macro
+/// and the last one in the script wins. Only CoordMode and
+/// #MaxThreadsPerHotkey apply to the lines after them.
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(default)]
+pub struct MacroSettings {
+    /// Delay after every key event.
+    pub key_delay_us: u64,
+    /// How long a tapped key is held before it is released.
+    pub key_press_duration_us: u64,
+    /// Delay after every mouse event.
+    pub mouse_delay_us: u64,
+    /// What absolute mouse coordinates in a script are relative to, as set in
+    /// the auto-execute section. Parsing gives them the matching
+    /// `CoordSpace`, so this is only read on export.
+    pub coord_mode: CoordMode,
+    pub single_instance: SingleInstance,
+    /// Thread limit given to hotkeys defined after `#MaxThreadsPerHotkey`.
+    pub max_threads_per_hotkey: u32,
+    /// Send keys by scan code, for games that ignore virtual keys.
+    pub scan_codes: bool,
+}
+
+impl Default for MacroSettings {
+    fn default() -> Self {
+        MacroSettings {
+            key_delay_us: 0,
+            key_press_duration_us: 10,
+            mouse_delay_us: 0,
+            coord_mode: CoordMode::Screen,
+            single_instance: SingleInstance::Prompt,
+            max_threads_per_hotkey: default_max_threads(),
+            scan_codes: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub enum CoordMode {
+    #[default]
+    Screen,
+    Window,
+    Client,
+}
+
+impl CoordMode {
+    /// The space a script's absolute mouse coordinates are in.
+    pub fn space(self) -> CoordSpace {
+        match self {
+            CoordMode::Screen => CoordSpace::VirtualDesk,
+            CoordMode::Window => CoordSpace::Window,
+            CoordMode::Client => CoordSpace::Client,
+        }
+    }
+}
+
+/// What `#SingleInstance` does when the macro is started while it's already
+/// running, see `instance::claim`.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub enum SingleInstance {
+    Force,
+    Ignore,
+    Prompt,
+    Off,
+}
+
+thread_local! {
+    // the macro thread running on this OS thread, see `MacroState::run_thread`
+    static THREAD: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Runtime state shared by the events of a running macro.
+#[derive(Debug)]
+pub struct MacroState {
+    pub settings: MacroSettings,
+    // AHK variable names are case-insensitive, so these are stored lowercase
+    pub variables: Mutex<HashMap<String, String>>,
+    pub timers: Timers,
+    pub suspended: AtomicBool,
+    // threads numbered below this are paused, see `pause`
+    paused_below: AtomicU64,
+    next_thread: AtomicU64,
+    pub reloading: AtomicBool,
+    pub windows: Box<dyn WindowProvider>,
+    pub screen: Box<dyn ScreenGeometry>,
+}
+
+/// Why [`Macro::run`] returned.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MacroExit {
+    Finished,
+    /// A Reload event asked for the macro to be read again.
+    Reload,
+}
+
+impl MacroState {
+    pub fn new(settings: MacroSettings, timers: &[MacroTimer]) -> Self {
+        MacroState::with_providers(
+            settings,
+            timers,
+            window::platform_provider(),
+            screen::platform_provider(),
+        )
+    }
+
+    /// A state that asks `windows` and `screen` about the desktop instead
+    /// of the platform, to fake a window or monitor setup.
+    pub fn with_providers(
+        settings: MacroSettings,
+        timers: &[MacroTimer],
+        windows: Box<dyn WindowProvider>,
+        screen: Box<dyn ScreenGeometry>,
+    ) -> Self {
+        MacroState {
+            settings,
+            variables: Mutex::new(HashMap::new()),
+            timers: Timers::new(timers),
+            suspended: AtomicBool::new(false),
+            paused_below: AtomicU64::new(0),
+            next_thread: AtomicU64::new(1),
+            reloading: AtomicBool::new(false),
+            windows,
+            screen,
+        }
+    }
+
+    /// Runs `f` as a new thread of the macro, numbered after every thread
+    /// already running. Events run outside of one act as thread 0.
+    pub fn run_thread<T>(&self, f: impl FnOnce() -> T) -> T {
+        let id = self.next_thread.fetch_add(1, Ordering::Relaxed);
+        let was = THREAD.with(|x| x.replace(id));
+        let result = f();
+        THREAD.with(|x| x.set(was));
+        result
+    }
+
+    /// Pauses or unpauses the current thread. In AHK a thread runs on top of
+    /// the ones it interrupted, so pausing it holds those up too, while
+    /// hotkeys pressed afterwards start threads that still run. Here threads
+    /// run side by side, so the same is done by pausing every thread started
+    /// before this one. Unpausing lets all of them continue.
+    pub fn pause(&self, mode: ToggleMode) {
+        let paused = self.paused_below.load(Ordering::Relaxed) != 0;
+        let below = if mode.is_on(paused) {
+            THREAD.with(Cell::get) + 1
+        } else {
+            0
+        };
+        self.paused_below.store(below, Ordering::Relaxed);
+    }
+
+    /// Whether any thread is paused, which keeps timers from running.
+    pub fn is_paused(&self) -> bool {
+        self.paused_below.load(Ordering::Relaxed) != 0
+    }
+
+    fn is_thread_paused(&self) -> bool {
+        THREAD.with(Cell::get) < self.paused_below.load(Ordering::Relaxed)
+    }
+
+    /// Blocks while the current thread is paused, returning how long that
+    /// was.
+    pub fn wait_while_paused(&self) -> Duration {
+        let start = Instant::now();
+        while self.is_thread_paused() && !self.is_reloading() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        start.elapsed()
+    }
+
+    pub fn is_reloading(&self) -> bool {
+        self.reloading.load(Ordering::Relaxed)
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<String> {
+        self.variables
+            .lock()
+            .unwrap()
+            .get(&name.to_lowercase())
+            .cloned()
+    }
+
+    /// Whether a variable holds something other than an empty string or 0.
+    pub fn var_is_true(&self, name: &str) -> bool {
+        let value = self.get_var(name).unwrap_or_default();
+        !(value.is_empty() || value == "0")
+    }
+
+    pub fn set_var(&self, name: &str, value: String) {
+        self.variables
+            .lock()
+            .unwrap()
+            .insert(name.to_lowercase(), value);
+    }
 }
 
 impl Macro {
@@ -36,53 +269,193 @@ impl Macro {
     //     }
     // }
 
-    pub fn run(&self) {
+    pub fn run(&self) -> MacroExit {
+        let state = MacroState::new(self.settings.clone(), &self.timers);
+        // how many threads each block is running
+        let running = self
+            .blocks
+            .iter()
+            .map(|_| AtomicU32::new(0))
+            .collect::<Vec<AtomicU32>>();
         // let (tx, mut rx) = mpsc::channel(32);
         // let semaphore = Arc::new(Semaphore::new(1));
 
-        for block in &self.blocks {
-            // let tx = tx.clone();
-            // let block = block.lock().await.clone();
-            // let semaphore = semaphore.clone();
-
-            // tokio::spawn(async move {
-            //     block.run().await;
-            //     tx.send(()).await.expect("Channel send failed");
-            // });
-            if block.hotkey.is_some() {
-                block.wait_for_keypress();
+        std::thread::scope(|s| {
+            // timers run on their own threads so they don't hold up the hotkeys
+            for timer in &self.timers {
+                let state = &state;
+                s.spawn(move || state.timers.run(timer, state));
             }
-            for event in &block.events {
-                event.run();
+
+            // blocks without a hotkey are the auto-execute section
+            for block in self.blocks.iter().filter(|x| x.hotkey.is_none()) {
+                state.run_thread(|| {
+                    for event in &block.events {
+                        event.run(&state);
+                    }
+                });
             }
-        }
+
+            if self.blocks.iter().any(|x| x.hotkey.is_some()) {
+                self.run_hotkeys(s, &state, &running);
+            }
+            // running timers keep the macro alive, like a persistent AHK script
+            state.timers.finish();
+        });
 
         // for _ in &self.blocks {
         //     rx.recv().await.expect("Channel receive failed");
         // }
+        if state.is_reloading() {
+            MacroExit::Reload
+        } else {
+            MacroExit::Finished
+        }
     }
-}
 
-impl MacroBlock {
-    fn wait_for_keypress(&self) {
-        let mut keys_pressed: HashSet<KeyCode> = HashSet::new();
-        loop {
-            for key in self.hotkey.as_ref().unwrap() {
-                let ret = unsafe { GetAsyncKeyState(*key as i32) };
-                if ret & 0x8000u16 as i16 != 0 {
-                    if keys_pressed.contains(key) {
-                        continue;
-                    }
-                    println!("Key: {:?}", key);
-                    keys_pressed.insert(*key);
-                } else if keys_pressed.contains(key) {
-                    println!("Key Up: {:?}", key);
-                    keys_pressed.remove(key);
+    /// Runs each hotkey's block on its own thread whenever it is pressed,
+    /// until the macro is reloaded.
+    fn run_hotkeys<'scope>(
+        &'scope self,
+        s: &'scope std::thread::Scope<'scope, '_>,
+        state: &'scope MacroState,
+        running: &'scope [AtomicU32],
+    ) {
+        let mut was_down = vec![false; self.blocks.len()];
+
+        while !state.is_reloading() {
+            for (i, block) in self.blocks.iter().enumerate() {
+                let Some(hotkey) = &block.hotkey else {
+                    continue;
+                };
+                let is_down = hotkey.iter().all(|x| x.is_down());
+                let pressed = is_down && !was_down[i];
+                was_down[i] = is_down;
+                if !pressed {
+                    continue;
                 }
+                if state.suspended.load(Ordering::Relaxed) && !block.suspend_exempt {
+                    continue;
+                }
+                if !block.is_active(state) {
+                    continue;
+                }
+                if running[i].load(Ordering::Relaxed) >= block.max_threads {
+                    continue;
+                }
+                running[i].fetch_add(1, Ordering::Relaxed);
+                s.spawn(move || {
+                    state.run_thread(|| {
+                        for event in &block.events {
+                            event.run(state);
+                        }
+                    });
+                    running[i].fetch_sub(1, Ordering::Relaxed);
+                });
             }
-            if keys_pressed.len() == self.hotkey.as_ref().unwrap().len() {
-                break;
-            }
+            std::thread::sleep(Duration::from_millis(1));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen::FixedScreen;
+    use crate::window::{ActiveWindow, FixedWindow};
+
+    fn state(active: Option<(&str, &str)>) -> MacroState {
+        let window = active.map(|(title, class)| ActiveWindow {
+            title: title.to_string(),
+            class: class.to_string(),
+            ..Default::default()
+        });
+        MacroState::with_providers(
+            MacroSettings::default(),
+            &[],
+            Box::new(FixedWindow(window)),
+            Box::new(FixedScreen::single(1920, 1080)),
+        )
+    }
+
+    fn block(active_when: Option<Condition>) -> MacroBlock {
+        MacroBlock {
+            hotkey: Some(vec![KeyCode::VK_F1]),
+            events: vec![],
+            running: false,
+            max_threads: 1,
+            suspend_exempt: false,
+            active_when,
+        }
+    }
+
+    #[test]
+    fn win_active_gates_hotkeys() {
+        let notepad = state(Some(("Untitled - Notepad", "Notepad")));
+        let browser = state(Some(("Example - Browser", "Chrome_WidgetWin_1")));
+        let no_window = state(None);
+
+        let in_notepad = block(Some(Condition::WinActive("ahk_class Notepad".to_string())));
+        assert!(in_notepad.is_active(&notepad));
+        assert!(!in_notepad.is_active(&browser));
+        assert!(!in_notepad.is_active(&no_window));
+
+        let not_in_notepad = block(Some(Condition::Not(Box::new(Condition::WinActive(
+            "Untitled".to_string(),
+        )))));
+        assert!(!not_in_notepad.is_active(&notepad));
+        assert!(not_in_notepad.is_active(&browser));
+        assert!(not_in_notepad.is_active(&no_window));
+
+        assert!(block(None).is_active(&no_window));
+    }
+
+    #[test]
+    fn variables_gate_hotkeys() {
+        let state = state(None);
+        let toggled = block(Some(Condition::Variable("Toggle".to_string())));
+        assert!(!toggled.is_active(&state));
+        state.set_var("toggle", "1".to_string());
+        assert!(toggled.is_active(&state));
+        state.set_var("TOGGLE", "0".to_string());
+        assert!(!toggled.is_active(&state));
+    }
+
+    #[test]
+    fn pause_holds_up_older_threads() {
+        let state = state(None);
+        state.run_thread(|| {
+            assert!(!state.is_thread_paused());
+            // a hotkey that pauses itself, and the thread it interrupted
+            state.run_thread(|| {
+                state.pause(ToggleMode::Toggle);
+                assert!(state.is_thread_paused());
+            });
+            assert!(state.is_paused());
+            assert!(state.is_thread_paused());
+            // a hotkey pressed after the pause still runs, and unpauses
+            state.run_thread(|| {
+                assert!(!state.is_thread_paused());
+                state.pause(ToggleMode::Toggle);
+            });
+            assert!(!state.is_paused());
+            assert!(!state.is_thread_paused());
+
+            state.pause(ToggleMode::On);
+            state.run_thread(|| state.pause(ToggleMode::Off));
+            assert!(!state.is_thread_paused());
+        });
+    }
+
+    #[test]
+    fn reload_interrupts_sleeps() {
+        let state = state(None);
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            s.spawn(|| state.run_thread(|| MacroEvent::SleepMs(60_000).run(&state)));
+            std::thread::sleep(Duration::from_millis(20));
+            MacroEvent::Reload.run(&state);
+        });
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -1,8 +1,14 @@
 use crate::{
+    condition::Condition,
     keycodes::{KeyboardFlags, MouseData, MouseFlags, KeyUpDown},
+    r#macro::{MacroSettings, MacroState},
+    screen::ScreenGeometry,
+    timer::SetTimerEvent,
+    window::WindowProvider,
     KeyCode,
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
 use windows::Win32::UI::Input::KeyboardAndMouse::{
     SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, INPUT_MOUSE, KEYBDINPUT,
     KEYBD_EVENT_FLAGS, MOUSEINPUT, MOUSE_EVENT_FLAGS, VIRTUAL_KEY,
@@ -14,62 +20,135 @@ pub enum MacroEvent {
     Keybd(KeyboardEvent),
     MouseMove(MouseMoveEvent),
     MouseBtn(MouseButtonEvent),
-    Run(String),
+    MouseWheel(MouseWheelEvent),
+    Run(RunEvent),
     Loop(LoopEvent),
+    If(IfEvent),
+    WaitForKey(WaitForKeyEvent),
+    SetTimer(SetTimerEvent),
+    SetVariable(SetVariableEvent),
+    Suspend(ToggleMode),
+    Pause(ToggleMode),
+    Reload,
     ExitApp,
     PreciseSleep(u64),
     LossySleep(u64),
 }
 
 impl MacroEvent {
-    pub fn run(&self) {
+    pub fn run(&self, state: &MacroState) {
+        // a reload abandons whatever was still running
+        if state.is_reloading() {
+            return;
+        }
+        // the events that control pausing still work while paused
+        if !matches!(
+            self,
+            MacroEvent::Suspend(_) | MacroEvent::Pause(_) | MacroEvent::Reload | MacroEvent::ExitApp
+        ) {
+            state.wait_while_paused();
+        }
+        let settings = &state.settings;
         let (elapsed_time, event_type) = match self {
             MacroEvent::LossySleep(ms) => {
                 let start = std::time::Instant::now();
                 std::thread::sleep(std::time::Duration::from_millis(*ms));
+                state.wait_while_paused();
                 (start.elapsed().as_micros(), "LossySleep")
             }
             MacroEvent::SleepMs(ms) |
             MacroEvent::PreciseSleep(ms) => {
                 let start = std::time::Instant::now();
+                // time spent paused doesn't count towards the sleep
+                let mut paused = std::time::Duration::ZERO;
                 let mut elapsed = 0;
-                while elapsed < *ms {
-                    elapsed = start.elapsed().as_millis() as u64;
+                while elapsed < *ms && !state.is_reloading() {
+                    paused += state.wait_while_paused();
+                    elapsed = (start.elapsed() - paused).as_millis() as u64;
                 }
                 (start.elapsed().as_micros(), "Sleep")
             }
             MacroEvent::Keybd(keybd_event) => {
                 let start = std::time::Instant::now();
-                keybd_event.run();
+                keybd_event.run(settings);
                 (start.elapsed().as_micros(), "Keybd")
             }
             MacroEvent::MouseMove(mouse_move_event) => {
                 let start = std::time::Instant::now();
-                mouse_move_event.run();
+                mouse_move_event.run(state.screen.as_ref(), state.windows.as_ref());
+                sleep_us(settings.mouse_delay_us);
                 (start.elapsed().as_micros(), "MouseMove")
             }
             MacroEvent::MouseBtn(mouse_btn_event) => {
                 let start = std::time::Instant::now();
                 mouse_btn_event.run();
+                sleep_us(settings.mouse_delay_us);
                 (start.elapsed().as_micros(), "MouseBtn")
             }
-            MacroEvent::Run(cmd) => {
+            MacroEvent::MouseWheel(mouse_wheel_event) => {
                 let start = std::time::Instant::now();
-                let _ = std::process::Command::new("cmd")
-                    .args([cmd])
-                    .output()
-                    .expect("Failed to execute command.");
+                mouse_wheel_event.run();
+                sleep_us(settings.mouse_delay_us);
+                (start.elapsed().as_micros(), "MouseWheel")
+            }
+            MacroEvent::Run(run_event) => {
+                let start = std::time::Instant::now();
+                if let Err(e) = run_event.run(state) {
+                    eprintln!("Failed to run {:?}: {}", run_event.program, e);
+                }
                 (start.elapsed().as_micros(), "Run")
-                // println!("Output: {}", String::from_utf8_lossy(&output.stdout));
             }
             MacroEvent::Loop(event) => {
                 for _ in 0..event.count {
                     for event in &event.events {
-                        event.run();
+                        event.run(state);
                     }
                 }
                 (0, "Loop")
             }
+            MacroEvent::If(event) => {
+                let events = if event.condition.evaluate(state) {
+                    &event.events
+                } else {
+                    &event.else_events
+                };
+                for event in events {
+                    event.run(state);
+                }
+                (0, "If")
+            }
+            MacroEvent::WaitForKey(wait_for_key_event) => {
+                let start = std::time::Instant::now();
+                // like KeyWait, ErrorLevel is 1 if the wait timed out
+                let timed_out = !wait_for_key_event.run(state);
+                state.set_var("ErrorLevel", (timed_out as u8).to_string());
+                (start.elapsed().as_micros(), "WaitForKey")
+            }
+            MacroEvent::SetTimer(set_timer_event) => {
+                state.timers.set(set_timer_event);
+                (0, "SetTimer")
+            }
+            MacroEvent::SetVariable(set_variable_event) => {
+                let value = match &set_variable_event.value {
+                    VariableValue::Text(text) => text.clone(),
+                    VariableValue::Not(name) => (!state.var_is_true(name) as u8).to_string(),
+                };
+                state.set_var(&set_variable_event.name, value);
+                (0, "SetVariable")
+            }
+            MacroEvent::Suspend(mode) => {
+                mode.apply(&state.suspended);
+                (0, "Suspend")
+            }
+            MacroEvent::Pause(mode) => {
+                state.pause(*mode);
+                (0, "Pause")
+            }
+            MacroEvent::Reload => {
+                state.reloading.store(true, Ordering::Relaxed);
+                state.timers.stop_all();
+                (0, "Reload")
+            }
             MacroEvent::ExitApp => {
                 std::process::exit(0);
             }
@@ -78,47 +157,411 @@ impl MacroEvent {
     }
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct SetVariableEvent {
+    pub name: String,
+    pub value: VariableValue,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub enum VariableValue {
+    Text(String),
+    /// `!Var`, 1 if the variable is empty or 0 and 0 otherwise.
+    Not(String),
+}
+
+/// The `On`/`Off`/`Toggle` argument of Suspend and Pause.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub enum ToggleMode {
+    On,
+    Off,
+    Toggle,
+}
+
+impl ToggleMode {
+    fn apply(&self, flag: &AtomicBool) {
+        match self {
+            ToggleMode::On => flag.store(true, Ordering::Relaxed),
+            ToggleMode::Off => flag.store(false, Ordering::Relaxed),
+            ToggleMode::Toggle => {
+                flag.fetch_xor(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether something that was `on` is on after this.
+    pub fn is_on(self, on: bool) -> bool {
+        match self {
+            ToggleMode::On => true,
+            ToggleMode::Off => false,
+            ToggleMode::Toggle => !on,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
 pub struct LoopEvent {
     pub count: u32,
     pub events: Vec<MacroEvent>,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(from = "RunEventRepr")]
+pub struct RunEvent {
+    pub program: String,
+    pub args: Vec<String>,
+    pub working_dir: Option<String>,
+    // don't show a window for the process (windows only)
+    pub hide: bool,
+    // wait for the process to exit (RunWait) instead of starting it and moving on
+    pub wait: bool,
+    // variable the process ID is stored in once it starts, AHK's OutputVarPID
+    pub pid_var: Option<String>,
+    // variables the exit code and stdout are stored in when waiting. Scripts
+    // can't ask for stdout, so only macro files set output_var
+    pub exit_code_var: Option<String>,
+    pub output_var: Option<String>,
+}
+
+// older macros stored Run as a single command string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RunEventRepr {
+    Command(String),
+    Event {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        working_dir: Option<String>,
+        #[serde(default)]
+        hide: bool,
+        #[serde(default)]
+        wait: bool,
+        #[serde(default)]
+        exit_code_var: Option<String>,
+        #[serde(default)]
+        pid_var: Option<String>,
+        #[serde(default)]
+        output_var: Option<String>,
+    },
+}
+
+impl From<RunEventRepr> for RunEvent {
+    fn from(repr: RunEventRepr) -> Self {
+        match repr {
+            RunEventRepr::Command(command) => {
+                let mut words = command.split_whitespace().map(|x| x.to_string());
+                RunEvent {
+                    program: words.next().unwrap_or_default(),
+                    args: words.collect(),
+                    working_dir: None,
+                    hide: false,
+                    wait: true,
+                    exit_code_var: None,
+                    pid_var: None,
+                    output_var: None,
+                }
+            }
+            RunEventRepr::Event {
+                program,
+                args,
+                working_dir,
+                hide,
+                wait,
+                exit_code_var,
+                pid_var,
+                output_var,
+            } => RunEvent {
+                program,
+                args,
+                working_dir,
+                hide,
+                wait,
+                exit_code_var,
+                pid_var,
+                output_var,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct IfEvent {
+    pub condition: Condition,
+    pub events: Vec<MacroEvent>,
+    pub else_events: Vec<MacroEvent>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct WaitForKeyEvent {
+    pub key: KeyCode,
+    // wait until the key is in this state
+    pub state: KeyUpDown,
+    // give up after this long, None to wait forever
+    pub timeout_ms: Option<u64>,
+}
+
 #[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
 pub struct KeyboardEvent {
     pub key: Option<KeyCode>,
     // none to tap, down to press and hold, up to release
     pub key_up_down: Option<KeyUpDown>,
+    // when set, these flags also say whether the key is extended, so
+    // Some(empty) sends an arrow key as the numpad's
     pub custom_flags: Option<KeyboardFlags>,
 }
 
+/// What a mouse move's coordinates are measured in.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub enum CoordSpace {
+    /// Pixels on the primary screen.
+    Pixels,
+    /// Pixels anywhere on the virtual desktop, which spans every monitor.
+    /// The primary screen's top left is still 0, 0, so monitors to its left
+    /// or above it have negative coordinates. This is what AHK calls Screen.
+    #[default]
+    VirtualDesk,
+    /// Pixels from the active window's top left, AHK's `CoordMode Window`.
+    /// With no active window they're the same as VirtualDesk.
+    Window,
+    /// Pixels from the active window's client area, AHK's `CoordMode
+    /// Client`.
+    Client,
+    /// 0-65535 across the primary screen, what `MOUSEEVENTF_ABSOLUTE` takes.
+    Normalized,
+    /// Mickeys from where the cursor is, subject to mouse acceleration.
+    Relative,
+}
+
 #[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(from = "MouseMoveEventRepr")]
 pub struct MouseMoveEvent {
     pub x: i32,
     pub y: i32,
-    pub absolute: bool,
+    pub space: CoordSpace,
+}
+
+// older macros only had `absolute`, and absolute moves were screen pixels
+// from AHK scripts even though they were sent as normalized units
+#[derive(Deserialize)]
+struct MouseMoveEventRepr {
+    x: i32,
+    y: i32,
+    #[serde(default, deserialize_with = "present")]
+    space: Option<CoordSpace>,
+    #[serde(default, deserialize_with = "present")]
+    absolute: Option<bool>,
 }
 
-// #[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
-// #[repr(u32)]
-// pub enum MouseButton {
-//     Left = KeyCode::VK_LBUTTON as u32,
-//     Right = KeyCode::VK_RBUTTON as u32,
-//     Middle = KeyCode::VK_MBUTTON as u32,
-//     XButton1 = KeyCode::VK_XBUTTON1 as u32,
-//     XButton2 = KeyCode::VK_XBUTTON2 as u32,
-// }
+// Some for a field that's there without RON wanting it written as Some(...)
+fn present<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+impl From<MouseMoveEventRepr> for MouseMoveEvent {
+    fn from(repr: MouseMoveEventRepr) -> Self {
+        let space = match (repr.space, repr.absolute) {
+            (Some(space), _) => space,
+            (None, Some(false)) => CoordSpace::Relative,
+            (None, _) => CoordSpace::VirtualDesk,
+        };
+        MouseMoveEvent {
+            x: repr.x,
+            y: repr.y,
+            space,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub enum MouseButton {
+    #[default]
+    Left,
+    Right,
+    Middle,
+    X1,
+    X2,
+}
+
+impl MouseButton {
+    /// The flags that press and release the button.
+    pub fn flags(self) -> (MouseFlags, MouseFlags) {
+        match self {
+            MouseButton::Left => (MouseFlags::MOUSEEVENTF_LEFTDOWN, MouseFlags::MOUSEEVENTF_LEFTUP),
+            MouseButton::Right => {
+                (MouseFlags::MOUSEEVENTF_RIGHTDOWN, MouseFlags::MOUSEEVENTF_RIGHTUP)
+            }
+            MouseButton::Middle => {
+                (MouseFlags::MOUSEEVENTF_MIDDLEDOWN, MouseFlags::MOUSEEVENTF_MIDDLEUP)
+            }
+            MouseButton::X1 | MouseButton::X2 => {
+                (MouseFlags::MOUSEEVENTF_XDOWN, MouseFlags::MOUSEEVENTF_XUP)
+            }
+        }
+    }
+
+    /// mouseData for the button, which says which X button it is.
+    pub fn mouse_data(self) -> MouseData {
+        match self {
+            MouseButton::X1 => MouseData::XBUTTON1,
+            MouseButton::X2 => MouseData::XBUTTON2,
+            _ => MouseData::NONE,
+        }
+    }
+
+    /// The button pressed or released by `flags`, with mouseData telling the
+    /// X buttons apart.
+    pub fn from_flags(flags: MouseFlags, mouse_data: MouseData) -> Option<MouseButton> {
+        let x = match mouse_data {
+            MouseData::XBUTTON2 => MouseButton::X2,
+            _ => MouseButton::X1,
+        };
+        [MouseButton::Left, MouseButton::Right, MouseButton::Middle, x]
+            .into_iter()
+            .find(|button| {
+                let (down, up) = button.flags();
+                flags.intersects(down | up)
+            })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub enum MouseAction {
+    Down,
+    Up,
+    #[default]
+    Click,
+    DoubleClick,
+}
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(from = "MouseButtonEventRepr")]
 pub struct MouseButtonEvent {
-    pub flags: MouseFlags,
-    pub up_down: Option<KeyUpDown>,
+    pub button: MouseButton,
+    pub action: MouseAction,
+}
+
+// older macros stored the button as its down or up flag, and up_down was
+// None for a click
+#[derive(Deserialize)]
+struct MouseButtonEventRepr {
+    #[serde(default)]
+    button: MouseButton,
+    #[serde(default)]
+    action: MouseAction,
+    #[serde(default = "MouseFlags::empty")]
+    flags: MouseFlags,
+    #[serde(default)]
+    up_down: Option<KeyUpDown>,
+}
+
+impl From<MouseButtonEventRepr> for MouseButtonEvent {
+    fn from(repr: MouseButtonEventRepr) -> Self {
+        let Some(button) = MouseButton::from_flags(repr.flags, MouseData::NONE) else {
+            return MouseButtonEvent {
+                button: repr.button,
+                action: repr.action,
+            };
+        };
+        let action = match repr.up_down {
+            Some(KeyUpDown::Down) => MouseAction::Down,
+            Some(KeyUpDown::Up) => MouseAction::Up,
+            None => MouseAction::Click,
+        };
+        MouseButtonEvent { button, action }
+    }
+}
+
+/// Raw wheel units in one notch of the wheel.
+pub const WHEEL_DELTA: i32 = 120;
+
+// delta is in raw wheel units, WHEEL_DELTA per notch. Positive is up or right.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct MouseWheelEvent {
+    pub delta: i32,
+    pub horizontal: bool,
+}
+
+impl MouseWheelEvent {
+    pub fn notches(notches: i32, horizontal: bool) -> Self {
+        MouseWheelEvent {
+            delta: notches * WHEEL_DELTA,
+            horizontal,
+        }
+    }
+}
+
+fn sleep_us(us: u64) {
+    if us > 0 {
+        std::thread::sleep(std::time::Duration::from_micros(us));
+    }
+}
+
+impl RunEvent {
+    pub fn run(&self, state: &MacroState) -> std::io::Result<()> {
+        let mut command = std::process::Command::new(&self.program);
+        command.args(&self.args);
+        if let Some(working_dir) = &self.working_dir {
+            command.current_dir(working_dir);
+        }
+        #[cfg(windows)]
+        if self.hide {
+            use std::os::windows::process::CommandExt;
+            const CREATE_NO_WINDOW: u32 = 0x08000000;
+            command.creation_flags(CREATE_NO_WINDOW);
+        }
+
+        if self.wait && self.output_var.is_some() {
+            command.stdout(std::process::Stdio::piped());
+        }
+        let child = command.spawn()?;
+        if let Some(pid_var) = &self.pid_var {
+            state.set_var(pid_var, child.id().to_string());
+        }
+        if !self.wait {
+            return Ok(());
+        }
+        let output = child.wait_with_output()?;
+        if let Some(exit_code_var) = &self.exit_code_var {
+            // -1 if the process was killed by a signal
+            let code = output.status.code().unwrap_or(-1);
+            state.set_var(exit_code_var, code.to_string());
+        }
+        if let Some(output_var) = &self.output_var {
+            state.set_var(output_var, String::from_utf8_lossy(&output.stdout).into_owned());
+        }
+        Ok(())
+    }
+}
+
+impl WaitForKeyEvent {
+    /// Returns false if the timeout ran out or the macro is reloading first.
+    pub fn run(&self, state: &MacroState) -> bool {
+        let start = std::time::Instant::now();
+        let want_down = self.state == KeyUpDown::Down;
+        while self.key.is_down() != want_down {
+            if state.is_reloading() {
+                return false;
+            }
+            if let Some(timeout_ms) = self.timeout_ms {
+                if start.elapsed().as_millis() as u64 >= timeout_ms {
+                    return false;
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        true
+    }
 }
 
 impl KeyboardEvent {
-    pub fn run(&self) {
-        let mut inputs = vec![];
-        let mut flags = self.custom_flags.unwrap_or(KeyboardFlags::NONE);
+    /// The virtual key, scan code and flags of each input to send: the
+    /// press, then the release if the key is tapped.
+    pub fn to_inputs(&self, settings: &MacroSettings) -> Vec<(u16, u16, KeyboardFlags)> {
+        let mut flags = self.custom_flags.unwrap_or(KeyboardFlags::empty());
 
         if let Some(key_up_down) = self.key_up_down {
             if key_up_down == KeyUpDown::Up {
@@ -126,107 +569,471 @@ impl KeyboardEvent {
             }
         }
 
-        let virtual_key = self.key.unwrap_or(KeyCode::VK_NONE) as u16;
+        let key = self.key.unwrap_or(KeyCode::VK_NONE);
+        let virtual_key = u16::from(key);
+        // custom flags decide for themselves whether the key is extended
+        if self.custom_flags.is_none() && key.is_extended() {
+            flags |= KeyboardFlags::KEYEVENTF_EXTENDEDKEY;
+        }
+        let mut scan_code = 0;
+        if settings.scan_codes || flags.contains(KeyboardFlags::KEYEVENTF_SCANCODE) {
+            // keys without a scan code are still sent as virtual keys
+            flags.remove(KeyboardFlags::KEYEVENTF_SCANCODE);
+            if let Some(code) = key.scan_code() {
+                flags |= KeyboardFlags::KEYEVENTF_SCANCODE;
+                scan_code = code & 0xFF;
+            }
+        }
+
+        let mut inputs = vec![(virtual_key, scan_code, flags)];
+        if self.key_up_down.is_none() {
+            inputs.push((virtual_key, scan_code, flags | KeyboardFlags::KEYEVENTF_KEYUP));
+        }
+        inputs
+    }
 
-        // Function to create an INPUT structure for a key event
-        fn create_input(dw_flags: KeyboardFlags, virtual_key: u16) -> INPUT {
-            INPUT {
+    pub fn run(&self, settings: &MacroSettings) {
+        let inputs = self.to_inputs(settings);
+        for (i, (virtual_key, scan_code, flags)) in inputs.into_iter().enumerate() {
+            // a tapped key is held down for a moment before it's released
+            if i > 0 {
+                sleep_us(settings.key_press_duration_us);
+            }
+            let inputs = [INPUT {
                 r#type: INPUT_KEYBOARD,
                 Anonymous: INPUT_0 {
                     ki: KEYBDINPUT {
                         wVk: VIRTUAL_KEY(virtual_key),
-                        wScan: 0,
-                        dwFlags: KEYBD_EVENT_FLAGS(dw_flags as u32),
+                        wScan: scan_code,
+                        dwFlags: KEYBD_EVENT_FLAGS(flags.bits() as u32),
                         time: 0,
                         dwExtraInfo: 0,
                     },
                 },
-            }
-        }
-
-        inputs.push(create_input(flags, virtual_key));
-
-        // Use the number of inputs in the vector
-        let num_inputs = inputs.len();
-        unsafe {
-            SendInput(&inputs, (std::mem::size_of::<INPUT>() * num_inputs) as i32);
-        }
-
-        if self.key_up_down.is_none() {
-            std::thread::sleep(std::time::Duration::from_micros(10));
-            let key_up_inputs = vec![create_input(flags | KeyboardFlags::KEYEVENTF_KEYUP, virtual_key)];
+            }];
             unsafe {
-                SendInput(&key_up_inputs, std::mem::size_of::<INPUT>() as i32);
+                SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
             }
         }
+        sleep_us(settings.key_delay_us);
     }
 }
 
 
 impl MouseMoveEvent {
-    pub fn run(&self) {
-        let mut inputs = vec![INPUT::default()];
-        let mut flags = MouseFlags::MOUSEEVENTF_MOVE;
-
-        if self.absolute {
-            flags |= MouseFlags::MOUSEEVENTF_ABSOLUTE;
-        }
-
-        let input = &mut inputs[0];
-        input.r#type = INPUT_MOUSE;
-        input.Anonymous = INPUT_0 {
-            mi: MOUSEINPUT {
-                dx: self.x,
-                dy: self.y,
-                mouseData: MouseData::NONE as i32,
-                dwFlags: MOUSE_EVENT_FLAGS(flags as u32),
-                time: 0,
-                dwExtraInfo: 0,
-            },
+    /// The dx, dy and flags to send for this move on `screen`, with
+    /// `windows` giving the active window for Window and Client moves.
+    pub fn to_input(
+        &self,
+        screen: &dyn ScreenGeometry,
+        windows: &dyn WindowProvider,
+    ) -> (i32, i32, MouseFlags) {
+        let absolute = MouseFlags::MOUSEEVENTF_MOVE | MouseFlags::MOUSEEVENTF_ABSOLUTE;
+        let corner = |client: bool| {
+            windows.active_window().map_or((0, 0), |x| {
+                let rect = if client { x.client } else { x.window };
+                (rect.left, rect.top)
+            })
         };
-        let len = inputs.len();
-        unsafe {
-            SendInput(&inputs, (std::mem::size_of::<INPUT>() * len) as i32);
+        let (left, top) = match self.space {
+            CoordSpace::Window => corner(false),
+            CoordSpace::Client => corner(true),
+            _ => (0, 0),
+        };
+        match self.space {
+            CoordSpace::Pixels => {
+                let (dx, dy) = screen.primary().normalize(self.x, self.y);
+                (dx, dy, absolute)
+            }
+            CoordSpace::VirtualDesk | CoordSpace::Window | CoordSpace::Client => {
+                let (dx, dy) = screen.virtual_desk().normalize(self.x + left, self.y + top);
+                (dx, dy, absolute | MouseFlags::MOUSEEVENTF_VIRTUALDESK)
+            }
+            CoordSpace::Normalized => (self.x, self.y, absolute),
+            CoordSpace::Relative => (self.x, self.y, MouseFlags::MOUSEEVENTF_MOVE),
         }
     }
-}
 
-impl MouseButtonEvent {
-    pub fn run(&self) {
-        let mut inputs = vec![INPUT::default()];
-        let right = self.flags & MouseFlags::MOUSEEVENTF_RIGHTDOWN == MouseFlags::MOUSEEVENTF_RIGHTDOWN || self.flags & MouseFlags::MOUSEEVENTF_RIGHTUP == MouseFlags::MOUSEEVENTF_RIGHTUP;
-
-        
-        let input = &mut inputs[0];
-        input.r#type = INPUT_MOUSE;
-        if self.up_down.is_some() {
-            input.Anonymous = INPUT_0 {
+    pub fn run(&self, screen: &dyn ScreenGeometry, windows: &dyn WindowProvider) {
+        let (dx, dy, flags) = self.to_input(screen, windows);
+        let inputs = [INPUT {
+            r#type: INPUT_MOUSE,
+            Anonymous: INPUT_0 {
                 mi: MOUSEINPUT {
-                    dx: 0,
-                    dy: 0,
+                    dx,
+                    dy,
                     mouseData: MouseData::NONE as i32,
-                    dwFlags: MOUSE_EVENT_FLAGS(self.flags as u32),
+                    dwFlags: MOUSE_EVENT_FLAGS(flags.bits() as u32),
                     time: 0,
                     dwExtraInfo: 0,
                 },
-            };
+            },
+        }];
+        unsafe {
+            SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
+        }
+    }
+}
+
+impl MouseButtonEvent {
+    /// The mouseData and flags of each input to send for this event.
+    pub fn to_inputs(&self) -> Vec<(i32, MouseFlags)> {
+        let (down, up) = self.button.flags();
+        let presses = match self.action {
+            MouseAction::Down => vec![down],
+            MouseAction::Up => vec![up],
+            MouseAction::Click => vec![down, up],
+            MouseAction::DoubleClick => vec![down, up, down, up],
+        };
+        let mouse_data = self.button.mouse_data() as i32;
+        presses.into_iter().map(|flags| (mouse_data, flags)).collect()
+    }
+
+    pub fn run(&self) {
+        let inputs = self
+            .to_inputs()
+            .into_iter()
+            .map(|(mouse_data, flags)| INPUT {
+                r#type: INPUT_MOUSE,
+                Anonymous: INPUT_0 {
+                    mi: MOUSEINPUT {
+                        dx: 0,
+                        dy: 0,
+                        mouseData: mouse_data,
+                        dwFlags: MOUSE_EVENT_FLAGS(flags.bits() as u32),
+                        time: 0,
+                        dwExtraInfo: 0,
+                    },
+                },
+            })
+            .collect::<Vec<INPUT>>();
+        unsafe {
+            SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
+        }
+    }
+}
+
+impl MouseWheelEvent {
+    /// The mouseData and flags to send for this turn of the wheel.
+    pub fn to_input(&self) -> (i32, MouseFlags) {
+        let flags = if self.horizontal {
+            MouseFlags::MOUSEEVENTF_HWHEEL
         } else {
-            let flags = self.flags | if right { MouseFlags::MOUSEEVENTF_RIGHTUP } else { MouseFlags::MOUSEEVENTF_LEFTUP };
-            input.Anonymous = INPUT_0 {
+            MouseFlags::MOUSEEVENTF_WHEEL
+        };
+        (self.delta, flags)
+    }
+
+    pub fn run(&self) {
+        let (mouse_data, flags) = self.to_input();
+        let inputs = [INPUT {
+            r#type: INPUT_MOUSE,
+            Anonymous: INPUT_0 {
                 mi: MOUSEINPUT {
                     dx: 0,
                     dy: 0,
-                    mouseData: MouseData::NONE as i32,
-                    dwFlags: MOUSE_EVENT_FLAGS(flags as u32),
+                    mouseData: mouse_data,
+                    dwFlags: MOUSE_EVENT_FLAGS(flags.bits() as u32),
                     time: 0,
                     dwExtraInfo: 0,
                 },
-            };
+            },
+        }];
+        unsafe {
+            SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
         }
+    }
+}
 
-        let len = inputs.len();
-        unsafe {
-            SendInput(&inputs, (std::mem::size_of::<INPUT>() * len) as i32);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen::{FixedScreen, Rect};
+    use crate::window::{ActiveWindow, FixedWindow};
+
+    fn tap(key: KeyCode, custom_flags: Option<KeyboardFlags>) -> KeyboardEvent {
+        KeyboardEvent {
+            key: Some(key),
+            key_up_down: None,
+            custom_flags,
+        }
+    }
+
+    fn is_extended(event: KeyboardEvent) -> bool {
+        let inputs = event.to_inputs(&MacroSettings::default());
+        let extended = inputs[0].2.contains(KeyboardFlags::KEYEVENTF_EXTENDEDKEY);
+        // the release is sent the same way as the press
+        assert!(inputs[1].2.contains(KeyboardFlags::KEYEVENTF_KEYUP));
+        assert_eq!(inputs[1].2.contains(KeyboardFlags::KEYEVENTF_EXTENDEDKEY), extended);
+        extended
+    }
+
+    #[test]
+    fn extended_keys() {
+        let extended = [
+            KeyCode::VK_UP,
+            KeyCode::VK_DOWN,
+            KeyCode::VK_LEFT,
+            KeyCode::VK_RIGHT,
+            KeyCode::VK_INSERT,
+            KeyCode::VK_DELETE,
+            KeyCode::VK_HOME,
+            KeyCode::VK_END,
+            KeyCode::VK_PRIOR,
+            KeyCode::VK_NEXT,
+            KeyCode::VK_RCONTROL,
+            KeyCode::VK_RMENU,
+            KeyCode::VK_DIVIDE,
+        ];
+        for key in extended {
+            assert!(is_extended(tap(key, None)), "{:?}", key);
         }
+        // NumpadEnter
+        let enter = tap(KeyCode::VK_RETURN, Some(KeyboardFlags::KEYEVENTF_EXTENDEDKEY));
+        assert!(is_extended(enter));
+    }
+
+    #[test]
+    fn not_extended_keys() {
+        let not_extended = [
+            KeyCode::VK_LCONTROL,
+            KeyCode::VK_LMENU,
+            KeyCode::VK_LSHIFT,
+            KeyCode::VK_RSHIFT,
+            KeyCode::VK_RETURN,
+            KeyCode::VK_NUMPAD0,
+            KeyCode::VK_NUMPAD8,
+            KeyCode::VK_DECIMAL,
+            KeyCode::VK_ADD,
+            KeyCode::VK_A,
+        ];
+        for key in not_extended {
+            assert!(!is_extended(tap(key, None)), "{:?}", key);
+        }
+        // NumpadUp and NumpadDel
+        assert!(!is_extended(tap(KeyCode::VK_UP, Some(KeyboardFlags::empty()))));
+        assert!(!is_extended(tap(KeyCode::VK_DELETE, Some(KeyboardFlags::empty()))));
+    }
+
+    #[test]
+    fn held_keys_send_one_input() {
+        let event = KeyboardEvent {
+            key: Some(KeyCode::VK_RCONTROL),
+            key_up_down: Some(KeyUpDown::Up),
+            custom_flags: None,
+        };
+        let inputs = event.to_inputs(&MacroSettings::default());
+        let flags = KeyboardFlags::KEYEVENTF_KEYUP | KeyboardFlags::KEYEVENTF_EXTENDEDKEY;
+        assert_eq!(inputs, vec![(0xA3, 0, flags)]);
+    }
+
+    #[test]
+    fn extended_scan_codes() {
+        let settings = MacroSettings {
+            scan_codes: true,
+            ..Default::default()
+        };
+        let inputs = tap(KeyCode::VK_RMENU, None).to_inputs(&settings);
+        let flags = KeyboardFlags::KEYEVENTF_SCANCODE | KeyboardFlags::KEYEVENTF_EXTENDEDKEY;
+        assert_eq!(inputs[0], (0xA5, 0x38, flags));
+        let inputs = tap(KeyCode::VK_NUMLOCK, None).to_inputs(&settings);
+        assert_eq!(inputs[0], (0x90, 0x45, flags));
+        // Pause has no scan code SendInput can send
+        let inputs = tap(KeyCode::VK_PAUSE, None).to_inputs(&settings);
+        assert_eq!(inputs[0], (0x13, 0, KeyboardFlags::empty()));
+    }
+
+    #[test]
+    fn mouse_button_inputs() {
+        use MouseFlags as F;
+        let buttons = [
+            (MouseButton::Left, F::MOUSEEVENTF_LEFTDOWN, F::MOUSEEVENTF_LEFTUP, 0),
+            (MouseButton::Right, F::MOUSEEVENTF_RIGHTDOWN, F::MOUSEEVENTF_RIGHTUP, 0),
+            (MouseButton::Middle, F::MOUSEEVENTF_MIDDLEDOWN, F::MOUSEEVENTF_MIDDLEUP, 0),
+            (MouseButton::X1, F::MOUSEEVENTF_XDOWN, F::MOUSEEVENTF_XUP, 1),
+            (MouseButton::X2, F::MOUSEEVENTF_XDOWN, F::MOUSEEVENTF_XUP, 2),
+        ];
+        for (button, down, up, data) in buttons {
+            let inputs = |action| MouseButtonEvent { button, action }.to_inputs();
+            assert_eq!(inputs(MouseAction::Down), vec![(data, down)], "{:?}", button);
+            assert_eq!(inputs(MouseAction::Up), vec![(data, up)], "{:?}", button);
+            assert_eq!(inputs(MouseAction::Click), vec![(data, down), (data, up)]);
+            assert_eq!(
+                inputs(MouseAction::DoubleClick),
+                vec![(data, down), (data, up), (data, down), (data, up)]
+            );
+        }
+    }
+
+    #[test]
+    fn reads_legacy_mouse_buttons() {
+        let read = |ron: &str| crate::keycodes::with_file_version(1, || ron::from_str(ron));
+        let event = |button, action| Ok(MouseButtonEvent { button, action });
+        // the button was its down or up flag, with up_down None for a click
+        assert_eq!(
+            read("(flags: MOUSEEVENTF_LEFTDOWN, up_down: None)"),
+            event(MouseButton::Left, MouseAction::Click)
+        );
+        assert_eq!(
+            read("(flags: MOUSEEVENTF_RIGHTDOWN, up_down: Some(Down))"),
+            event(MouseButton::Right, MouseAction::Down)
+        );
+        assert_eq!(
+            read("(flags: MOUSEEVENTF_MIDDLEUP, up_down: Some(Up))"),
+            event(MouseButton::Middle, MouseAction::Up)
+        );
+        // which X button it was wasn't kept
+        assert_eq!(
+            read("(flags: MOUSEEVENTF_XDOWN, up_down: None)"),
+            event(MouseButton::X1, MouseAction::Click)
+        );
+        // and the current representation still reads as itself
+        let current = MouseButtonEvent {
+            button: MouseButton::X2,
+            action: MouseAction::DoubleClick,
+        };
+        let ron = ron::to_string(&current).unwrap();
+        assert_eq!(ron::from_str::<MouseButtonEvent>(&ron), Ok(current));
+    }
+
+    #[test]
+    fn wheel_inputs() {
+        let wheel = MouseFlags::MOUSEEVENTF_WHEEL;
+        let hwheel = MouseFlags::MOUSEEVENTF_HWHEEL;
+        assert_eq!(MouseWheelEvent::notches(1, false).to_input(), (120, wheel));
+        assert_eq!(MouseWheelEvent::notches(3, false).to_input(), (360, wheel));
+        assert_eq!(MouseWheelEvent::notches(-2, false).to_input(), (-240, wheel));
+        assert_eq!(MouseWheelEvent::notches(1, true).to_input(), (120, hwheel));
+        assert_eq!(MouseWheelEvent::notches(-3, true).to_input(), (-360, hwheel));
+        // recorded deltas from high resolution wheels are sent as they are
+        let event = MouseWheelEvent {
+            delta: 30,
+            horizontal: false,
+        };
+        assert_eq!(event.to_input(), (30, wheel));
+    }
+
+    fn mouse_move(x: i32, y: i32, space: CoordSpace) -> MouseMoveEvent {
+        MouseMoveEvent { x, y, space }
+    }
+
+    // a 1280x1024 monitor to the left of and 200 pixels higher than the
+    // 1920x1080 primary
+    fn two_monitors() -> FixedScreen {
+        FixedScreen {
+            virtual_desk: Rect {
+                left: -1280,
+                top: -200,
+                width: 3200,
+                height: 1280,
+            },
+            ..FixedScreen::single(1920, 1080)
+        }
+    }
+
+    #[test]
+    fn mouse_move_pixels() {
+        let screen = FixedScreen::single(1920, 1080);
+        let absolute = MouseFlags::MOUSEEVENTF_MOVE | MouseFlags::MOUSEEVENTF_ABSOLUTE;
+        let input = |x, y| {
+            mouse_move(x, y, CoordSpace::Pixels).to_input(&screen, &FixedWindow(None))
+        };
+        assert_eq!(input(0, 0), (0, 0, absolute));
+        assert_eq!(input(960, 540), (32768, 32768, absolute));
+        assert_eq!(input(1919, 1079), (65502, 65476, absolute));
+        assert_eq!(input(1920, 1080), (65535, 65535, absolute));
+        // pixels are on the primary screen even with another monitor
+        assert_eq!(
+            mouse_move(960, 540, CoordSpace::Pixels).to_input(&two_monitors(), &FixedWindow(None)),
+            (32768, 32768, absolute)
+        );
+    }
+
+    #[test]
+    fn mouse_move_virtual_desk() {
+        let screen = two_monitors();
+        let flags = MouseFlags::MOUSEEVENTF_MOVE
+            | MouseFlags::MOUSEEVENTF_ABSOLUTE
+            | MouseFlags::MOUSEEVENTF_VIRTUALDESK;
+        let input = |x, y| {
+            mouse_move(x, y, CoordSpace::VirtualDesk).to_input(&screen, &FixedWindow(None))
+        };
+        // the left monitor's top left, then the primary's
+        assert_eq!(input(-1280, -200), (0, 0, flags));
+        assert_eq!(input(0, 0), (26215, 10240, flags));
+        assert_eq!(input(1919, 1079), (65516, 65485, flags));
+        assert_eq!(input(-5000, 5000), (0, 65535, flags));
+    }
+
+    #[test]
+    fn mouse_move_window() {
+        let screen = two_monitors();
+        // a window on the left monitor with a 30 pixel title bar and 8
+        // pixel borders
+        let window = FixedWindow(Some(ActiveWindow {
+            window: Rect {
+                left: -1000,
+                top: -100,
+                width: 800,
+                height: 600,
+            },
+            client: Rect {
+                left: -992,
+                top: -70,
+                width: 784,
+                height: 562,
+            },
+            ..Default::default()
+        }));
+        let at = |x, y, space| mouse_move(x, y, space).to_input(&screen, &window);
+        let on_desk = |x, y| mouse_move(x, y, CoordSpace::VirtualDesk).to_input(&screen, &window);
+        assert_eq!(at(0, 0, CoordSpace::Window), on_desk(-1000, -100));
+        assert_eq!(at(10, 20, CoordSpace::Window), on_desk(-990, -80));
+        assert_eq!(at(0, 0, CoordSpace::Client), on_desk(-992, -70));
+        assert_eq!(at(10, 20, CoordSpace::Client), on_desk(-982, -50));
+        // and without an active window they're from the primary's corner
+        let none = |x, y, space| mouse_move(x, y, space).to_input(&screen, &FixedWindow(None));
+        assert_eq!(none(10, 20, CoordSpace::Window), on_desk(10, 20));
+        assert_eq!(none(10, 20, CoordSpace::Client), on_desk(10, 20));
+    }
+
+    #[test]
+    fn mouse_move_passthrough() {
+        let screen = two_monitors();
+        assert_eq!(
+            mouse_move(40000, 123, CoordSpace::Normalized).to_input(&screen, &FixedWindow(None)),
+            (40000, 123, MouseFlags::MOUSEEVENTF_MOVE | MouseFlags::MOUSEEVENTF_ABSOLUTE)
+        );
+        assert_eq!(
+            mouse_move(-15, 30, CoordSpace::Relative).to_input(&screen, &FixedWindow(None)),
+            (-15, 30, MouseFlags::MOUSEEVENTF_MOVE)
+        );
+    }
+
+    #[test]
+    fn mouse_move_legacy_absolute() {
+        let read = |ron| ron::from_str::<MouseMoveEvent>(ron).unwrap();
+        assert_eq!(
+            read("(x: 1, y: 2, absolute: true)"),
+            mouse_move(1, 2, CoordSpace::VirtualDesk)
+        );
+        assert_eq!(
+            read("(x: 1, y: 2, absolute: false)"),
+            mouse_move(1, 2, CoordSpace::Relative)
+        );
+        assert_eq!(read("(x: 1, y: 2)"), mouse_move(1, 2, CoordSpace::VirtualDesk));
+        // a space wins over absolute
+        assert_eq!(
+            read("(x: 1, y: 2, space: Normalized, absolute: false)"),
+            mouse_move(1, 2, CoordSpace::Normalized)
+        );
+        let json = r#"{"x": 3, "y": 4, "absolute": false}"#;
+        assert_eq!(
+            serde_json::from_str::<MouseMoveEvent>(json).unwrap(),
+            mouse_move(3, 4, CoordSpace::Relative)
+        );
+        // and new files round trip
+        let event = mouse_move(-1280, -200, CoordSpace::Pixels);
+        assert_eq!(read(&ron::to_string(&event).unwrap()), event);
     }
 }
@@ -0,0 +1,559 @@
+// The on-disk format of a macro: a versioned envelope around `Macro`, so
+// files saved by older versions can be upgraded when they're loaded.
+
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{event_stream, keycodes, r#macro::Macro};
+
+/// Bump this and add a step to `migrate` whenever a change to `Macro` can't
+/// be read from older files with serde defaults alone.
+pub const CURRENT_VERSION: u32 = 3;
+
+// binary files start with this, then the version, payload length and CRC32
+// of the payload, all little-endian u32s
+const BINARY_MAGIC: &[u8; 4] = b"AHKM";
+const BINARY_HEADER_LEN: usize = 16;
+
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct MacroFile {
+    pub version: u32,
+    pub name: String,
+    #[serde(default)]
+    pub metadata: MacroMetadata,
+    pub body: Macro,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(default)]
+pub struct MacroMetadata {
+    /// Program and version that wrote the file.
+    pub generator: String,
+    /// The script the macro was converted from, if any.
+    pub source: Option<String>,
+}
+
+// just enough of a file to tell which version it is, 0 if it has none
+#[derive(Deserialize)]
+struct Header {
+    #[serde(default)]
+    version: u32,
+}
+
+impl MacroFile {
+    pub fn new(body: Macro, source: Option<String>) -> Self {
+        MacroFile {
+            version: CURRENT_VERSION,
+            name: body.name.clone(),
+            metadata: MacroMetadata {
+                generator: format!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+                source,
+            },
+            body,
+        }
+    }
+
+    /// Reads a file of any version, upgrading it to the current one. Also
+    /// returns the version it was saved with.
+    pub fn parse(contents: &[u8], format: Format) -> Result<(MacroFile, u32), String> {
+        if format == Format::Binary {
+            return decode_binary(contents);
+        }
+        let contents = std::str::from_utf8(contents).map_err(|e| e.to_string())?;
+        let version = if format == Format::Ron && contents.trim_start().starts_with("Some(") {
+            0
+        } else {
+            format.deserialize::<Header>(contents)?.version
+        };
+        if version > CURRENT_VERSION {
+            return Err(format!(
+                "macro file is version {}, newer than the supported version {}",
+                version, CURRENT_VERSION
+            ));
+        }
+        Ok((migrate(contents, format, version)?, version))
+    }
+
+    /// Upgrades the contents of a file of any version to the current one,
+    /// returning the new contents and the version it was saved with. None if
+    /// it's already current.
+    pub fn migrate(contents: &[u8], format: Format) -> Result<Option<(Vec<u8>, u32)>, String> {
+        let (file, version) = MacroFile::parse(contents, format)?;
+        if version == CURRENT_VERSION {
+            return Ok(None);
+        }
+        Ok(Some((file.to_bytes(format)?, version)))
+    }
+
+    pub fn to_bytes(&self, format: Format) -> Result<Vec<u8>, String> {
+        let contents = match format {
+            Format::Ron => {
+                ron::ser::to_string_pretty(self, Default::default()).map_err(|e| e.to_string())
+            }
+            Format::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string()),
+            // serde_yaml writes enums as tags, which can't be nested (`!Not !KeyState`),
+            // so go through JSON's single-key maps, which it reads back just as well
+            Format::Yaml => {
+                let value = serde_json::to_value(self).map_err(|e| e.to_string())?;
+                serde_yaml::to_string(&value).map_err(|e| e.to_string())
+            }
+            // TOML integers are i64s, so longer sleeps can't be saved
+            Format::Toml => toml::to_string_pretty(self)
+                .map_err(|e| format!("{}, TOML numbers can be at most {}", e, i64::MAX)),
+            Format::Binary => return encode_binary(self),
+        };
+        contents.map(String::into_bytes)
+    }
+}
+
+fn encode_binary(file: &MacroFile) -> Result<Vec<u8>, String> {
+    // the events go in event streams after everything else, which is written
+    // as MessagePack with the event lists left empty. Structs are written as
+    // maps rather than arrays, so fields can be added and reordered like in
+    // the text formats, and RunEvent's untagged repr works
+    let mut head = file.clone();
+    let mut streams = vec![];
+    let blocks = head.body.blocks.iter_mut().map(|x| &mut x.events);
+    for events in blocks.chain(head.body.timers.iter_mut().map(|x| &mut x.events)) {
+        event_stream::encode(&std::mem::take(events), &mut streams)?;
+    }
+    let head = rmp_serde::to_vec_named(&head).map_err(|e| e.to_string())?;
+    let mut payload = Vec::with_capacity(head.len() + streams.len() + 5);
+    event_stream::write_varint(&mut payload, head.len() as u64);
+    payload.extend_from_slice(&head);
+    payload.extend_from_slice(&streams);
+
+    let len = u32::try_from(payload.len()).map_err(|_| "macro is too big for a binary file")?;
+    let mut bytes = Vec::with_capacity(BINARY_HEADER_LEN + payload.len());
+    bytes.extend_from_slice(BINARY_MAGIC);
+    bytes.extend_from_slice(&file.version.to_le_bytes());
+    bytes.extend_from_slice(&len.to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+fn decode_binary(bytes: &[u8]) -> Result<(MacroFile, u32), String> {
+    if bytes.len() < BINARY_HEADER_LEN || &bytes[..4] != BINARY_MAGIC {
+        return Err("not a binary macro file".to_string());
+    }
+    let header_u32 = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+    let (version, len, checksum) = (header_u32(4), header_u32(8), header_u32(12));
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "macro file is version {}, newer than the supported version {}",
+            version, CURRENT_VERSION
+        ));
+    }
+    let payload = &bytes[BINARY_HEADER_LEN..];
+    if payload.len() != len as usize {
+        return Err(format!(
+            "macro file is truncated, expected {} bytes of events but found {}",
+            len,
+            payload.len()
+        ));
+    }
+    if crc32fast::hash(payload) != checksum {
+        return Err("macro file is corrupted, its checksum doesn't match".to_string());
+    }
+    // binary files were added after the envelope, so there's no version 0,
+    // but key codes in older ones are read like the text formats'
+    let mut file = if version < CURRENT_VERSION {
+        keycodes::with_file_version(version, || decode_payload(payload))?
+    } else {
+        decode_payload(payload)?
+    };
+    file.version = CURRENT_VERSION;
+    Ok((file, version))
+}
+
+fn decode_payload(mut payload: &[u8]) -> Result<MacroFile, String> {
+    let head_len = event_stream::read_varint(&mut payload)? as usize;
+    if payload.len() < head_len {
+        return Err("macro file is corrupted, its header runs past the end".to_string());
+    }
+    let (head, mut streams) = payload.split_at(head_len);
+    let mut file: MacroFile = rmp_serde::from_slice(head).map_err(|e| e.to_string())?;
+    let blocks = file.body.blocks.iter_mut().map(|x| &mut x.events);
+    for events in blocks.chain(file.body.timers.iter_mut().map(|x| &mut x.events)) {
+        *events = event_stream::decode(&mut streams)?;
+    }
+    if !streams.is_empty() {
+        return Err("macro file has data after its last event".to_string());
+    }
+    Ok(file)
+}
+
+/// A format macro files can be saved in.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Format {
+    #[default]
+    Ron,
+    Json,
+    Yaml,
+    Toml,
+    /// Compact event streams with a checksummed header, for long recordings.
+    Binary,
+}
+
+impl Format {
+    /// Picks the format from a file extension, or a `--format` name.
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_lowercase().as_str() {
+            "ron" => Some(Format::Ron),
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "toml" => Some(Format::Toml),
+            "bin" | "binary" => Some(Format::Binary),
+            _ => None,
+        }
+    }
+
+    /// Anything without a known extension is RON, like before there were
+    /// other formats.
+    pub fn from_path(path: &Path) -> Format {
+        path.extension()
+            .and_then(|x| Format::from_name(&x.to_string_lossy()))
+            .unwrap_or(Format::Ron)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Ron => "ron",
+            Format::Json => "json",
+            Format::Yaml => "yaml",
+            Format::Toml => "toml",
+            Format::Binary => "bin",
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, contents: &str) -> Result<T, String> {
+        match self {
+            Format::Ron => ron::de::from_str(contents).map_err(|e| e.to_string()),
+            Format::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+            // enums are single-key maps, see `MacroFile::to_string`
+            Format::Yaml => serde_yaml::from_str::<serde_json::Value>(contents)
+                .map_err(|e| e.to_string())
+                .and_then(|x| serde_json::from_value(x).map_err(|e| e.to_string())),
+            Format::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+            Format::Binary => unreachable!("binary files are decoded by decode_binary"),
+        }
+    }
+}
+
+fn migrate(contents: &str, format: Format, version: u32) -> Result<MacroFile, String> {
+    // keys, mouse flags and key states (before version 2) and keyboard flags
+    // (before 3) were plain enums, written in RON as identifiers like
+    // `VK_LCONTROL` rather than strings
+    if version < CURRENT_VERSION {
+        return keycodes::with_file_version(version, || migrate_from(contents, format, version));
+    }
+    format.deserialize::<MacroFile>(contents)
+}
+
+fn migrate_from(contents: &str, format: Format, version: u32) -> Result<MacroFile, String> {
+    let mut file = match version {
+        // version 0 is a bare Macro from before the envelope, sometimes
+        // wrapped in the `Some(...)` the .ahk converter used to write
+        0 => {
+            let body = if format == Format::Ron && contents.trim_start().starts_with("Some(") {
+                format
+                    .deserialize::<Option<Macro>>(contents)?
+                    .ok_or("macro file is empty")?
+            } else {
+                format.deserialize::<Macro>(contents)?
+            };
+            let mut file = MacroFile::new(body, None);
+            file.metadata.generator = String::new();
+            file
+        }
+        _ => format.deserialize::<MacroFile>(contents)?,
+    };
+    file.version = CURRENT_VERSION;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::condition::{Condition, KeyStateMode};
+    use crate::keycodes::{KeyCode, KeyUpDown, KeyboardFlags};
+    use crate::macro_events::{
+        CoordSpace, IfEvent, KeyboardEvent, LoopEvent, MacroEvent, MouseAction, MouseButton,
+        MouseButtonEvent, MouseMoveEvent, MouseWheelEvent, SetVariableEvent, ToggleMode,
+        VariableValue, WaitForKeyEvent,
+    };
+    use crate::r#macro::{MacroBlock, MacroSettings};
+    use crate::timer::MacroTimer;
+
+    fn key(key: KeyCode, key_up_down: Option<KeyUpDown>) -> MacroEvent {
+        MacroEvent::Keybd(KeyboardEvent {
+            key: Some(key),
+            key_up_down,
+            custom_flags: None,
+        })
+    }
+
+    fn file(events: Vec<MacroEvent>, timer_events: Vec<MacroEvent>) -> MacroFile {
+        let block = MacroBlock {
+            hotkey: Some(vec![KeyCode::VK_CONTROL, KeyCode::VK_F1]),
+            events,
+            running: false,
+            max_threads: 1,
+            suspend_exempt: false,
+            active_when: None,
+        };
+        let timer = MacroTimer {
+            name: "Tick".to_string(),
+            period_ms: 100,
+            run_count: None,
+            enabled: true,
+            events: timer_events,
+        };
+        let body = Macro {
+            name: "test".to_string(),
+            blocks: vec![block.clone(), MacroBlock { hotkey: None, events: vec![], ..block }],
+            settings: MacroSettings::default(),
+            timers: vec![timer],
+        };
+        MacroFile::new(body, Some("test.ahk".to_string()))
+    }
+
+    fn round_trip_as(file: &MacroFile, format: Format) -> MacroFile {
+        let bytes = file.to_bytes(format).unwrap();
+        let (decoded, version) = MacroFile::parse(&bytes, format)
+            .unwrap_or_else(|e| panic!("{:?}: {}\n{}", format, e, String::from_utf8_lossy(&bytes)));
+        assert_eq!(version, CURRENT_VERSION);
+        decoded
+    }
+
+    fn round_trip(file: &MacroFile) -> MacroFile {
+        round_trip_as(file, Format::Binary)
+    }
+
+    // one of every kind of event, with the values most likely to break a format
+    fn every_event() -> MacroFile {
+        let events = vec![
+            MacroEvent::SleepMs(0),
+            key(KeyCode::VK_A, None),
+            MacroEvent::SleepMs(20),
+            MacroEvent::SleepMs(u64::MAX - 1),
+            key(KeyCode::VK_LSHIFT, Some(KeyUpDown::Down)),
+            key(KeyCode::VK_OEM_1, Some(KeyUpDown::Up)),
+            MacroEvent::Keybd(KeyboardEvent {
+                key: Some(KeyCode::VK_RETURN),
+                key_up_down: None,
+                custom_flags: Some(KeyboardFlags::KEYEVENTF_EXTENDEDKEY),
+            }),
+            MacroEvent::Keybd(KeyboardEvent {
+                key: None,
+                key_up_down: Some(KeyUpDown::Down),
+                custom_flags: Some(KeyboardFlags::KEYEVENTF_UNICODE),
+            }),
+            MacroEvent::MouseMove(MouseMoveEvent {
+                x: -1920,
+                y: i32::MAX,
+                space: CoordSpace::VirtualDesk,
+            }),
+            MacroEvent::MouseMove(MouseMoveEvent {
+                x: i32::MIN,
+                y: 0,
+                space: CoordSpace::Client,
+            }),
+            MacroEvent::MouseBtn(MouseButtonEvent {
+                button: MouseButton::X2,
+                action: MouseAction::DoubleClick,
+            }),
+            MacroEvent::MouseWheel(MouseWheelEvent {
+                delta: -240,
+                horizontal: true,
+            }),
+            MacroEvent::Loop(LoopEvent {
+                count: 3,
+                events: vec![
+                    MacroEvent::SleepMs(5),
+                    MacroEvent::Loop(LoopEvent {
+                        count: 0,
+                        events: vec![],
+                    }),
+                    MacroEvent::SleepMs(5),
+                ],
+            }),
+            MacroEvent::If(IfEvent {
+                condition: Condition::KeyState(KeyCode::VK_CAPITAL, KeyStateMode::Toggled),
+                events: vec![key(KeyCode::VK_B, None)],
+                else_events: vec![],
+            }),
+            MacroEvent::WaitForKey(WaitForKeyEvent {
+                key: KeyCode::VK_SPACE,
+                state: KeyUpDown::Up,
+                timeout_ms: Some(1000),
+            }),
+            MacroEvent::SetVariable(SetVariableEvent {
+                name: "Toggle".to_string(),
+                value: VariableValue::Not("Toggle".to_string()),
+            }),
+            MacroEvent::Suspend(ToggleMode::Toggle),
+            MacroEvent::PreciseSleep(15),
+            MacroEvent::Reload,
+            MacroEvent::ExitApp,
+            MacroEvent::SleepMs(7),
+        ];
+        file(events, vec![MacroEvent::SleepMs(1), MacroEvent::SleepMs(2)])
+    }
+
+    #[test]
+    fn binary_round_trips_every_event() {
+        let full = every_event();
+        assert_eq!(round_trip(&full), full);
+        let empty = file(vec![], vec![]);
+        assert_eq!(round_trip(&empty), empty);
+    }
+
+    #[test]
+    fn ron_round_trips_every_event() {
+        let full = every_event();
+        assert_eq!(round_trip_as(&full, Format::Ron), full);
+    }
+
+    #[test]
+    fn json_round_trips_every_event() {
+        let full = every_event();
+        assert_eq!(round_trip_as(&full, Format::Json), full);
+    }
+
+    #[test]
+    fn yaml_round_trips_every_event() {
+        let full = every_event();
+        assert_eq!(round_trip_as(&full, Format::Yaml), full);
+    }
+
+    #[test]
+    fn toml_round_trips_every_event() {
+        let mut full = every_event();
+        let e = full.to_bytes(Format::Toml).unwrap_err();
+        assert!(e.contains("TOML numbers can be at most"), "{}", e);
+
+        for event in &mut full.body.blocks[0].events {
+            if let MacroEvent::SleepMs(ms) = event {
+                *ms = (*ms).min(i64::MAX as u64);
+            }
+        }
+        assert_eq!(round_trip_as(&full, Format::Toml), full);
+    }
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+        std::fs::read(path).unwrap()
+    }
+
+    // written by the recorder and the .ahk converter before files had versions
+    #[test]
+    fn migrates_baseline_files() {
+        let recorded = fixture("baseline.ron");
+        let (file, version) = MacroFile::parse(&recorded, Format::Ron).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(file.version, CURRENT_VERSION);
+        assert_eq!(file.name, "baseline");
+
+        let [block, exit] = &file.body.blocks[..] else {
+            panic!("expected 2 blocks: {:?}", file.body.blocks);
+        };
+        assert_eq!(block.hotkey, Some(vec![KeyCode::VK_LCONTROL, KeyCode::VK_F1]));
+        assert_eq!(exit.events, vec![MacroEvent::ExitApp]);
+        let events = &block.events;
+        assert_eq!(events[0], key(KeyCode::VK_A, None));
+        assert_eq!(events[2], key(KeyCode::VK_LSHIFT, Some(KeyUpDown::Down)));
+        assert_eq!(
+            events[5],
+            MacroEvent::Keybd(KeyboardEvent {
+                key: Some(KeyCode::VK_RETURN),
+                key_up_down: None,
+                custom_flags: Some(KeyboardFlags::KEYEVENTF_EXTENDEDKEY),
+            })
+        );
+        assert_eq!(
+            events[6..10],
+            [
+                MacroEvent::MouseMove(MouseMoveEvent {
+                    x: 100,
+                    y: 200,
+                    space: CoordSpace::VirtualDesk,
+                }),
+                MacroEvent::MouseMove(MouseMoveEvent {
+                    x: -5,
+                    y: 10,
+                    space: CoordSpace::Relative,
+                }),
+                MacroEvent::MouseBtn(MouseButtonEvent {
+                    button: MouseButton::Left,
+                    action: MouseAction::Down,
+                }),
+                MacroEvent::MouseBtn(MouseButtonEvent {
+                    button: MouseButton::Right,
+                    action: MouseAction::Click,
+                }),
+            ]
+        );
+        let MacroEvent::Run(run) = &events[11] else {
+            panic!("expected a Run: {:?}", events[11]);
+        };
+        assert_eq!(run.program, "notepad.exe");
+        assert_eq!(run.args, vec!["notes.txt".to_string()]);
+
+        // the converter wrapped the same macro in Some(...)
+        let converted = fixture("baseline_converted.ron");
+        let (converted, version) = MacroFile::parse(&converted, Format::Ron).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(converted.body, file.body);
+
+        // --migrate rewrites it at the current version, once
+        let (migrated, from) = MacroFile::migrate(&recorded, Format::Ron).unwrap().unwrap();
+        assert_eq!(from, 0);
+        let (reread, version) = MacroFile::parse(&migrated, Format::Ron).unwrap();
+        assert_eq!(version, CURRENT_VERSION);
+        assert_eq!(reread.body, file.body);
+        assert_eq!(MacroFile::migrate(&migrated, Format::Ron).unwrap(), None);
+    }
+
+    #[test]
+    fn binary_rejects_damaged_files() {
+        let damaged = file(vec![key(KeyCode::VK_A, None), MacroEvent::SleepMs(10)], vec![]);
+        let bytes = damaged.to_bytes(Format::Binary).unwrap();
+        let parse = |bytes: &[u8]| MacroFile::parse(bytes, Format::Binary).unwrap_err();
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 0xFF;
+        assert!(parse(&corrupted).contains("checksum"), "{}", parse(&corrupted));
+        assert!(parse(&bytes[..bytes.len() - 1]).contains("truncated"));
+        assert!(parse(&bytes[..8]).contains("not a binary macro file"));
+
+        let mut newer = bytes.clone();
+        newer[4..8].copy_from_slice(&(CURRENT_VERSION + 1).to_le_bytes());
+        assert!(parse(&newer).contains("newer than the supported version"));
+    }
+
+    #[test]
+    fn binary_recordings_are_small() {
+        // a recording is mostly key presses with a short sleep between them
+        let keys = [KeyCode::VK_A, KeyCode::VK_S, KeyCode::VK_D, KeyCode::VK_F];
+        let events = (0..25_000)
+            .flat_map(|i| {
+                let key = keys[i % keys.len()];
+                [
+                    self::key(key, Some(KeyUpDown::Down)),
+                    MacroEvent::SleepMs(40 + (i % 50) as u64),
+                    self::key(key, Some(KeyUpDown::Up)),
+                    MacroEvent::SleepMs(90 + (i % 100) as u64),
+                ]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 100_000);
+        let recording = file(events, vec![]);
+        let bytes = recording.to_bytes(Format::Binary).unwrap();
+        // 3 or 4 bytes for each key event and the sleep before it
+        assert!(bytes.len() < 200_000, "{} bytes", bytes.len());
+        assert_eq!(round_trip(&recording), recording);
+    }
+}
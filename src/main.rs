@@ -1,15 +1,12 @@
 #![deny(clippy::correctness, clippy::suspicious, clippy::complexity)]
-pub mod ahk;
-pub mod keycodes;
-pub mod r#macro;
-pub mod macro_events;
-pub mod recorder;
+use ahk_rs::ahk::{AhkFile, AhkVersion};
+use ahk_rs::ahk_export;
+use ahk_rs::instance;
+use ahk_rs::layout::Layout;
+use ahk_rs::macro_file::{Format, MacroFile, CURRENT_VERSION};
+use ahk_rs::r#macro::MacroExit;
+use ahk_rs::recorder::MacroRecorder;
 
-use crate::ahk::AhkFile;
-use crate::r#macro::Macro;
-use crate::recorder::MacroRecorder;
-
-use crate::keycodes::KeyCode;
 use std::io::{Read, Write};
 
 fn usage() -> String {
@@ -17,6 +14,22 @@ fn usage() -> String {
         "Usage: {} [OPTIONS] [MACRO_FILE]
 Options:
     -r, --record    Record a new macro
+    -a, --to-ahk    Convert MACRO_FILE to an .ahk script instead of running it
+    -c, --convert OUTPUT
+                    Convert MACRO_FILE to OUTPUT (.ahk, .ron, .json, .yaml,
+                    .toml or .bin)
+    -f, --format FORMAT
+                    Read and save macro files and recordings as ron, json,
+                    yaml, toml or bin instead of going by the extension. With
+                    --convert it is the format of OUTPUT
+    -l, --layout LAYOUT
+                    Type characters in .ahk scripts with the us, uk, de, fr
+                    or system keyboard layout (default us)
+    -m, --migrate   Upgrade MACRO_FILE to the current file version in place
+    -s, --scan-codes
+                    Send keys by scan code, for games that ignore virtual keys
+        --v1        Parse .ahk files as AutoHotkey v1
+        --v2        Parse .ahk files as AutoHotkey v2
     -h, --help      Print this help message and exit
     -v, --version   Print version information and exit",
         std::env::args().next().unwrap()
@@ -26,6 +39,13 @@ Options:
 #[derive(Debug)]
 enum Argument {
     Record,
+    ToAhk,
+    Convert(String),
+    Format(Format),
+    Layout(Layout),
+    Migrate,
+    ScanCodes,
+    AhkVersion(AhkVersion),
     Help,
     Version,
     MacroFile(String),
@@ -46,15 +66,42 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
     // let ronstr = ron::ser::to_string_pretty(&m, Default::default()).unwrap();
     // let file = std::fs::File::create(format!("{}.ron", m.name)).unwrap();
     // let mut writer = std::io::BufWriter::new(file);
-    // writer.write_all(ronstr.as_bytes()).unwrap();
+    // writer.write_all(&contents).unwrap();
     
     // return Ok(());
 
 
     let mut arguments = vec![];
-    for arg in std::env::args().skip(1) {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
         match arg.as_str() {
             "-r" | "--record" => arguments.push(Argument::Record),
+            "-a" | "--to-ahk" => arguments.push(Argument::ToAhk),
+            "-c" | "--convert" => match args.next() {
+                Some(output) => arguments.push(Argument::Convert(output)),
+                None => {
+                    println!("{} needs an output file.\n{}", arg, usage());
+                    return Ok(());
+                }
+            },
+            "-f" | "--format" => match args.next().as_deref().and_then(Format::from_name) {
+                Some(format) => arguments.push(Argument::Format(format)),
+                None => {
+                    println!("{} needs one of ron, json, yaml, toml or bin.\n{}", arg, usage());
+                    return Ok(());
+                }
+            },
+            "-l" | "--layout" => match args.next().as_deref().and_then(Layout::from_name) {
+                Some(layout) => arguments.push(Argument::Layout(layout)),
+                None => {
+                    println!("{} needs one of us, uk, de, fr or system.\n{}", arg, usage());
+                    return Ok(());
+                }
+            },
+            "-m" | "--migrate" => arguments.push(Argument::Migrate),
+            "-s" | "--scan-codes" => arguments.push(Argument::ScanCodes),
+            "--v1" => arguments.push(Argument::AhkVersion(AhkVersion::V1)),
+            "--v2" => arguments.push(Argument::AhkVersion(AhkVersion::V2)),
             "-h" | "--help" => arguments.push(Argument::Help),
             "-v" | "--version" => arguments.push(Argument::Version),
             _ => arguments.push(Argument::MacroFile(arg)),
@@ -62,11 +109,25 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
     }
 
     let mut record = false;
+    let mut to_ahk = false;
+    let mut convert = None;
+    let mut format = None;
+    let mut migrate = false;
+    let mut scan_codes = false;
     let mut macro_file = None;
+    let mut ahk_version = None;
+    let mut layout = Layout::default();
 
     for argument in arguments {
         match argument {
             Argument::Record => record = true,
+            Argument::ToAhk => to_ahk = true,
+            Argument::Convert(output) => convert = Some(output),
+            Argument::Format(f) => format = Some(f),
+            Argument::Layout(l) => layout = l,
+            Argument::Migrate => migrate = true,
+            Argument::ScanCodes => scan_codes = true,
+            Argument::AhkVersion(version) => ahk_version = Some(version),
             Argument::Help => {
                 println!("{}", usage());
                 return Ok(());
@@ -80,7 +141,10 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
     }
 
     if record {
-        let mut macro_recorder = MacroRecorder::default();
+        let mut macro_recorder = MacroRecorder {
+            format: format.unwrap_or_default(),
+            ..Default::default()
+        };
         println!("Recording macro. Press enter to stop recording.");
         macro_recorder.start().await;
         return Ok(());
@@ -91,14 +155,135 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
         return Ok(());
     }
 
-    let ma: Option<Macro>;
+    let macro_file_path = macro_file.unwrap();
+    if migrate {
+        migrate_file(&macro_file_path, format)?;
+        return Ok(());
+    }
+    // the .ron (or other format) saved next to an .ahk script when it's run
+    let save_format = match convert {
+        Some(_) => None,
+        None => Some(format.unwrap_or(Format::Ron)),
+    };
+    // when converting, --format is what the macro is converted to
+    let load_format = if convert.is_some() { None } else { format };
+    let file = load_macro(&macro_file_path, load_format, ahk_version, layout, save_format);
+
+    if file.is_none() {
+        println!("Failed to parse macro file.");
+        return Ok(());
+    }
+
+    let mut file = file.unwrap();
+    file.body.settings.scan_codes |= scan_codes;
+
+    if let Some(output) = convert {
+        convert_macro(&file, &output, format, layout)?;
+        return Ok(());
+    }
+
+    let mut ma = file.body;
+
+    if to_ahk {
+        let path = std::path::Path::new(&macro_file_path).with_extension("ahk");
+        if macro_file_path.ends_with(".ahk") {
+            println!("{} is already an .ahk file.", macro_file_path);
+            return Ok(());
+        }
+        std::fs::write(&path, ahk_export::to_ahk(&ma, layout))?;
+        println!("Macro saved to {}", path.display());
+        return Ok(());
+    }
+
+    // held until the macro exits, so another instance can tell it's running
+    let lock_path = instance::lock_path(std::path::Path::new(&macro_file_path));
+    let Some(_instance) = instance::claim(&lock_path, ma.settings.single_instance)? else {
+        println!("{} is already running.", macro_file_path);
+        return Ok(());
+    };
+
+    loop {
+        println!("Running macro: {}", ma.name);
+        let start = std::time::Instant::now();
+        let exit = ma.run();
+        println!("Total time elapsed: {:?}ms", start.elapsed().as_millis());
+        if exit != MacroExit::Reload {
+            break;
+        }
+        println!("Reloading {}", macro_file_path);
+        match load_macro(&macro_file_path, load_format, ahk_version, layout, save_format) {
+            Some(reloaded) => {
+                ma = reloaded.body;
+                ma.settings.scan_codes |= scan_codes;
+            }
+            None => println!("Failed to reload macro file, running the old one."),
+        }
+    }
+
+    Ok(())
+}
+
+/// Rewrites a macro file saved by an older version in the current format.
+/// The file is read and written in `format`, or the one matching its
+/// extension.
+fn migrate_file(path: &str, format: Option<Format>) -> anyhow::Result<()> {
+    if path.ends_with(".ahk") {
+        println!("{} is an .ahk file, only macro files can be migrated.", path);
+        return Ok(());
+    }
+    let format = format.unwrap_or_else(|| Format::from_path(std::path::Path::new(path)));
+    let contents = std::fs::read(path)?;
+    let Some((contents, version)) =
+        MacroFile::migrate(&contents, format).map_err(anyhow::Error::msg)?
+    else {
+        println!("{} is already version {}.", path, CURRENT_VERSION);
+        return Ok(());
+    };
+    std::fs::write(path, contents)?;
+    println!("Migrated {} from version {} to {}.", path, version, CURRENT_VERSION);
+    Ok(())
+}
+
+/// Writes a macro to `output` as an .ahk script or a macro file in `format`,
+/// which defaults to the one matching the extension.
+fn convert_macro(
+    file: &MacroFile,
+    output: &str,
+    format: Option<Format>,
+    layout: Layout,
+) -> anyhow::Result<()> {
+    let path = std::path::Path::new(output);
+    let contents = if output.ends_with(".ahk") && format.is_none() {
+        ahk_export::to_ahk(&file.body, layout).into_bytes()
+    } else {
+        let format = format.unwrap_or_else(|| Format::from_path(path));
+        file.to_bytes(format).map_err(anyhow::Error::msg)?
+    };
+    std::fs::write(path, contents)?;
+    println!("Macro saved to {}", path.display());
+    Ok(())
+}
 
-    if macro_file.clone().unwrap().ends_with(".ahk") {
+/// Reads a macro from an .ahk script or a macro file, in `format` if given
+/// or else the one matching its extension. Scripts are also saved next to
+/// the script in `save_format`, if given.
+fn load_macro(
+    macro_file: &str,
+    format: Option<Format>,
+    ahk_version: Option<AhkVersion>,
+    layout: Layout,
+    save_format: Option<Format>,
+) -> Option<MacroFile> {
+    if macro_file.ends_with(".ahk") {
         let mut ahk = AhkFile {
-            path: macro_file.unwrap(),
+            path: macro_file.to_string(),
             blocks: vec![],
+            settings: Default::default(),
+            version: ahk_version,
+            layout,
         };
-        ma = ahk.parse();
+        let ma = ahk.parse();
+        ma.as_ref()?;
         // cant use .map() because of the async block
         // let blocks_ser = ahk.blocks.iter().map(|x| x.lock().await.clone()).collect::<Vec<MacroSer>>();
         let mut blocks_ser = vec![];
@@ -110,27 +295,37 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
         //     blocks: blocks_ser,
         // };
 
-        let ronstr = ron::ser::to_string_pretty(&ma, Default::default()).unwrap();
-        let file = std::fs::File::create(format!("{}.ron", ma.as_ref().unwrap().name)).unwrap();
-        let mut writer = std::io::BufWriter::new(file);
-        writer.write_all(ronstr.as_bytes()).unwrap();
+        let file = MacroFile::new(ma.unwrap(), Some(macro_file.to_string()));
+        let Some(save_format) = save_format else {
+            return Some(file);
+        };
+        let contents = match file.to_bytes(save_format) {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("Failed to save {}: {}", macro_file, e);
+                return Some(file);
+            }
+        };
+        let path = format!("{}.{}", file.name, save_format.extension());
+        let writer_file = std::fs::File::create(path).unwrap();
+        let mut writer = std::io::BufWriter::new(writer_file);
+        writer.write_all(&contents).unwrap();
+        Some(file)
     } else {
-        let mut file = std::fs::File::open(macro_file.unwrap()).unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        ma = Some(ron::de::from_str(&contents).unwrap());
-    }
-
-    if ma.is_none() {
-        println!("Failed to parse macro file.");
-        return Ok(());
+        let mut contents = vec![];
+        if let Err(e) =
+            std::fs::File::open(macro_file).and_then(|mut x| x.read_to_end(&mut contents))
+        {
+            eprintln!("{}: {}", macro_file, e);
+            return None;
+        }
+        let format = format.unwrap_or_else(|| Format::from_path(std::path::Path::new(macro_file)));
+        match MacroFile::parse(&contents, format) {
+            Ok((file, _)) => Some(file),
+            Err(e) => {
+                eprintln!("{}: {}", macro_file, e);
+                None
+            }
+        }
     }
-
-    let ma = ma.unwrap();
-    println!("Running macro: {}", ma.name);
-    let start = std::time::Instant::now();
-    ma.run();
-    println!("Total time elapsed: {:?}ms", start.elapsed().as_millis());
-
-    Ok(())
 }
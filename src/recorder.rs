@@ -1,16 +1,31 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
 use tokio::io::{self, AsyncBufReadExt};
 
 use serde::{Deserialize, Serialize};
+use windows::Win32::Foundation::{HINSTANCE, HWND, LPARAM, LRESULT, WPARAM};
+use windows::Win32::System::Threading::GetCurrentThreadId;
 use windows::Win32::UI::Input::KeyboardAndMouse::{GetAsyncKeyState, GetKeyState};
+use windows::Win32::UI::WindowsAndMessaging::{
+    CallNextHookEx, GetMessageW, PeekMessageW, PostThreadMessageW, SetWindowsHookExW,
+    UnhookWindowsHookEx, HHOOK, MSG, MSLLHOOKSTRUCT, PM_NOREMOVE, WH_MOUSE_LL, WM_MOUSEHWHEEL,
+    WM_MOUSEWHEEL, WM_QUIT,
+};
 
 use crate::keycodes::KeyUpDown;
-use crate::macro_events::{KeyboardEvent, MacroEvent};
+use crate::macro_events::{KeyboardEvent, MacroEvent, MouseWheelEvent};
+use crate::macro_file::{Format, MacroFile};
 use crate::r#macro::Macro;
 use crate::KeyCode;
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
 pub struct MacroRecorder {
     pub events: Vec<MacroEvent>,
+    /// What the recording is saved as.
+    #[serde(skip)]
+    pub format: Format,
 }
 
 impl MacroRecorder {
@@ -27,37 +42,44 @@ impl MacroRecorder {
                 .expect("Failed to read line");
             tx.send(()).expect("Failed to send signal");
         });
+        let wheel_hook = WheelHook::start();
         let mut keymap: std::collections::HashMap<i32, bool> = std::collections::HashMap::new();
         let mut sleep_time = std::time::Instant::now();
         loop {
             if rx.try_recv().is_ok() {
                 break;
             }
+            while let Ok(wheel) = wheel_hook.events.try_recv() {
+                self.events
+                    .push(MacroEvent::SleepMs(sleep_time.elapsed().as_millis() as u64));
+                self.events.push(MacroEvent::MouseWheel(wheel));
+                sleep_time = std::time::Instant::now();
+            }
             for i in 1..256 {
                 let ret = unsafe { GetAsyncKeyState(i) };
                 if ret & 0x8000u16 as i16 != 0 {
                     if keymap.contains_key(&i) {
                         continue;
                     }
-                    println!("Key: {:?}", KeyCode::from(i as u32));
+                    println!("Key: {:?}", KeyCode::from_code(i as u32));
                     println!("Sleep time: {:?}", sleep_time.elapsed().as_millis() as u32);
                     keymap.insert(i, true);
                     self.events
                         .push(MacroEvent::SleepMs(sleep_time.elapsed().as_millis() as u64));
                     self.events.push(MacroEvent::Keybd(KeyboardEvent {
-                        key: Some(KeyCode::from(i as u32)),
+                        key: Some(KeyCode::from_code(i as u32)),
                         key_up_down: Some(KeyUpDown::Down),
                         custom_flags: None,
                     }));
                     sleep_time = std::time::Instant::now();
                 } else if keymap.contains_key(&i) {
-                    println!("Key Up: {:?}", KeyCode::from(i as u32));
+                    println!("Key Up: {:?}", KeyCode::from_code(i as u32));
                     println!("Sleep time: {:?}", sleep_time.elapsed().as_millis() as u32);
                     keymap.remove(&i);
                     self.events
                         .push(MacroEvent::SleepMs(sleep_time.elapsed().as_millis() as u64));
                     self.events.push(MacroEvent::Keybd(KeyboardEvent {
-                        key: Some(KeyCode::from(i as u32)),
+                        key: Some(KeyCode::from_code(i as u32)),
                         key_up_down: Some(KeyUpDown::Up),
                         custom_flags: None,
                     }));
@@ -65,6 +87,7 @@ impl MacroRecorder {
                 }
             }
         }
+        wheel_hook.stop();
         println!("{:?}", keymap);
         self.save();
     }
@@ -75,31 +98,31 @@ impl MacroRecorder {
         loop {
             for i in 0..256 {
                 let ret = unsafe { GetKeyState(i) };
-                if ret & 0x8000u16 as i16 != 0 && i == KeyCode::VK_ESCAPE as i32 {
+                if ret & 0x8000u16 as i16 != 0 && i == KeyCode::VK_ESCAPE.code() as i32 {
                     keymap.insert(i, true);
                     break;
                 } else if ret & 0x8000u16 as i16 != 0
                     && i != 0
-                    && i != KeyCode::VK_RETURN as i32
-                    && i != KeyCode::VK_LBUTTON as i32
-                    && i != KeyCode::VK_RBUTTON as i32
+                    && i != KeyCode::VK_RETURN.code() as i32
+                    && i != KeyCode::VK_LBUTTON.code() as i32
+                    && i != KeyCode::VK_RBUTTON.code() as i32
                 {
                     if keymap.contains_key(&i) {
                         continue;
                     }
                     keymap.insert(i, true);
-                    println!("{:?}", KeyCode::from(i as u32));
+                    println!("{:?}", KeyCode::from_code(i as u32));
                     break;
                 }
             }
-            if keymap.contains_key(&(KeyCode::VK_ESCAPE as i32)) {
-                keymap.remove(&(KeyCode::VK_ESCAPE as i32));
+            if keymap.contains_key(&(KeyCode::VK_ESCAPE.code() as i32)) {
+                keymap.remove(&(KeyCode::VK_ESCAPE.code() as i32));
                 break;
             }
         }
         let keys_pressed = keymap
             .keys()
-            .map(|&x| KeyCode::from(x as u32))
+            .map(|&x| KeyCode::from_code(x as u32))
             .collect::<Vec<KeyCode>>();
         let final_macro = Macro {
             name: "test".to_string(),
@@ -107,10 +130,82 @@ impl MacroRecorder {
                 hotkey: Some(keys_pressed),
                 events: self.events.clone(),
                 running: false,
+                max_threads: 1,
+                suspend_exempt: false,
+                active_when: None,
             }],
+            settings: Default::default(),
+            timers: vec![],
         };
-        let serialized = ron::ser::to_string_pretty(&final_macro, Default::default()).unwrap();
-        std::fs::write("test_macro2.ron", serialized).unwrap();
-        println!("Macro saved to test_macro2.ron");
+        let serialized = MacroFile::new(final_macro, None)
+            .to_bytes(self.format)
+            .unwrap();
+        let path = format!("test_macro2.{}", self.format.extension());
+        std::fs::write(&path, serialized).unwrap();
+        println!("Macro saved to {}", path);
+    }
+}
+
+// where the hook sends the wheel events it sees
+static WHEEL_EVENTS: Mutex<Option<Sender<MouseWheelEvent>>> = Mutex::new(None);
+
+/// The wheel isn't a key `GetAsyncKeyState` can see, so it's recorded by a
+/// low level mouse hook on its own thread, which has to pump messages for
+/// the hook to be called.
+struct WheelHook {
+    thread_id: u32,
+    thread: JoinHandle<()>,
+    events: Receiver<MouseWheelEvent>,
+}
+
+impl WheelHook {
+    fn start() -> WheelHook {
+        let (tx, events) = mpsc::channel();
+        *WHEEL_EVENTS.lock().unwrap() = Some(tx);
+        let (id_tx, id_rx) = mpsc::channel();
+        let thread = std::thread::spawn(move || unsafe {
+            // makes the message queue, so WM_QUIT can be posted to it
+            let mut msg = MSG::default();
+            PeekMessageW(&mut msg, HWND(0), 0, 0, PM_NOREMOVE);
+            id_tx.send(GetCurrentThreadId()).unwrap();
+            let hook = match SetWindowsHookExW(WH_MOUSE_LL, Some(wheel_hook), HINSTANCE(0), 0) {
+                Ok(hook) => hook,
+                Err(e) => {
+                    eprintln!("Failed to hook the mouse, the wheel won't be recorded: {}", e);
+                    return;
+                }
+            };
+            while GetMessageW(&mut msg, HWND(0), 0, 0).as_bool() {}
+            let _ = UnhookWindowsHookEx(hook);
+        });
+        WheelHook {
+            thread_id: id_rx.recv().unwrap(),
+            thread,
+            events,
+        }
+    }
+
+    fn stop(self) {
+        unsafe {
+            let _ = PostThreadMessageW(self.thread_id, WM_QUIT, WPARAM(0), LPARAM(0));
+        }
+        let _ = self.thread.join();
+        *WHEEL_EVENTS.lock().unwrap() = None;
+    }
+}
+
+unsafe extern "system" fn wheel_hook(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    let message = wparam.0 as u32;
+    if code >= 0 && (message == WM_MOUSEWHEEL || message == WM_MOUSEHWHEEL) {
+        let info = &*(lparam.0 as *const MSLLHOOKSTRUCT);
+        // the delta is the signed high word
+        let event = MouseWheelEvent {
+            delta: (info.mouseData >> 16) as i16 as i32,
+            horizontal: message == WM_MOUSEHWHEEL,
+        };
+        if let Some(tx) = WHEEL_EVENTS.lock().unwrap().as_ref() {
+            let _ = tx.send(event);
+        }
     }
+    CallNextHookEx(HHOOK(0), code, wparam, lparam)
 }
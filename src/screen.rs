@@ -0,0 +1,164 @@
+// Screen geometry for turning pixel coordinates into the 0-65535 units
+// absolute mouse input takes. It's behind a trait so conversions can be
+// checked against a fake screen where there's no Win32 API to ask.
+
+use std::fmt::Debug;
+
+/// A rectangle in screen pixels, with the primary screen's top left at 0, 0.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Rect {
+    pub left: i32,
+    pub top: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+pub trait ScreenGeometry: Debug + Send + Sync {
+    fn primary(&self) -> Rect;
+    /// The bounding box of every monitor.
+    fn virtual_desk(&self) -> Rect;
+}
+
+impl Rect {
+    /// Normalizes a pixel to the 0-65535 units across this rectangle,
+    /// rounding up so Windows maps it back to the same pixel.
+    pub fn normalize(&self, x: i32, y: i32) -> (i32, i32) {
+        let scale = |pixel: i32, origin: i32, size: i32| {
+            let size = size.max(1) as i64;
+            let units = ((pixel - origin) as i64 * 65536 + size - 1).div_euclid(size);
+            units.clamp(0, 65535) as i32
+        };
+        (
+            scale(x, self.left, self.width),
+            scale(y, self.top, self.height),
+        )
+    }
+}
+
+/// The screens Windows reports.
+#[cfg(windows)]
+#[derive(Debug)]
+pub struct SystemScreen;
+
+#[cfg(windows)]
+impl ScreenGeometry for SystemScreen {
+    fn primary(&self) -> Rect {
+        use windows::Win32::UI::WindowsAndMessaging::{GetSystemMetrics, SM_CXSCREEN, SM_CYSCREEN};
+
+        Rect {
+            left: 0,
+            top: 0,
+            width: unsafe { GetSystemMetrics(SM_CXSCREEN) },
+            height: unsafe { GetSystemMetrics(SM_CYSCREEN) },
+        }
+    }
+
+    fn virtual_desk(&self) -> Rect {
+        use windows::Win32::UI::WindowsAndMessaging::{
+            GetSystemMetrics, SM_CXVIRTUALSCREEN, SM_CYVIRTUALSCREEN, SM_XVIRTUALSCREEN,
+            SM_YVIRTUALSCREEN,
+        };
+
+        Rect {
+            left: unsafe { GetSystemMetrics(SM_XVIRTUALSCREEN) },
+            top: unsafe { GetSystemMetrics(SM_YVIRTUALSCREEN) },
+            width: unsafe { GetSystemMetrics(SM_CXVIRTUALSCREEN) },
+            height: unsafe { GetSystemMetrics(SM_CYVIRTUALSCREEN) },
+        }
+    }
+}
+
+/// A screen that never changes, for where there's no Win32 API to ask or
+/// to fake a monitor setup.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FixedScreen {
+    pub primary: Rect,
+    pub virtual_desk: Rect,
+}
+
+impl FixedScreen {
+    /// One monitor of the given size.
+    pub fn single(width: i32, height: i32) -> Self {
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width,
+            height,
+        };
+        FixedScreen {
+            primary: rect,
+            virtual_desk: rect,
+        }
+    }
+}
+
+impl ScreenGeometry for FixedScreen {
+    fn primary(&self) -> Rect {
+        self.primary
+    }
+
+    fn virtual_desk(&self) -> Rect {
+        self.virtual_desk
+    }
+}
+
+#[cfg(windows)]
+pub fn platform_provider() -> Box<dyn ScreenGeometry> {
+    Box::new(SystemScreen)
+}
+
+#[cfg(not(windows))]
+pub fn platform_provider() -> Box<dyn ScreenGeometry> {
+    Box::new(FixedScreen::single(1920, 1080))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // where Windows puts a normalized coordinate
+    fn pixel(units: i32, origin: i32, size: i32) -> i32 {
+        origin + ((units as i64 * size as i64) >> 16) as i32
+    }
+
+    #[test]
+    fn normalize_primary() {
+        let screen = FixedScreen::single(1920, 1080).primary();
+        assert_eq!(screen.normalize(0, 0), (0, 0));
+        assert_eq!(screen.normalize(960, 540), (32768, 32768));
+        // the last pixel, and past the edges
+        assert_eq!(screen.normalize(1919, 1079), (65502, 65476));
+        assert_eq!(screen.normalize(1920, 1080), (65535, 65535));
+        assert_eq!(screen.normalize(5000, -10), (65535, 0));
+    }
+
+    #[test]
+    fn normalize_maps_back_to_the_pixel() {
+        let screen = FixedScreen::single(1920, 1080).primary();
+        for x in 0..1920 {
+            let (dx, dy) = screen.normalize(x, x.min(1079));
+            assert_eq!(pixel(dx, 0, 1920), x);
+            assert_eq!(pixel(dy, 0, 1080), x.min(1079));
+        }
+    }
+
+    #[test]
+    fn normalize_virtual_desk() {
+        // a 1280x1024 monitor to the left of and 200 pixels higher than
+        // the 1920x1080 primary
+        let desk = Rect {
+            left: -1280,
+            top: -200,
+            width: 3200,
+            height: 1280,
+        };
+        assert_eq!(desk.normalize(-1280, -200), (0, 0));
+        assert_eq!(desk.normalize(0, 0), (26215, 10240));
+        assert_eq!(desk.normalize(1919, 1079), (65516, 65485));
+        for (x, y) in [(-1280, -200), (-1, -1), (0, 0), (1919, 1079), (-640, 823)] {
+            let (dx, dy) = desk.normalize(x, y);
+            assert_eq!((pixel(dx, -1280, 3200), pixel(dy, -200, 1280)), (x, y));
+        }
+    }
+}
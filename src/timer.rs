@@ -0,0 +1,310 @@
+// Timers run a list of events periodically on their own thread, like AHK's
+// `SetTimer, Label, Period`, so they keep going while hotkeys are handled.
+
+use std::{
+    collections::HashMap,
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{macro_events::MacroEvent, r#macro::MacroState};
+
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct MacroTimer {
+    pub name: String,
+    #[serde(default = "default_period_ms")]
+    pub period_ms: u64,
+    /// How many times the timer runs before turning itself off. None runs
+    /// it until it is stopped.
+    #[serde(default)]
+    pub run_count: Option<u32>,
+    /// Whether the timer is running when the macro starts.
+    #[serde(default)]
+    pub enabled: bool,
+    pub events: Vec<MacroEvent>,
+}
+
+// AHK's period for timers turned on without one
+pub fn default_period_ms() -> u64 {
+    250
+}
+
+/// Changes a timer from another event (`SetTimer`).
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub enum TimerCommand {
+    /// Starts or restarts the timer. Without a period it keeps its current
+    /// period and run count, but the count starts over.
+    Start {
+        period_ms: Option<u64>,
+        run_count: Option<u32>,
+    },
+    Stop,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+pub struct SetTimerEvent {
+    pub name: String,
+    pub command: TimerCommand,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TimerState {
+    enabled: bool,
+    period_ms: u64,
+    run_count: Option<u32>,
+    remaining: Option<u32>,
+    // bumped on every change so a sleeping timer starts its period over
+    generation: u64,
+}
+
+#[derive(Debug, Default)]
+struct TimersInner {
+    // keyed by lowercase name since AHK labels are case-insensitive
+    timers: HashMap<String, TimerState>,
+    // set once the macro's blocks are done, so stopped timers can exit
+    finished: bool,
+}
+
+/// The runtime state of every timer in a macro.
+#[derive(Debug, Default)]
+pub struct Timers {
+    inner: Mutex<TimersInner>,
+    changed: Condvar,
+}
+
+impl Timers {
+    pub fn new(timers: &[MacroTimer]) -> Self {
+        let timers = timers
+            .iter()
+            .map(|timer| {
+                let state = TimerState {
+                    enabled: timer.enabled,
+                    period_ms: timer.period_ms,
+                    run_count: timer.run_count,
+                    remaining: timer.run_count,
+                    generation: 0,
+                };
+                (timer.name.to_lowercase(), state)
+            })
+            .collect();
+        Timers {
+            inner: Mutex::new(TimersInner {
+                timers,
+                finished: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn set(&self, event: &SetTimerEvent) {
+        let mut inner = self.inner.lock().unwrap();
+        // scripts can't name a label that doesn't exist, see `AhkFile::parse`
+        let Some(timer) = inner.timers.get_mut(&event.name.to_lowercase()) else {
+            return;
+        };
+        match event.command {
+            TimerCommand::Start {
+                period_ms,
+                run_count,
+            } => {
+                if let Some(period_ms) = period_ms {
+                    timer.period_ms = period_ms;
+                    timer.run_count = run_count;
+                }
+                timer.remaining = timer.run_count;
+                timer.enabled = timer.run_count != Some(0);
+            }
+            TimerCommand::Stop => timer.enabled = false,
+        }
+        timer.generation += 1;
+        self.changed.notify_all();
+    }
+
+    /// Lets timers that are turned off exit once nothing can turn them on.
+    pub fn finish(&self) {
+        self.inner.lock().unwrap().finished = true;
+        self.changed.notify_all();
+    }
+
+    /// Turns every timer off and lets them exit, for a Reload.
+    pub fn stop_all(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        for timer in inner.timers.values_mut() {
+            timer.enabled = false;
+            timer.generation += 1;
+        }
+        inner.finished = true;
+        self.changed.notify_all();
+    }
+
+    /// Runs `timer` until it is off and the macro has finished.
+    pub fn run(&self, timer: &MacroTimer, state: &MacroState) {
+        let name = timer.name.to_lowercase();
+        loop {
+            let mut inner = self.inner.lock().unwrap();
+            let (period_ms, generation) = loop {
+                let t = inner.timers[&name];
+                if t.enabled {
+                    break (t.period_ms, t.generation);
+                }
+                if inner.finished {
+                    return;
+                }
+                inner = self.changed.wait(inner).unwrap();
+            };
+
+            let (mut inner, wait) = self
+                .changed
+                .wait_timeout_while(inner, Duration::from_millis(period_ms), |inner| {
+                    inner.timers[&name].generation == generation
+                })
+                .unwrap();
+            if !wait.timed_out() {
+                // changed by SetTimer while waiting, start over with the new state
+                continue;
+            }
+            // like in AHK, timers that come due while paused are skipped
+            if state.is_paused() {
+                continue;
+            }
+            let t = inner.timers.get_mut(&name).unwrap();
+            if let Some(remaining) = &mut t.remaining {
+                *remaining = remaining.saturating_sub(1);
+                t.enabled = *remaining > 0;
+            }
+            drop(inner);
+
+            state.run_thread(|| {
+                for event in &timer.events {
+                    event.run(state);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::macro_events::{SetVariableEvent, ToggleMode, VariableValue};
+    use crate::r#macro::MacroSettings;
+    use crate::screen::FixedScreen;
+    use crate::window::FixedWindow;
+    use std::time::Instant;
+
+    /// A timer that sets `ticked` to 1 whenever it runs.
+    fn timer(period_ms: u64, enabled: bool) -> MacroTimer {
+        MacroTimer {
+            name: "Tick".to_string(),
+            period_ms,
+            run_count: None,
+            enabled,
+            events: vec![MacroEvent::SetVariable(SetVariableEvent {
+                name: "ticked".to_string(),
+                value: VariableValue::Text("1".to_string()),
+            })],
+        }
+    }
+
+    /// Runs `timer` on its own thread while `test` runs.
+    fn run_timer(timer: MacroTimer, test: impl FnOnce(&MacroState)) {
+        let state = MacroState::with_providers(
+            MacroSettings::default(),
+            std::slice::from_ref(&timer),
+            Box::new(FixedWindow(None)),
+            Box::new(FixedScreen::single(1920, 1080)),
+        );
+        std::thread::scope(|s| {
+            let runner = s.spawn(|| state.timers.run(&timer, &state));
+            test(&state);
+            state.timers.stop_all();
+            runner.join().unwrap();
+        });
+    }
+
+    /// Clears `ticked`, then waits up to `ms` for the timer to set it again.
+    fn ticks_within(state: &MacroState, ms: u64) -> bool {
+        state.set_var("ticked", String::new());
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(ms) {
+            if state.var_is_true("ticked") {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        false
+    }
+
+    fn set_timer(state: &MacroState, command: TimerCommand) {
+        state.timers.set(&SetTimerEvent {
+            name: "tick".to_string(),
+            command,
+        });
+        // lets a run that was already under way finish
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    fn start(period_ms: Option<u64>, run_count: Option<u32>) -> TimerCommand {
+        TimerCommand::Start {
+            period_ms,
+            run_count,
+        }
+    }
+
+    #[test]
+    fn turns_on_and_off() {
+        run_timer(timer(5, false), |state| {
+            assert!(!ticks_within(state, 50));
+            set_timer(state, start(None, None));
+            assert!(ticks_within(state, 500));
+            assert!(ticks_within(state, 500));
+            set_timer(state, TimerCommand::Stop);
+            assert!(!ticks_within(state, 50));
+        });
+    }
+
+    #[test]
+    fn changes_period() {
+        run_timer(timer(60_000, true), |state| {
+            assert!(!ticks_within(state, 50));
+            set_timer(state, start(Some(5), None));
+            assert!(ticks_within(state, 500));
+            assert_eq!(state.timers.inner.lock().unwrap().timers["tick"].period_ms, 5);
+            // On keeps the new period
+            set_timer(state, TimerCommand::Stop);
+            set_timer(state, start(None, None));
+            assert!(ticks_within(state, 500));
+        });
+    }
+
+    #[test]
+    fn runs_once() {
+        run_timer(timer(5, false), |state| {
+            set_timer(state, start(Some(5), Some(1)));
+            // it may have run already
+            assert!(state.var_is_true("ticked") || ticks_within(state, 500));
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(!ticks_within(state, 50));
+            assert!(!state.timers.inner.lock().unwrap().timers["tick"].enabled);
+            // turning it on again runs it once more
+            set_timer(state, start(None, None));
+            assert!(state.var_is_true("ticked") || ticks_within(state, 500));
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(!ticks_within(state, 50));
+        });
+    }
+
+    #[test]
+    fn skips_ticks_while_paused() {
+        run_timer(timer(5, true), |state| {
+            assert!(ticks_within(state, 500));
+            state.pause(ToggleMode::On);
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(!ticks_within(state, 50));
+            state.pause(ToggleMode::Off);
+            assert!(ticks_within(state, 500));
+        });
+    }
+}
@@ -0,0 +1,176 @@
+// Looks up the focused window for `#IfWinActive` and `WinActive()`. It's
+// behind a trait so conditions can be evaluated against a fake window where
+// there's no Win32 API to ask.
+
+use std::fmt::Debug;
+
+use crate::screen::Rect;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ActiveWindow {
+    pub title: String,
+    pub class: String,
+    /// Where the window is on the screen, for `CoordMode Window`.
+    pub window: Rect,
+    /// Where the window's client area is on the screen, for `CoordMode
+    /// Client`.
+    pub client: Rect,
+}
+
+pub trait WindowProvider: Debug + Send + Sync {
+    fn active_window(&self) -> Option<ActiveWindow>;
+}
+
+/// Splits a WinTitle into the title and its `ahk_` criteria.
+fn split_win_title(win_title: &str) -> (&str, &str) {
+    match win_title.find("ahk_") {
+        Some(i) => (win_title[..i].trim(), &win_title[i..]),
+        None => (win_title.trim(), ""),
+    }
+}
+
+/// Checks that a WinTitle only uses criteria `ActiveWindow::matches`
+/// knows, so scripts using `ahk_exe`, `ahk_id` or `ahk_pid` are rejected when
+/// they're parsed instead of never matching.
+pub fn check_win_title(win_title: &str) -> Result<(), String> {
+    let (_, criteria) = split_win_title(win_title);
+    for word in criteria.split_whitespace().step_by(2) {
+        if !word.eq_ignore_ascii_case("ahk_class") {
+            return Err(format!("WinTitle criteria not implemented: {:?}", word));
+        }
+    }
+    Ok(())
+}
+
+impl ActiveWindow {
+    /// Matches an AHK WinTitle like `Untitled - Notepad` or `ahk_class
+    /// Notepad`. Titles match from the start, like SetTitleMatchMode 1.
+    /// Criteria that `check_win_title` rejects never match.
+    pub fn matches(&self, win_title: &str) -> bool {
+        let (title, criteria) = split_win_title(win_title);
+        if !self.title.starts_with(title) {
+            return false;
+        }
+        let mut words = criteria.split_whitespace();
+        while let Some(word) = words.next() {
+            let value = words.next().unwrap_or("");
+            let matched = word.eq_ignore_ascii_case("ahk_class") && self.class == value;
+            if !matched {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The window in the foreground, from Win32.
+#[cfg(windows)]
+#[derive(Debug)]
+pub struct ForegroundWindow;
+
+#[cfg(windows)]
+impl WindowProvider for ForegroundWindow {
+    fn active_window(&self) -> Option<ActiveWindow> {
+        use windows::Win32::Foundation::RECT;
+        use windows::Win32::UI::WindowsAndMessaging::{
+            GetClassNameW, GetForegroundWindow, GetWindowInfo, GetWindowTextW, WINDOWINFO,
+        };
+
+        let hwnd = unsafe { GetForegroundWindow() };
+        if hwnd.0 == 0 {
+            return None;
+        }
+        let mut title = [0u16; 512];
+        let title_len = unsafe { GetWindowTextW(hwnd, &mut title) }.max(0) as usize;
+        let mut class = [0u16; 256];
+        let class_len = unsafe { GetClassNameW(hwnd, &mut class) }.max(0) as usize;
+        let mut info = WINDOWINFO {
+            cbSize: std::mem::size_of::<WINDOWINFO>() as u32,
+            ..Default::default()
+        };
+        // a window that's gone away by now is left at 0, 0
+        let _ = unsafe { GetWindowInfo(hwnd, &mut info) };
+        let rect = |r: RECT| Rect {
+            left: r.left,
+            top: r.top,
+            width: r.right - r.left,
+            height: r.bottom - r.top,
+        };
+        Some(ActiveWindow {
+            title: String::from_utf16_lossy(&title[..title_len]),
+            class: String::from_utf16_lossy(&class[..class_len]),
+            window: rect(info.rcWindow),
+            client: rect(info.rcClient),
+        })
+    }
+}
+
+/// A window that's always the active one, or none at all, for where there's
+/// no Win32 API to ask or to fake a window being focused.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FixedWindow(pub Option<ActiveWindow>);
+
+impl WindowProvider for FixedWindow {
+    fn active_window(&self) -> Option<ActiveWindow> {
+        self.0.clone()
+    }
+}
+
+#[cfg(windows)]
+pub fn platform_provider() -> Box<dyn WindowProvider> {
+    Box::new(ForegroundWindow)
+}
+
+#[cfg(not(windows))]
+pub fn platform_provider() -> Box<dyn WindowProvider> {
+    Box::new(FixedWindow(None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notepad() -> ActiveWindow {
+        ActiveWindow {
+            title: "Untitled - Notepad".to_string(),
+            class: "Notepad".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matches_title() {
+        let window = notepad();
+        assert!(window.matches("Untitled - Notepad"));
+        // from the start, like SetTitleMatchMode 1
+        assert!(window.matches("Untitled"));
+        assert!(!window.matches("Notepad"));
+        assert!(!window.matches("untitled"));
+        assert!(window.matches(""));
+    }
+
+    #[test]
+    fn matches_class() {
+        let window = notepad();
+        assert!(window.matches("ahk_class Notepad"));
+        assert!(!window.matches("ahk_class Notepad++"));
+        assert!(window.matches("Untitled ahk_class Notepad"));
+        assert!(!window.matches("Document ahk_class Notepad"));
+        assert!(!window.matches("ahk_exe notepad.exe"));
+    }
+
+    #[test]
+    fn checks_criteria() {
+        assert_eq!(check_win_title("Untitled - Notepad"), Ok(()));
+        assert_eq!(check_win_title("Untitled AHK_CLASS Notepad"), Ok(()));
+        for win_title in ["ahk_exe notepad.exe", "ahk_class Notepad ahk_id 0x1", "ahk_pid 4"] {
+            assert!(check_win_title(win_title).is_err(), "{}", win_title);
+        }
+    }
+
+    #[test]
+    fn fixed_window() {
+        assert_eq!(FixedWindow(Some(notepad())).active_window(), Some(notepad()));
+        assert_eq!(FixedWindow(None).active_window(), None);
+    }
+}